@@ -0,0 +1,27 @@
+#include <stdbool.h>
+#include <stdio.h>
+
+int counter = 7;
+
+int sum(int a, int b) {
+    int total = a + b;
+    return total;
+}
+
+double half(double value) {
+    return value / 2;
+}
+
+int main() {
+    int x = 42;
+    long big = -1234567890123L;
+    unsigned int u = 3000000000u;
+    char c = 'A';
+    bool flag = true;
+    double ratio = half(1.5);
+    int *ptr = &x;
+    int result = sum(x, 8);
+    counter += result;
+    printf("%d %ld %u %c %d %f %p %d\n", x, big, u, c, flag, ratio, (void *)ptr, result);
+    return 0;
+}
@@ -1,41 +1,93 @@
 use std::collections::HashMap;
+use std::mem::size_of;
 use std::panic::Location;
 
-use crate::debugger_command::DebuggerCommand;
-use crate::inferior::{Inferior, Status};
+use crate::debugger_command::{CommandCompleter, DebuggerCommand};
+use crate::inferior::{Frame, Inferior, Status};
+use libc::user_regs_struct;
+use nix::sys::ptrace;
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
 use rustyline::error::ReadlineError;
 use rustyline::history::FileHistory;
 use rustyline::Editor;
 
-use crate::dwarf_data::{DwarfData,Error as DwarfError};
+use crate::dwarf_data::{DwarfData,Error as DwarfError,Location as VarLocation,Type,TypeKind,Variable};
 
 pub struct Debugger {
     target: String,
     history_path: String,
-    readline: Editor<(),FileHistory>,
+    readline: Editor<CommandCompleter,FileHistory>,
     inferior: Option<Inferior>,
     debug_data:DwarfData,
     breakpoints:HashMap<usize,Breakpoint>,
+    // the arguments of the last run, used again by a run without any
+    last_args: Vec<String>,
+    // the command an empty line repeats, if the last one can be repeated
+    last_command: Option<DebuggerCommand>,
+    // lines of source shown around the stop location, and by list
+    list_size: usize,
+    // the file and first line the next list without an argument shows
+    list_position: Option<(String, usize)>,
+    // the frame print and info locals/args look at, counting out from the innermost one
+    selected_frame: usize,
+    // watchpoints are held in the inferior's debug registers, so they go away with it
+    watchpoints: Vec<Watchpoint>,
+    // ids stay the same when other breakpoints are deleted, so they can't come from the map
+    next_breakpoint_id: usize,
+    // signals not listed are stopped at and passed on
+    signal_actions: HashMap<Signal, SignalAction>,
+    // how many values finish has reported, which numbers them $1, $2, ... like gdb's value history
+    value_history_len: usize,
 }
 
 #[derive(Clone)]
 pub struct Breakpoint {
+    pub id: usize,
     pub addr: usize,
     pub orig_byte: u8,
+    // a disabled breakpoint keeps its entry, but the original byte is back in the inferior
+    pub enabled: bool,
+    // deleted the first time it stops the inferior
+    pub temporary: bool,
+    pub hit_count: usize,
+    // how many more hits to let through without stopping
+    pub ignore_count: usize,
+    // set by line or function rather than raw address, so it moves with the load bias
+    pub relocatable: bool,
+}
+
+#[derive(Clone, Copy)]
+pub struct SignalAction {
+    // whether the inferior stops and the signal is reported
+    pub stop: bool,
+    // whether the signal is delivered to the inferior when it is continued
+    pub pass: bool,
+}
+
+pub struct Watchpoint {
+    pub id: usize,
+    pub name: String,
+    pub addr: usize,
+    pub entity_type: Type,
+    // which of the four debug registers it is in
+    pub slot: usize,
+    // the value when it was last checked, to show with the new one when it changes
+    pub value: Vec<u8>,
 }
 
 // there are two ways to set breakpoints
-// 1. set breakpoint at the line number or func name
+// 1. set breakpoint at the line number (optionally in a given file) or func name
 // 2. set breakpoint at the raw address
 pub enum Point {
-    Line(usize),
+    Line(Option<String>, usize),
     Func(String),
     Addr(usize), 
 }
 
 impl Debugger {
     /// Initializes the debugger.
-    pub fn new(target: &str) -> Debugger {
+    pub fn new(target: &str, list_size: usize) -> Debugger {
         // Load the target executable file to initialize the DwarfData
         let debug_data = match DwarfData::from_file(target) {
             Ok(val) => val,
@@ -53,7 +105,8 @@ impl Debugger {
         debug_data.print();
 
         let history_path = format!("{}/.deet_history", std::env::var("HOME").unwrap());
-        let mut readline = Editor::<(), FileHistory>::new().expect("Create Editor fail");
+        let mut readline = Editor::<CommandCompleter, FileHistory>::new().expect("Create Editor fail");
+        readline.set_helper(Some(CommandCompleter::new(debug_data.function_names())));
         // Attempt to load history from ~/.deet_history if it exists
         let _ = readline.load_history(&history_path);
 
@@ -64,101 +117,559 @@ impl Debugger {
             inferior: None,
             debug_data,
             breakpoints: HashMap::new(),
+            selected_frame: 0,
+            last_args: Vec::new(),
+            last_command: None,
+            list_size,
+            list_position: None,
+            watchpoints: Vec::new(),
+            next_breakpoint_id: 0,
+            signal_actions: default_signal_actions(),
+            value_history_len: 0,
         }
     }
 
     pub fn run(&mut self) {
         loop {
-            match self.get_next_command() {
+            let command = self.get_next_command();
+            match command {
                 DebuggerCommand::Run(args) => {
-                    if let Some(inferior) = self.inferior.as_mut() {
-                        if inferior.alive() {
-                            self.kill_inferior();
-                        }
+                    if let Some(args) = args {
+                        self.last_args = args;
+                    }
+                    if self.inferior_alive() {
+                        self.end_inferior();
                     }
 
-                    if let Some(inferior) = Inferior::new(&self.target, &args,&mut self.breakpoints) {
+                    if let Some(inferior) = Inferior::new(&self.target, &self.last_args, &self.signal_actions) {
                         // Create the inferior
-                        self.inferior = Some(inferior);
+                        self.adopt_inferior(inferior);
                         // TODO (milestone 1): make the inferior run
                         // You may use self.inferior.as_mut().unwrap() to get a mutable reference
                         // to the Inferior object
                         // self.inferior.as_mut().unwrap().run();
-                        self.debugger_next();
+                        self.debugger_next(1, "starting subprocess");
                     } else {
                         println!("Error starting subprocess");
                     }
                 }
 
+                DebuggerCommand::Attach(pid) => {
+                    if self.inferior_alive() {
+                        self.end_inferior();
+                    }
+                    self.attach(Pid::from_raw(pid));
+                }
+
                 DebuggerCommand::Quit => {
-                    if let Some(inferior) = self.inferior.as_mut() {
-                        if inferior.alive() {
-                            self.kill_inferior();
+                    if self.inferior_alive() {
+                        if !self.confirm("An inferior is running. Quit anyway? (y/n) ") {
+                            continue;
                         }
+                        self.end_inferior();
                     }
                     return;
                 }
 
-                DebuggerCommand::Continue => {
-                    if self.inferior.as_mut().unwrap().alive() {
-                        self.debugger_next();
+                DebuggerCommand::Continue(count) => {
+                    if self.inferior_alive() {
+                        self.debugger_next(count, "continuing");
                     } else {
                         println!("Inferior process is not running");
                     }             
                 }
 
+                DebuggerCommand::Step | DebuggerCommand::Next => {
+                    if !self.inferior_alive() {
+                        println!("Inferior process is not running");
+                        continue;
+                    }
+                    let over_calls = matches!(command, DebuggerCommand::Next);
+                    let status = self.inferior.as_mut().unwrap().step_line(&mut self.breakpoints, &self.debug_data, over_calls);
+                    self.print_status(status, "stepping");
+                }
+
+                DebuggerCommand::StepInstruction | DebuggerCommand::NextInstruction => {
+                    if !self.inferior_alive() {
+                        println!("Inferior process is not running");
+                        continue;
+                    }
+                    let over_calls = matches!(command, DebuggerCommand::NextInstruction);
+                    let status = self.inferior.as_mut().unwrap().step_single(&mut self.breakpoints, over_calls);
+                    let stopped_at = match status {
+                        Ok(Status::Stopped(_, rip)) => Some(rip),
+                        _ => None,
+                    };
+                    self.print_status(status, "stepping");
+                    if let Some(rip) = stopped_at {
+                        println!("At {:#x}", rip);
+                    }
+                }
+
+                DebuggerCommand::Finish => {
+                    if !self.inferior_alive() {
+                        println!("Inferior process is not running");
+                        continue;
+                    }
+                    let inferior = self.inferior.as_mut().unwrap();
+                    let rip = match inferior.get_rip() {
+                        Ok(rip) => rip,
+                        Err(e) => {
+                            self.print_inferior_error("reading registers", e);
+                            continue;
+                        }
+                    };
+                    let func = self.debug_data.get_function_from_addr(rip);
+                    if func.as_deref() == Some("main") {
+                        println!("\"finish\" not meaningful in the outermost frame.");
+                        continue;
+                    }
+                    let return_type = self.debug_data.get_function(rip).and_then(|func| func.return_type.clone());
+                    match inferior.finish(&mut self.breakpoints, &self.debug_data) {
+                        Ok(Some((status, return_addr))) => {
+                            println!("Run till exit from {}", func.as_deref().unwrap_or("??"));
+                            let returned = matches!(status, Status::Stopped(nix::sys::signal::Signal::SIGTRAP, rip) if rip == return_addr);
+                            self.print_status(Ok(status), "finishing");
+                            // a void function has no return type
+                            if let (true, Some(return_type)) = (returned, return_type) {
+                                self.print_return_value(&return_type);
+                            }
+                        },
+                        Ok(None) => println!("Can't finish: not stopped in a function with debugging info"),
+                        Err(e) => self.print_status(Err(e), "finishing"),
+                    }
+                }
+
                 DebuggerCommand::Backtrace => {
-                    self.inferior.as_mut().unwrap().print_backtrace(&self.debug_data).expect("No trace");
+                    if !self.inferior_alive() {
+                        println!("Inferior process is not running");
+                        continue;
+                    }
+                    if let Err(e) = self.inferior.as_ref().unwrap().print_backtrace(&self.debug_data) {
+                        println!("Error reading the stack : {}", e);
+                    }
                 }
 
-                DebuggerCommand::Breakpoint(point) => {
-                    let mut location:usize = 0;                    
-                    match type_breakpoint(point.as_str()) {
-                        Point::Line(line) => {
-                            location = self.debug_data.get_addr_for_line(None, line).unwrap();
+                DebuggerCommand::Breakpoint(ref point) | DebuggerCommand::TempBreakpoint(ref point) => {
+                    let location = match type_breakpoint(point.as_str()) {
+                        Some(Point::Line(file, line)) => {
+                            match self.debug_data.get_addr_for_line(file.as_deref(), line) {
+                                Some(addr) => addr,
+                                None => {
+                                    match file {
+                                        Some(file) => println!("No line {} in file {}", line, file),
+                                        None => println!("No line {}", line),
+                                    }
+                                    continue;
+                                }
+                            }
                         },
-                        Point::Func(func) => {
-                            location = self.debug_data.get_addr_for_function(None, func.as_str()).unwrap();
+                        Some(Point::Func(func)) => {
+                            match self.debug_data.get_addr_for_function(None, func.as_str()) {
+                                // break once the frame is set up, so the args and backtrace are right
+                                Some(addr) => self.debug_data.skip_prologue(addr),
+                                None => {
+                                    println!("No function {}", func);
+                                    continue;
+                                }
+                            }
                         },
-                        Point::Addr(addr) => {
-                            location = addr;
+                        // a raw address is taken as a runtime address as it is
+                        Some(Point::Addr(addr)) => addr,
+                        None => {
+                            println!("Invalid address {}", point);
+                            continue;
                         }
+                    };
+
+                    // breakpoints are keyed by address, so there can only be one per address
+                    if let Some(existing) = self.breakpoints.get(&location) {
+                        println!("Breakpoint {} already at {}", existing.id, location);
+                        continue;
                     }
 
-                    println!("Set breakpoint {} at {}",self.breakpoints.len(),location);
-                    
-                    if let Some(inferior) = self.inferior.as_mut() {
-                        if inferior.alive() {
-                            match inferior.write_byte(location, 0xcc) {
-                                Ok(orignal_byte) => {
-                                    self.breakpoints.insert(
-                                        location, 
-                                        Breakpoint{addr:location, orig_byte:orignal_byte});
-                                },
-                                Err(e) => {
-                                    println!("Error setting breakpoint : {}",e);
-                                }
+                    let orig_byte = if self.inferior_alive() {
+                        match self.inferior.as_mut().unwrap().write_byte(location, 0xcc) {
+                            Ok(orig_byte) => orig_byte,
+                            Err(e) => {
+                                self.print_inferior_error("setting breakpoint", e);
+                                continue;
                             }
                         }
                     } else {
-                        self.breakpoints.insert(
-                            location,
-                            Breakpoint{addr:location,orig_byte:0}
-                        );
+                        // filled in by Inferior::insert_breakpoints once the inferior is started
+                        0
+                    };
+
+                    let temporary = matches!(command, DebuggerCommand::TempBreakpoint(_));
+                    let relocatable = !matches!(type_breakpoint(point.as_str()), Some(Point::Addr(_)));
+                    let id = self.next_breakpoint_id;
+                    self.next_breakpoint_id += 1;
+                    if temporary {
+                        println!("Set temporary breakpoint {} at {}",id,location);
+                    } else {
+                        println!("Set breakpoint {} at {}",id,location);
+                    }
+                    self.breakpoints.insert(location, Breakpoint{id, addr:location, orig_byte, enabled: true, temporary, hit_count: 0, ignore_count: 0, relocatable});
+                }
+
+                DebuggerCommand::Delete(id) => {
+                    if let Some(index) = self.watchpoints.iter().position(|wp| wp.id == id) {
+                        let watchpoint = self.watchpoints.remove(index);
+                        if self.inferior_alive() {
+                            self.inferior.as_ref().unwrap().clear_watchpoint(watchpoint.slot).ok();
+                        }
+                        println!("Deleted watchpoint {}", id);
+                        continue;
+                    }
+                    let addr = match self.breakpoints.values().find(|bp| bp.id == id) {
+                        Some(breakpoint) => breakpoint.addr,
+                        None => {
+                            println!("No breakpoint number {}", id);
+                            continue;
+                        }
+                    };
+                    self.delete_breakpoint(addr);
+                    println!("Deleted breakpoint {}", id);
+                }
+
+                DebuggerCommand::Enable(id) | DebuggerCommand::Disable(id) => {
+                    let enable = matches!(command, DebuggerCommand::Enable(_));
+                    let addr = match self.breakpoints.values().find(|bp| bp.id == id) {
+                        Some(breakpoint) if breakpoint.enabled == enable => continue,
+                        Some(breakpoint) => breakpoint.addr,
+                        None => {
+                            println!("No breakpoint number {}", id);
+                            continue;
+                        }
+                    };
+                    if self.inferior_alive() {
+                        let inferior = self.inferior.as_mut().unwrap();
+                        let result = if enable {
+                            inferior.write_byte(addr, 0xcc)
+                        } else {
+                            inferior.write_byte(addr, self.breakpoints[&addr].orig_byte)
+                        };
+                        match result {
+                            Ok(orig_byte) if enable => self.breakpoints.get_mut(&addr).unwrap().orig_byte = orig_byte,
+                            Ok(_) => {},
+                            Err(e) => {
+                                self.print_inferior_error("changing breakpoint", e);
+                                continue;
+                            }
+                        }
+                    }
+                    self.breakpoints.get_mut(&addr).unwrap().enabled = enable;
+                }
+
+                DebuggerCommand::Ignore(id, count) => {
+                    match self.breakpoints.values_mut().find(|bp| bp.id == id) {
+                        Some(breakpoint) => {
+                            breakpoint.ignore_count = count;
+                            println!("Will ignore next {} crossings of breakpoint {}.", count, id);
+                        }
+                        None => println!("No breakpoint number {}", id),
+                    }
+                }
+
+                DebuggerCommand::InfoBreakpoints => {
+                    self.print_breakpoints();
+                }
+
+                DebuggerCommand::InfoLocals | DebuggerCommand::InfoArgs => {
+                    if !self.inferior_alive() {
+                        println!("Inferior process is not running");
+                        continue;
+                    }
+                    self.print_frame_variables(matches!(command, DebuggerCommand::InfoArgs));
+                }
+
+                DebuggerCommand::Watch(name) => {
+                    if !self.inferior_alive() {
+                        println!("Inferior process is not running");
+                        continue;
                     }
+                    self.set_watchpoint(&name);
                 }
+
+                DebuggerCommand::Examine(count, format, addr) => {
+                    if !self.inferior_alive() {
+                        println!("Inferior process is not running");
+                        continue;
+                    }
+                    match self.examine_address(&addr) {
+                        Some(addr) => self.examine(addr, count, format),
+                        None => println!("Can't work out an address from {}", addr),
+                    }
+                }
+
+                DebuggerCommand::InfoRegisters(name) => {
+                    if !self.inferior_alive() {
+                        println!("Inferior process is not running");
+                        continue;
+                    }
+                    let regs = match ptrace::getregs(self.inferior.as_ref().unwrap().pid()) {
+                        Ok(regs) => regs,
+                        Err(e) => {
+                            println!("Error reading registers : {}", e);
+                            continue;
+                        }
+                    };
+                    match name {
+                        Some(name) => match get_register(&regs, &name) {
+                            Some(value) => print_register(&name, value),
+                            None => println!("Invalid register `{}'", name),
+                        },
+                        None => {
+                            for name in REGISTER_NAMES {
+                                print_register(name, get_register(&regs, name).unwrap());
+                            }
+                        }
+                    }
+                }
+
+                DebuggerCommand::List(point) => {
+                    let position = match (point, self.list_position.clone()) {
+                        (None, Some(position)) => position,
+                        // with nothing listed or stopped at yet, list around main
+                        (point, _) => {
+                            let point = point.unwrap_or_else(|| "main".to_string());
+                            match self.find_source_line(&point) {
+                                Some((file, line)) => (file, line.saturating_sub(self.list_size / 2).max(1)),
+                                None => {
+                                    println!("No source for {}", point);
+                                    continue;
+                                }
+                            }
+                        }
+                    };
+                    self.list_from(position);
+                }
+
+                DebuggerCommand::Handle(name, stop, pass) => {
+                    let name = name.to_uppercase();
+                    let name = if name.starts_with("SIG") { name } else { format!("SIG{}", name) };
+                    let signal = match name.parse::<Signal>() {
+                        Ok(signal) => signal,
+                        Err(_) => {
+                            println!("Unknown signal {}", name);
+                            continue;
+                        }
+                    };
+                    if signal == Signal::SIGTRAP && (stop.is_some() || pass.is_some()) {
+                        println!("SIGTRAP is used by the debugger and can't be changed");
+                        continue;
+                    }
+                    let mut action = self.signal_actions.get(&signal).copied().unwrap_or(SignalAction { stop: true, pass: true });
+                    action.stop = stop.unwrap_or(action.stop);
+                    action.pass = pass.unwrap_or(action.pass);
+                    self.signal_actions.insert(signal, action);
+                    if let Some(inferior) = self.inferior.as_mut() {
+                        inferior.set_signal_action(signal, action);
+                    }
+                    let yes_no = |flag| if flag { "Yes" } else { "No" };
+                    println!("Signal        Stop\tPass");
+                    println!("{:<14}{}\t{}", name, yes_no(action.stop), yes_no(action.pass));
+                }
+
+                DebuggerCommand::SetRegister(name, value) => {
+                    if !self.inferior_alive() {
+                        println!("Inferior process is not running");
+                        continue;
+                    }
+                    let pid = self.inferior.as_ref().unwrap().pid();
+                    let mut regs = match ptrace::getregs(pid) {
+                        Ok(regs) => regs,
+                        Err(e) => {
+                            println!("Error reading registers : {}", e);
+                            continue;
+                        }
+                    };
+                    let value = match parse_integer(&value) {
+                        Some(value) => value as u64,
+                        None => {
+                            println!("Invalid value {}", value);
+                            continue;
+                        }
+                    };
+                    match register_mut(&mut regs, &name) {
+                        Some(register) => *register = value,
+                        None => {
+                            println!("Invalid register `{}'", name);
+                            continue;
+                        }
+                    }
+                    if let Err(e) = ptrace::setregs(pid, regs) {
+                        println!("Error writing registers : {}", e);
+                    }
+                }
+
+                DebuggerCommand::SetVariable(name, value) => {
+                    if !self.inferior_alive() {
+                        println!("Inferior process is not running");
+                        continue;
+                    }
+                    let var = match self.find_variable(&name) {
+                        Some(var) => var,
+                        None => {
+                            println!("No symbol \"{}\" in current context.", name);
+                            continue;
+                        }
+                    };
+                    let bytes = match parse_value(&var.entity_type, &value) {
+                        Some(bytes) => bytes,
+                        None => {
+                            println!("Invalid value {} for {} of type {}", value, name, var.entity_type.name);
+                            continue;
+                        }
+                    };
+                    let addr = match self.variable_address(var) {
+                        Ok(Some(addr)) => addr,
+                        Ok(None) => {
+                            println!("Can't find the frame of {}", name);
+                            continue;
+                        }
+                        Err(e) => {
+                            println!("Error writing {} : {}", name, e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = self.inferior.as_mut().unwrap().write_memory(addr, &bytes) {
+                        println!("Error writing {} : {}", name, e);
+                    }
+                }
+
+                DebuggerCommand::Up | DebuggerCommand::Down => {
+                    if !self.inferior_alive() {
+                        println!("Inferior process is not running");
+                        continue;
+                    }
+                    self.move_frame(matches!(command, DebuggerCommand::Up));
+                }
+
+                DebuggerCommand::Print(name) => {
+                    if !self.inferior_alive() {
+                        println!("Inferior process is not running");
+                        continue;
+                    }
+                    match self.read_variable(&name) {
+                        Ok(Some((var, bytes))) => println!("{} = {}", name, format_value(&var.entity_type, &bytes)),
+                        Ok(None) => println!("No symbol \"{}\" in current context.", name),
+                        Err(e) => println!("Error reading {} : {}", name, e),
+                    }
+                }
+            }
+        }
+    }
+
+    // continues until the inferior has stopped at breakpoints `count` times, only reporting the
+    // last one. `action` is what an error is reported as happening while
+    fn debugger_next(&mut self, count: usize, action: &str) {
+        let mut status = self.inferior.as_mut().unwrap().continue_exec(&mut self.breakpoints);
+        for _ in 1..count {
+            match status {
+                Ok(Status::Stopped(nix::sys::signal::Signal::SIGTRAP, rip)) if self.inferior.as_ref().unwrap().check_at_breakpoint(rip, &self.breakpoints) => {
+                    self.delete_hit_temporary_breakpoint(rip);
+                    status = self.inferior.as_mut().unwrap().continue_exec(&mut self.breakpoints);
+                }
+                _ => break,
             }
         }
+        self.print_status(status, action);
     }
 
-    fn debugger_next(&mut self) {
-        match self.inferior.as_mut().unwrap().continue_exec(&mut self.breakpoints) {
+    /// Prints `count` lines of `file` starting at line `first`, with a marker on line `current`.
+    /// Returns how many lines there were to print.
+    fn print_source(&self, file: &str, first: usize, count: usize, current: Option<usize>) -> usize {
+        let source = match std::fs::read_to_string(file) {
+            Ok(source) => source,
+            Err(e) => {
+                if count > 0 {
+                    println!("Can't read {} : {}", file, e);
+                }
+                return 0;
+            }
+        };
+        let mut printed = 0;
+        for (number, text) in source.lines().enumerate().map(|(i, text)| (i + 1, text)).skip(first - 1).take(count) {
+            let marker = if Some(number) == current { "=>" } else { "  " };
+            println!("{}{:>4}\t{}", marker, number, text);
+            printed += 1;
+        }
+        printed
+    }
+
+    /// Lists `list_size` lines of a file from the given line, and moves the position the next
+    /// list carries on from past them.
+    fn list_from(&mut self, (file, first): (String, usize)) {
+        let printed = self.print_source(&file, first, self.list_size, None);
+        if printed == 0 && self.list_size > 0 {
+            println!("Line number {} out of range; \"{}\" has fewer lines.", first, file);
+        }
+        self.list_position = Some((file, first + printed));
+    }
+
+    /// Finds the source file and line a `list` argument (a line, `file:line`, function or raw
+    /// address) refers to.
+    fn find_source_line(&self, point: &str) -> Option<(String, usize)> {
+        let line = match type_breakpoint(point)? {
+            Point::Line(file, number) => {
+                // any line of the file will do to find it, the one asked for may be past the end
+                let addr = self.debug_data.get_addr_for_line(file.as_deref(), 1)?;
+                return Some((self.debug_data.get_line_from_addr(addr)?.file, number));
+            }
+            Point::Func(func) => self.debug_data.get_line_from_addr(self.debug_data.get_addr_for_function(None, &func)?)?,
+            Point::Addr(addr) => self.debug_data.get_line_from_addr(addr)?,
+        };
+        Some((line.file, line.number))
+    }
+
+    fn delete_breakpoint(&mut self, addr: usize) {
+        let breakpoint = self.breakpoints.remove(&addr).unwrap();
+        if self.inferior_alive() {
+            // put the original instruction back
+            if let Err(e) = self.inferior.as_mut().unwrap().write_byte(addr, breakpoint.orig_byte) {
+                self.print_inferior_error("deleting breakpoint", e);
+            }
+        }
+    }
+
+    // a temporary breakpoint goes away once it has stopped us, but not when a step merely lands
+    // on it
+    fn delete_hit_temporary_breakpoint(&mut self, rip: usize) {
+        let temporary = self.breakpoints.get(&rip).filter(|bp| bp.temporary && bp.enabled && bp.hit_count > 0).map(|bp| bp.id);
+        if let Some(id) = temporary {
+            self.delete_breakpoint(rip);
+            println!("Deleted temporary breakpoint {}", id);
+        }
+    }
+
+    // tells the user why the inferior stopped, and where. Errors are reported as happening while
+    // `action`, e.g. "stepping"
+    fn print_status(&mut self, status: Result<Status, nix::Error>, action: &str) {
+        match status {
             Ok(status) => match status{
                 Status::Stopped(signal, rip) => {
                     println!("Child stopped ({})",signal);
+                    self.selected_frame = 0;
+                    if signal == nix::sys::signal::Signal::SIGTRAP {
+                        self.report_watchpoint();
+                    }
+                    if signal == nix::sys::signal::Signal::SIGSEGV || signal == nix::sys::signal::Signal::SIGBUS {
+                        self.report_fault(rip);
+                    }
                     // milestone 4 : print stopped location
-                    let location = self.debug_data.get_line_from_addr(rip).unwrap();
-                    println!("Stopped at {}",location);
+                    // there's no line info for code outside the target, e.g. in libc
+                    if let Some(breakpoint) = self.breakpoints.get(&rip).filter(|bp| bp.hit_count > 0) {
+                        println!("Breakpoint {} hit {}", breakpoint.id, times(breakpoint.hit_count));
+                    }
+                    if let Some(location) = self.debug_data.get_line_from_addr(rip) {
+                        println!("Stopped at {}",location);
+                        let first = location.number.saturating_sub(self.list_size / 2).max(1);
+                        self.print_source(&location.file, first, self.list_size, Some(location.number));
+                        self.list_position = Some((location.file, first));
+                    }
+                    self.delete_hit_temporary_breakpoint(rip);
                 },
                 Status::Signaled(signal) => {
                     println!("Child exited with signal {:?}", signal);
@@ -167,7 +678,384 @@ impl Debugger {
                     println!("Child exited (status {})", exited);
                 },
             },
-            Err(e) => println!("Error starting subprocess : {}",e)
+            Err(e) => self.print_inferior_error(action, e),
+        }
+    }
+
+    /// Reports an error from tracing the inferior. ptrace fails with ESRCH once the process has
+    /// exited or been killed, which is reported as it not running rather than as an error.
+    fn print_inferior_error(&mut self, action: &str, e: nix::Error) {
+        if e == nix::errno::Errno::ESRCH || !self.inferior_alive() {
+            println!("Inferior process is not running");
+        } else {
+            println!("Error {} : {}", action, e);
+        }
+    }
+
+    /// Prints the value the function that was just finished returned, from the register it's
+    /// passed back in, numbered like gdb's value history (`Value returned is $1 = 42`).
+    fn print_return_value(&mut self, return_type: &Type) {
+        let inferior = self.inferior.as_ref().unwrap();
+        let value = match inferior.return_value(return_type.kind == TypeKind::Float) {
+            Ok(bytes) if return_type.size <= bytes.len() => {
+                format_value(return_type, &bytes[..return_type.size])
+            }
+            Ok(_) => format!("<{} bytes of {}>", return_type.size, return_type.name),
+            Err(e) => {
+                println!("Error reading the return value : {}", e);
+                return;
+            }
+        };
+        self.value_history_len += 1;
+        println!("Value returned is ${} = {}", self.value_history_len, value);
+    }
+
+    /// Prints the bad memory access that made the inferior crash, and the code that made it.
+    fn report_fault(&self, rip: usize) {
+        let addr = match self.inferior.as_ref().unwrap().fault_address() {
+            Ok(addr) => addr,
+            Err(e) => {
+                println!("Can't read the fault address : {}", e);
+                return;
+            }
+        };
+        let func = self.debug_data.get_function_from_addr(rip);
+        let line = self.debug_data.get_line_from_addr(rip);
+        let place = match (func, line) {
+            (Some(func), Some(line)) => format!(" in {} ({})", func, line),
+            (Some(func), None) => format!(" in {}", func),
+            (None, Some(line)) => format!(" ({})", line),
+            (None, None) => String::new(),
+        };
+        println!("Invalid memory access at {:#x}, instruction at {:#x}{}", addr, rip, place);
+    }
+
+    fn print_breakpoints(&self) {
+        if self.breakpoints.is_empty() && self.watchpoints.is_empty() {
+            println!("No breakpoints or watchpoints.");
+            return;
+        }
+        let mut breakpoints: Vec<&Breakpoint> = self.breakpoints.values().collect();
+        breakpoints.sort_by_key(|bp| bp.id);
+        println!("Num Disp Enb Address             What");
+        for breakpoint in breakpoints {
+            let func = self.debug_data.get_function_from_addr(breakpoint.addr);
+            let line = self.debug_data.get_line_from_addr(breakpoint.addr);
+            let what = match (func, line) {
+                (Some(func), Some(line)) => format!("in {} at {}", func, line),
+                (Some(func), None) => format!("in {}", func),
+                (None, Some(line)) => format!("at {}", line),
+                (None, None) => String::new(),
+            };
+            let enabled = if breakpoint.enabled { "y" } else { "n" };
+            let disposition = if breakpoint.temporary { "del" } else { "keep" };
+            let row = format!("{:<4}{:<5}{:<4}{:<20}{}", breakpoint.id, disposition, enabled, format!("{:#x}", breakpoint.addr), what);
+            println!("{}", row.trim_end());
+            if breakpoint.hit_count > 0 {
+                println!("        breakpoint already hit {}", times(breakpoint.hit_count));
+            }
+            if breakpoint.ignore_count > 0 {
+                println!("        will ignore next {} crossings of breakpoint", breakpoint.ignore_count);
+            }
+        }
+        for watchpoint in &self.watchpoints {
+            let row = format!("{:<4}{:<5}{:<4}{:<20}watchpoint on {}", watchpoint.id, "keep", "y", format!("{:#x}", watchpoint.addr), watchpoint.name);
+            println!("{}", row);
+        }
+    }
+
+    /// Looks `name` up among the variables of the function the inferior is stopped in, then
+    /// among the globals, and reads its value. Returns None if there is no such variable.
+    fn read_variable(&self, name: &str) -> Result<Option<(&Variable, Vec<u8>)>, nix::Error> {
+        let var = match self.find_variable(name) {
+            Some(var) => var,
+            None => return Ok(None),
+        };
+        Ok(self.read_value(var)?.map(|bytes| (var, bytes)))
+    }
+
+    fn find_variable(&self, name: &str) -> Option<&Variable> {
+        let local = self
+            .current_function_variables()
+            .and_then(|vars| vars.iter().find(|var| var.name == name));
+        local.or_else(|| self.debug_data.get_global_variable(name))
+    }
+
+    fn current_function_variables(&self) -> Option<&Vec<Variable>> {
+        let frame = self.selected_frame().ok()??;
+        Some(&self.debug_data.get_function(frame.rip)?.variables)
+    }
+
+    // the frame picked with up/down
+    fn selected_frame(&self) -> Result<Option<Frame>, nix::Error> {
+        let frames = self.inferior.as_ref().unwrap().frames(&self.debug_data)?;
+        Ok(frames.get(self.selected_frame).copied())
+    }
+
+    /// Returns where a variable is in memory. It must be a global or belong to the selected
+    /// frame's function. Returns None if the frame can't be found.
+    fn variable_address(&self, var: &Variable) -> Result<Option<usize>, nix::Error> {
+        Ok(match var.location {
+            VarLocation::Address(addr) => Some(addr + self.debug_data.load_bias()),
+            VarLocation::FramePointerOffset(offset) => {
+                let frame = self.selected_frame()?;
+                frame.map(|frame| (frame.cfa as isize + offset) as usize)
+            }
+        })
+    }
+
+    // moves the selected frame `outwards` towards main, or back in
+    fn move_frame(&mut self, outwards: bool) {
+        let frames = match self.inferior.as_ref().unwrap().frames(&self.debug_data) {
+            Ok(frames) if !frames.is_empty() => frames,
+            Ok(_) => {
+                println!("No stack.");
+                return;
+            }
+            Err(e) => {
+                println!("Error reading the stack : {}", e);
+                return;
+            }
+        };
+        if outwards {
+            if self.selected_frame + 1 >= frames.len() {
+                println!("Initial frame selected; you cannot go up.");
+                return;
+            }
+            self.selected_frame += 1;
+        } else {
+            if self.selected_frame == 0 {
+                println!("Bottom (innermost) frame selected; you cannot go down.");
+                return;
+            }
+            self.selected_frame -= 1;
+        }
+        let mut rip = frames[self.selected_frame].rip;
+        if self.selected_frame > 0 {
+            // the return address can be on the line after the call
+            rip -= 1;
+        }
+        let func = self.debug_data.get_function_from_addr(rip).unwrap_or("??".to_string());
+        match self.debug_data.get_line_from_addr(rip) {
+            Some(line) => println!("#{}  {} ({})", self.selected_frame, func, line),
+            None => println!("#{}  {}", self.selected_frame, func),
+        }
+    }
+
+    fn read_value(&self, var: &Variable) -> Result<Option<Vec<u8>>, nix::Error> {
+        match self.variable_address(var)? {
+            Some(addr) => self.inferior.as_ref().unwrap().read_memory(addr, var.entity_type.size).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn set_watchpoint(&mut self, name: &str) {
+        let var = match self.find_variable(name) {
+            Some(var) => var.clone(),
+            None => {
+                println!("No symbol \"{}\" in current context.", name);
+                return;
+            }
+        };
+        let size = var.entity_type.size;
+        if ![1, 2, 4, 8].contains(&size) {
+            println!("Can't watch {} bytes at once, only 1, 2, 4 or 8", size);
+            return;
+        }
+        let slot = match (0..4).find(|slot| self.watchpoints.iter().all(|wp| wp.slot != *slot)) {
+            Some(slot) => slot,
+            None => {
+                println!("No free debug register: at most 4 watchpoints can be set");
+                return;
+            }
+        };
+        let addr = match self.variable_address(&var) {
+            Ok(Some(addr)) => addr,
+            Ok(None) => {
+                println!("Can't find the frame of {}", name);
+                return;
+            }
+            Err(e) => {
+                println!("Error setting watchpoint : {}",e);
+                return;
+            }
+        };
+        let inferior = self.inferior.as_ref().unwrap();
+        let value = match inferior.read_memory(addr, size).and_then(|value| {
+            inferior.set_watchpoint(slot, addr, size)?;
+            Ok(value)
+        }) {
+            Ok(value) => value,
+            Err(e) => {
+                println!("Error setting watchpoint : {}",e);
+                return;
+            }
+        };
+        let id = self.next_breakpoint_id;
+        self.next_breakpoint_id += 1;
+        println!("Hardware watchpoint {}: {}", id, name);
+        self.watchpoints.push(Watchpoint { id, name: name.to_string(), addr, entity_type: var.entity_type, slot, value });
+    }
+
+    /// Works out the address for `x`: a literal address, a `$register`, `&variable`, or a
+    /// variable. A pointer variable gives the address it points to, and any other variable its
+    /// own address.
+    fn examine_address(&self, arg: &str) -> Option<usize> {
+        if let Some(name) = arg.strip_prefix('&') {
+            return self.variable_address(self.find_variable(name)?).ok()?;
+        }
+        if let Some(register) = arg.strip_prefix('$') {
+            let regs = ptrace::getregs(self.inferior.as_ref().unwrap().pid()).ok()?;
+            return get_register(&regs, register).map(|value| value as usize);
+        }
+        if let Some(var) = self.find_variable(arg) {
+            if var.entity_type.kind == TypeKind::Pointer {
+                let bytes = self.read_value(var).ok()??;
+                return Some(usize::from_le_bytes(bytes.try_into().ok()?));
+            }
+            return self.variable_address(var).ok()?;
+        }
+        parse_address(arg)
+    }
+
+    // prints `count` words of memory from `addr`, two to a line
+    fn examine(&self, addr: usize, count: usize, format: char) {
+        let word_size = size_of::<usize>();
+        let len = match count.checked_mul(word_size) {
+            Some(len) if count <= MAX_EXAMINE_COUNT => len,
+            _ => {
+                println!("Cannot examine more than {} words at once", MAX_EXAMINE_COUNT);
+                return;
+            }
+        };
+        let bytes = match self.inferior.as_ref().unwrap().read_memory(addr, len) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                println!("Cannot access memory at address {:#x} : {}", addr, e);
+                return;
+            }
+        };
+        for (line, words) in bytes.chunks(2 * word_size).enumerate() {
+            let values: Vec<String> = words
+                .chunks(word_size)
+                .map(|word| {
+                    let value = u64::from_le_bytes(word.try_into().unwrap());
+                    match format {
+                        'd' => (value as i64).to_string(),
+                        _ => format!("{:#018x}", value),
+                    }
+                })
+                .collect();
+            println!("{:#x}:\t{}", addr + line * 2 * word_size, values.join("\t"));
+        }
+    }
+
+    // if the inferior stopped because of a watchpoint, shows how the value changed
+    fn report_watchpoint(&mut self) {
+        let inferior = self.inferior.as_ref().unwrap();
+        let slot = match inferior.watchpoint_hit() {
+            Ok(Some(slot)) => slot,
+            _ => return,
+        };
+        inferior.clear_watchpoint_hit().ok();
+        let watchpoint = match self.watchpoints.iter_mut().find(|wp| wp.slot == slot) {
+            Some(watchpoint) => watchpoint,
+            None => return,
+        };
+        let new_value = match inferior.read_memory(watchpoint.addr, watchpoint.entity_type.size) {
+            Ok(value) => value,
+            Err(e) => {
+                println!("Error reading {} : {}", watchpoint.name, e);
+                return;
+            }
+        };
+        println!("Hardware watchpoint {}: {}", watchpoint.id, watchpoint.name);
+        println!("Old value = {}", format_value(&watchpoint.entity_type, &watchpoint.value));
+        println!("New value = {}", format_value(&watchpoint.entity_type, &new_value));
+        watchpoint.value = new_value;
+    }
+
+    // prints the arguments or the other local variables of the current function
+    fn print_frame_variables(&self, parameters: bool) {
+        let vars: Vec<&Variable> = match self.current_function_variables() {
+            Some(vars) => vars.iter().filter(|var| var.parameter == parameters).collect(),
+            None => {
+                println!("No symbol table info available.");
+                return;
+            }
+        };
+        if vars.is_empty() {
+            println!("{}", if parameters { "No arguments." } else { "No locals." });
+        }
+        for var in vars {
+            match self.read_value(var) {
+                Ok(Some(bytes)) => println!("{} = {}", var.name, format_value(&var.entity_type, &bytes)),
+                Ok(None) => println!("{} = <unavailable>", var.name),
+                Err(e) => println!("{} = <error: {}>", var.name, e),
+            }
+        }
+    }
+
+    fn inferior_alive(&mut self) -> bool {
+        match self.inferior.as_mut() {
+            Some(inferior) => inferior.alive(),
+            None => false,
+        }
+    }
+
+    /// Attaches to the running process `pid` and shows where it was stopped.
+    pub fn attach(&mut self, pid: Pid) {
+        if let Some(inferior) = Inferior::attach(pid, &self.signal_actions) {
+            println!("Attached to process {}", pid);
+            let status = inferior.get_rip().map(|rip| Status::Stopped(nix::sys::signal::Signal::SIGSTOP, rip));
+            self.adopt_inferior(inferior);
+            self.print_status(status, "attaching");
+        }
+    }
+
+    /// Takes on a newly started or attached inferior: works out where it has the target loaded,
+    /// moves the breakpoints there and inserts them.
+    fn adopt_inferior(&mut self, mut inferior: Inferior) {
+        if self.debug_data.position_independent() {
+            match inferior.load_address() {
+                Ok(load_bias) => self.relocate(load_bias),
+                Err(e) => println!("Can't find where the program is loaded : {}", e),
+            }
+        }
+        inferior.insert_breakpoints(&mut self.breakpoints);
+        self.inferior = Some(inferior);
+        self.watchpoints.clear();
+    }
+
+    // switches the debugging info and the breakpoints set from it over to a new load bias
+    fn relocate(&mut self, load_bias: usize) {
+        let old_bias = self.debug_data.load_bias();
+        if load_bias == old_bias {
+            return;
+        }
+        self.debug_data.set_load_bias(load_bias);
+        self.breakpoints = self
+            .breakpoints
+            .drain()
+            .map(|(addr, mut breakpoint)| {
+                if breakpoint.relocatable {
+                    breakpoint.addr = addr - old_bias + load_bias;
+                }
+                (breakpoint.addr, breakpoint)
+            })
+            .collect();
+    }
+
+    /// Gets rid of the current inferior: a process we attached to is detached and left running,
+    /// one we started is killed.
+    fn end_inferior(&mut self) {
+        if !self.inferior.as_ref().unwrap().attached() {
+            self.kill_inferior();
+            return;
+        }
+        match self.inferior.as_mut().unwrap().detach(&self.breakpoints) {
+            Ok(_) => self.inferior = None,
+            Err(e) => println!("Error detaching from process : {}", e),
         }
     }
 
@@ -178,6 +1066,21 @@ impl Debugger {
         } 
     }
 
+    /// Asks the user a yes or no question. Ctrl+d counts as yes, and ctrl+c as no.
+    fn confirm(&mut self, question: &str) -> bool {
+        loop {
+            match self.readline.readline(question) {
+                Ok(answer) => match answer.trim().to_lowercase().as_str() {
+                    "y" | "yes" => return true,
+                    "n" | "no" => return false,
+                    _ => println!("Please answer y or n."),
+                },
+                Err(ReadlineError::Eof) => return true,
+                Err(_) => return false,
+            }
+        }
+    }
+
     /// This function prompts the user to enter a command, and continues re-prompting until the user
     /// enters a valid command. It uses DebuggerCommand::from_tokens to do the command parsing.
     ///
@@ -191,7 +1094,11 @@ impl Debugger {
                     println!("Type \"quit\" to exit");
                 }
                 Err(ReadlineError::Eof) => {
-                    // User pressed ctrl+d, which is the equivalent of "quit" for our purposes
+                    // User pressed ctrl+d, which is the equivalent of "quit" for our purposes,
+                    // except that it doesn't ask before ending the inferior
+                    if self.inferior_alive() {
+                        self.end_inferior();
+                    }
                     return DebuggerCommand::Quit;
                 }
                 Err(err) => {
@@ -199,7 +1106,11 @@ impl Debugger {
                 }
                 Ok(line) => {
                     if line.trim().len() == 0 {
-                        continue;
+                        // like gdb, an empty line repeats stepping, continuing and listing
+                        match &self.last_command {
+                            Some(cmd) => return cmd.clone(),
+                            None => continue,
+                        }
                     }
                     self.readline.add_history_entry(line.as_str());
                     if let Err(err) = self.readline.save_history(&self.history_path) {
@@ -210,6 +1121,11 @@ impl Debugger {
                     }
                     let tokens: Vec<&str> = line.split_whitespace().collect();
                     if let Some(cmd) = DebuggerCommand::from_tokens(&tokens) {
+                        self.last_command = match cmd {
+                            // repeating a list carries on from where it got to
+                            DebuggerCommand::List(_) => Some(DebuggerCommand::List(None)),
+                            _ => Some(cmd.clone()).filter(DebuggerCommand::repeatable),
+                        };
                         return cmd;
                     } else {
                         println!("Unrecognized command.");
@@ -221,6 +1137,67 @@ impl Debugger {
 
 }
 
+// the signals handled differently from the rest by default: SIGINT is how the user interrupts the
+// inferior, and the others are ones programs get all the time in normal running
+fn default_signal_actions() -> HashMap<Signal, SignalAction> {
+    let mut actions = HashMap::new();
+    actions.insert(Signal::SIGINT, SignalAction { stop: true, pass: false });
+    for signal in [Signal::SIGALRM, Signal::SIGCHLD, Signal::SIGWINCH, Signal::SIGURG, Signal::SIGPROF, Signal::SIGIO] {
+        actions.insert(signal, SignalAction { stop: false, pass: true });
+    }
+    actions
+}
+
+// the most words that `x` reads in one go
+const MAX_EXAMINE_COUNT: usize = 4096;
+
+// the registers shown by `info registers`
+const REGISTER_NAMES: [&str; 18] = [
+    "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "rsp", "r8", "r9", "r10", "r11", "r12",
+    "r13", "r14", "r15", "rip", "eflags",
+];
+
+fn print_register(name: &str, value: u64) {
+    println!("{:<8}{:<20}{}", name, format!("{:#x}", value), value);
+}
+
+// returns the value of a general-purpose register by its name, like "rsp" or "eflags"
+fn get_register(regs: &user_regs_struct, name: &str) -> Option<u64> {
+    register_mut(&mut regs.clone(), name).map(|value| *value)
+}
+
+fn register_mut<'a>(regs: &'a mut user_regs_struct, name: &str) -> Option<&'a mut u64> {
+    Some(match name {
+        "rax" => &mut regs.rax,
+        "rbx" => &mut regs.rbx,
+        "rcx" => &mut regs.rcx,
+        "rdx" => &mut regs.rdx,
+        "rsi" => &mut regs.rsi,
+        "rdi" => &mut regs.rdi,
+        "rbp" => &mut regs.rbp,
+        "rsp" => &mut regs.rsp,
+        "r8" => &mut regs.r8,
+        "r9" => &mut regs.r9,
+        "r10" => &mut regs.r10,
+        "r11" => &mut regs.r11,
+        "r12" => &mut regs.r12,
+        "r13" => &mut regs.r13,
+        "r14" => &mut regs.r14,
+        "r15" => &mut regs.r15,
+        "rip" | "pc" => &mut regs.rip,
+        "eflags" => &mut regs.eflags,
+        _ => return None,
+    })
+}
+
+fn times(count: usize) -> String {
+    if count == 1 {
+        "1 time".to_string()
+    } else {
+        format!("{} times", count)
+    }
+}
+
 fn parse_address(addr: &str) -> Option<usize> {
     let addr_without_0x = if addr.to_lowercase().starts_with("0x") {
         &addr[2..]
@@ -230,16 +1207,87 @@ fn parse_address(addr: &str) -> Option<usize> {
     usize::from_str_radix(addr_without_0x, 16).ok()
 }
 
-fn type_breakpoint(point: &str) -> Point {
+// returns None if the point is a raw address that can't be parsed
+fn type_breakpoint(point: &str) -> Option<Point> {
     // if the point starts with *, it is a raw address
     if point.starts_with("*"){
-        Point::Addr(parse_address(&point[1..]).unwrap())
+        Some(Point::Addr(parse_address(&point[1..])?))
     }   // if the point is a number, it is a line number 
-    else if point.parse::<usize>().is_ok() {
-        Point::Line(point.parse().unwrap())
+    else if let Ok(line) = point.parse::<usize>() {
+        Some(Point::Line(None, line))
+    }   // if the point is file:number, it is a line in that file
+    else if let Some((file, line)) = point.rsplit_once(':').and_then(|(file, line)| Some((file, line.parse::<usize>().ok()?))) {
+        Some(Point::Line(Some(file.to_string()), line))
     }   // otherwise, it is a function name
     else {
-        Point::Func(point.to_string())
+        Some(Point::Func(point.to_string()))
     } 
 }
-                     
\ No newline at end of file
+                     
+
+// formats the bytes of a value (in the inferior's little-endian order) according to its type
+fn format_value(dtype: &Type, bytes: &[u8]) -> String {
+    let mut buf = [0u8; 8];
+    if bytes.len() > buf.len() {
+        return format!("<{} bytes of {}>", bytes.len(), dtype.name);
+    }
+    buf[..bytes.len()].copy_from_slice(bytes);
+    let unsigned = u64::from_le_bytes(buf);
+    // shift the sign bit of the value up to the top to sign-extend it
+    let shift = 64 - 8 * bytes.len() as u32;
+    let signed = if bytes.is_empty() { 0 } else { ((unsigned << shift) as i64) >> shift };
+    match dtype.kind {
+        TypeKind::Signed => signed.to_string(),
+        TypeKind::Unsigned => unsigned.to_string(),
+        TypeKind::Char => {
+            let value = if dtype.name.contains("unsigned") { unsigned as i64 } else { signed };
+            format!("{} '{}'", value, (unsigned as u8 as char).escape_default())
+        }
+        TypeKind::Bool => match unsigned {
+            0 => "false".to_string(),
+            1 => "true".to_string(),
+            _ => unsigned.to_string(),
+        },
+        TypeKind::Float if bytes.len() == 4 => f32::from_bits(unsigned as u32).to_string(),
+        TypeKind::Float if bytes.len() == 8 => f64::from_bits(unsigned).to_string(),
+        TypeKind::Pointer => format!("({}) {:#x}", dtype.name, unsigned),
+        _ => format!("<cannot display {}>", dtype.name),
+    }
+}
+
+// parses a decimal or 0x-prefixed hex integer, which may be negative
+fn parse_integer(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if digits.to_lowercase().starts_with("0x") {
+        u64::from_str_radix(&digits[2..], 16).ok()? as i64
+    } else {
+        digits.parse::<u64>().ok()? as i64
+    };
+    Some(if negative { value.wrapping_neg() } else { value })
+}
+
+// the opposite of format_value: turns text into the bytes of a value of the given type
+fn parse_value(dtype: &Type, text: &str) -> Option<Vec<u8>> {
+    let bytes = match dtype.kind {
+        TypeKind::Signed | TypeKind::Unsigned | TypeKind::Pointer => parse_integer(text)?.to_le_bytes(),
+        TypeKind::Char => match text.strip_prefix('\'').and_then(|text| text.strip_suffix('\'')) {
+            Some(c) if c.len() == 1 => (c.as_bytes()[0] as i64).to_le_bytes(),
+            _ => parse_integer(text)?.to_le_bytes(),
+        },
+        TypeKind::Bool => match text {
+            "true" | "1" => 1u64.to_le_bytes(),
+            "false" | "0" => 0u64.to_le_bytes(),
+            _ => return None,
+        },
+        TypeKind::Float if dtype.size == 4 => (text.parse::<f32>().ok()?.to_bits() as u64).to_le_bytes(),
+        TypeKind::Float if dtype.size == 8 => text.parse::<f64>().ok()?.to_bits().to_le_bytes(),
+        _ => return None,
+    };
+    if dtype.size > bytes.len() {
+        return None;
+    }
+    Some(bytes[..dtype.size].to_vec())
+}
@@ -1,33 +1,240 @@
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+
+#[derive(Clone)]
 pub enum DebuggerCommand {
     Quit,
-    Run(Vec<String>),
-    Continue,
+    // None to use the arguments of the previous run
+    Run(Option<Vec<String>>),
+    Attach(i32),
+    // how many breakpoint stops to go through
+    Continue(usize),
+    Step,
+    Next,
+    StepInstruction,
+    NextInstruction,
+    Finish,
     Backtrace,
+    Up,
+    Down,
     Breakpoint(String),
+    TempBreakpoint(String),
+    Delete(usize),
+    Enable(usize),
+    Disable(usize),
+    Ignore(usize, usize),
+    InfoBreakpoints,
+    InfoLocals,
+    InfoArgs,
+    InfoRegisters(Option<String>),
+    // name and value, from `set $reg = value` and `set var name = value`
+    SetRegister(String, String),
+    SetVariable(String, String),
+    Print(String),
+    Watch(String),
+    // count, format ('x' or 'd') and address
+    Examine(usize, char, String),
+    // what to list around, or None to carry on from the last listing (or the stop location)
+    List(Option<String>),
+    // signal name, and the stop and pass settings to change (None to leave one as it is)
+    Handle(String, Option<bool>, Option<bool>),
 }
 
 impl DebuggerCommand {
     pub fn from_tokens(tokens: &Vec<&str>) -> Option<DebuggerCommand> {
         match tokens[0] {
             "q" | "quit" => Some(DebuggerCommand::Quit),
-            "r" | "run" => {
+            "r" | "run" | "restart" => {
                 let args = tokens[1..].to_vec();
-                Some(DebuggerCommand::Run(
+                if args.is_empty() {
+                    return Some(DebuggerCommand::Run(None));
+                }
+                Some(DebuggerCommand::Run(Some(
                     args.iter().map(|s| s.to_string()).collect(),
-                ))
+                )))
+            },
+            "attach" => {
+                let pid = tokens.get(1)?.parse().ok()?;
+                Some(DebuggerCommand::Attach(pid))
             },
             "c" | "continue" | "cont"=> {
-                Some(DebuggerCommand::Continue)
+                let count = match tokens.get(1) {
+                    Some(count) => count.parse().ok().filter(|count| *count > 0)?,
+                    None => 1,
+                };
+                Some(DebuggerCommand::Continue(count))
+            },
+            "s" | "step" => {
+                Some(DebuggerCommand::Step)
+            },
+            "n" | "next" => {
+                Some(DebuggerCommand::Next)
+            },
+            "si" | "stepi" => {
+                Some(DebuggerCommand::StepInstruction)
+            },
+            "ni" | "nexti" => {
+                Some(DebuggerCommand::NextInstruction)
+            },
+            "fin" | "finish" => {
+                Some(DebuggerCommand::Finish)
             },
             "bt" | "back" | "backtrace" => {
                 Some(DebuggerCommand::Backtrace)
             },
+            "up" => Some(DebuggerCommand::Up),
+            "down" => Some(DebuggerCommand::Down),
             "b" | "breakpoint" | "break"  => {
-                let arg = tokens[1].to_string();
+                let arg = tokens.get(1)?.to_string();
                 Some(DebuggerCommand::Breakpoint(arg))
             }
+            "tb" | "tbreak" => {
+                let arg = tokens.get(1)?.to_string();
+                Some(DebuggerCommand::TempBreakpoint(arg))
+            }
+            "d" | "delete" => {
+                let id = tokens.get(1)?.parse().ok()?;
+                Some(DebuggerCommand::Delete(id))
+            }
+            "enable" => {
+                let id = tokens.get(1)?.parse().ok()?;
+                Some(DebuggerCommand::Enable(id))
+            }
+            "disable" => {
+                let id = tokens.get(1)?.parse().ok()?;
+                Some(DebuggerCommand::Disable(id))
+            }
+            "p" | "print" => {
+                let name = tokens.get(1)?.to_string();
+                Some(DebuggerCommand::Print(name))
+            }
+            "ignore" => {
+                let id = tokens.get(1)?.parse().ok()?;
+                let count = tokens.get(2)?.parse().ok()?;
+                Some(DebuggerCommand::Ignore(id, count))
+            }
+            "watch" => {
+                let name = tokens.get(1)?.to_string();
+                Some(DebuggerCommand::Watch(name))
+            }
+            command if command == "x" || command.starts_with("x/") => {
+                // x/<count><format>, where both parts are optional
+                let spec = command.strip_prefix("x").unwrap().trim_start_matches('/');
+                let digits = spec.chars().take_while(|c| c.is_ascii_digit()).count();
+                let count = if digits == 0 { 1 } else { spec[..digits].parse().ok()? };
+                let format = match &spec[digits..] {
+                    "" | "x" => 'x',
+                    "d" => 'd',
+                    _ => return None,
+                };
+                let addr = tokens.get(1)?.to_string();
+                Some(DebuggerCommand::Examine(count, format, addr))
+            }
+            "set" => {
+                let assignment = tokens[1..].join(" ");
+                let (target, value) = assignment.split_once('=')?;
+                let (target, value) = (target.trim(), value.trim().to_string());
+                if let Some(register) = target.strip_prefix('$') {
+                    Some(DebuggerCommand::SetRegister(register.to_string(), value))
+                } else {
+                    let name = target.strip_prefix("var ")?.trim().to_string();
+                    Some(DebuggerCommand::SetVariable(name, value))
+                }
+            }
+            "l" | "list" => Some(DebuggerCommand::List(tokens.get(1).map(|arg| arg.to_string()))),
+            "handle" => {
+                let name = tokens.get(1)?.to_string();
+                let (mut stop, mut pass) = (None, None);
+                for action in tokens.iter().skip(2) {
+                    match *action {
+                        "stop" => stop = Some(true),
+                        "nostop" => stop = Some(false),
+                        "pass" => pass = Some(true),
+                        "nopass" => pass = Some(false),
+                        _ => return None,
+                    }
+                }
+                Some(DebuggerCommand::Handle(name, stop, pass))
+            }
+            "i" | "info" => {
+                match *tokens.get(1)? {
+                    "b" | "break" | "breakpoints" => Some(DebuggerCommand::InfoBreakpoints),
+                    "locals" => Some(DebuggerCommand::InfoLocals),
+                    "args" => Some(DebuggerCommand::InfoArgs),
+                    "r" | "reg" | "registers" => {
+                        let name = tokens.get(2).map(|name| name.trim_start_matches('$').to_string());
+                        Some(DebuggerCommand::InfoRegisters(name))
+                    }
+                    _ => None,
+                }
+            }
             // Default case:
             _ => None,
         }
     }
-}
\ No newline at end of file
+
+    /// Whether pressing enter on an empty line should run the command again.
+    pub fn repeatable(&self) -> bool {
+        matches!(
+            self,
+            DebuggerCommand::Continue(_)
+                | DebuggerCommand::Step
+                | DebuggerCommand::Next
+                | DebuggerCommand::StepInstruction
+                | DebuggerCommand::NextInstruction
+        )
+    }
+}
+
+/// The command keywords that tab completes at the start of a line
+const COMMAND_NAMES: [&str; 25] = [
+    "attach", "backtrace", "break", "continue", "delete", "disable", "down", "enable", "finish", "handle", "ignore",
+    "info", "list", "next", "nexti", "print", "quit", "restart", "run", "set", "step", "stepi", "tbreak",
+    "up", "watch",
+];
+
+/// Tab completion for the readline prompt: command keywords, and function names after `break`.
+pub struct CommandCompleter {
+    functions: Vec<String>,
+}
+
+impl CommandCompleter {
+    pub fn new(functions: Vec<String>) -> CommandCompleter {
+        CommandCompleter { functions }
+    }
+}
+
+impl Completer for CommandCompleter {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let line = &line[..pos];
+        let start = line.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let word = &line[start..];
+        let words: Vec<&str> = line[..start].split_whitespace().collect();
+        let options: Vec<&str> = match words[..] {
+            [] => COMMAND_NAMES.to_vec(),
+            ["b" | "break" | "breakpoint" | "tb" | "tbreak"] => self.functions.iter().map(String::as_str).collect(),
+            _ => Vec::new(),
+        };
+        let candidates = options
+            .into_iter()
+            .filter(|option| option.starts_with(word))
+            .map(str::to_string)
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for CommandCompleter {
+    type Hint = String;
+}
+
+impl Highlighter for CommandCompleter {}
+
+impl Validator for CommandCompleter {}
+
+impl Helper for CommandCompleter {}
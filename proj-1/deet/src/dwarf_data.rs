@@ -13,6 +13,10 @@ pub enum Error {
 pub struct DwarfData {
     files: Vec<File>,
     addr2line: Context<addr2line::gimli::EndianRcSlice<addr2line::gimli::RunTimeEndian>>,
+    // a position-independent executable is loaded at a different address on each run
+    position_independent: bool,
+    // what to add to the addresses in the debugging info to get the inferior's runtime addresses
+    load_bias: usize,
 }
 
 impl fmt::Debug for DwarfData {
@@ -41,9 +45,30 @@ impl DwarfData {
         Ok(DwarfData {
             files: gimli_wrapper::load_file(&object, endian)?,
             addr2line: Context::new(&object).or_else(|e| Err(gimli_wrapper::Error::from(e)))?,
+            position_independent: object.kind() == object::ObjectKind::Dynamic,
+            load_bias: 0,
         })
     }
 
+    pub fn position_independent(&self) -> bool {
+        self.position_independent
+    }
+
+    pub fn load_bias(&self) -> usize {
+        self.load_bias
+    }
+
+    /// Sets where the inferior has the target loaded. All the addresses taken and returned from
+    /// here on are runtime addresses, offset by `load_bias` from the ones in the debugging info.
+    pub fn set_load_bias(&mut self, load_bias: usize) {
+        self.load_bias = load_bias;
+    }
+
+    // the address in the debugging info for a runtime address, if it can be in the target at all
+    fn to_static(&self, addr: usize) -> Option<usize> {
+        addr.checked_sub(self.load_bias)
+    }
+
     #[allow(dead_code)]
     fn get_target_file(&self, file: &str) -> Option<&File> {
         self.files.iter().find(|f| {
@@ -55,14 +80,20 @@ impl DwarfData {
     pub fn get_addr_for_line(&self, file: Option<&str>, line_number: usize) -> Option<usize> {
         let target_file = match file {
             Some(filename) => self.get_target_file(filename)?,
-            None => self.files.get(0)?,
+            // default to the file with main in it
+            None => self
+                .files
+                .iter()
+                .find(|f| f.functions.iter().any(|func| func.name == "main" && func.text_length > 0))
+                .or(self.files.get(0))?,
         };
         Some(
             target_file
                 .lines
                 .iter()
                 .find(|line| line.number >= line_number)?
-                .address,
+                .address
+                + self.load_bias,
         )
     }
 
@@ -74,12 +105,13 @@ impl DwarfData {
                     .functions
                     .iter()
                     .find(|func| func.name == func_name)?
-                    .address,
+                    .address
+                    + self.load_bias,
             ),
             None => {
                 for file in &self.files {
                     if let Some(func) = file.functions.iter().find(|func| func.name == func_name) {
-                        return Some(func.address);
+                        return Some(func.address + self.load_bias);
                     }
                 }
                 None
@@ -87,11 +119,41 @@ impl DwarfData {
         }
     }
 
+    /// Returns where the body of the function starting at `func_addr` begins, after the prologue
+    /// that sets up its stack frame: the first line table entry after the function's entry point
+    /// that is on a later line than its declaration (or just the next entry, for a function all
+    /// on one line). Addresses that aren't the start of a known function are returned unchanged.
+    pub fn skip_prologue(&self, func_addr: usize) -> usize {
+        let static_addr = match self.to_static(func_addr) {
+            Some(addr) => addr,
+            None => return func_addr,
+        };
+        for file in &self.files {
+            let func = match file.functions.iter().find(|func| func.address == static_addr && func.text_length > 0) {
+                Some(func) => func,
+                None => continue,
+            };
+            let end = func.address + func.text_length;
+            let mut body: Vec<&Line> = file
+                .lines
+                .iter()
+                .filter(|line| func.address < line.address && line.address < end)
+                .collect();
+            body.sort_by_key(|line| line.address);
+            return body
+                .iter()
+                .find(|line| line.number > func.line_number)
+                .or(body.first())
+                .map_or(func_addr, |line| line.address + self.load_bias);
+        }
+        func_addr
+    }
+
     #[allow(dead_code)]
     pub fn get_line_from_addr(&self, curr_addr: usize) -> Option<Line> {
         let location = self
             .addr2line
-            .find_location(curr_addr.try_into().unwrap())
+            .find_location(self.to_static(curr_addr)?.try_into().unwrap())
             .ok()??;
         Some(Line {
             file: location.file?.to_string(),
@@ -104,13 +166,44 @@ impl DwarfData {
     pub fn get_function_from_addr(&self, curr_addr: usize) -> Option<String> {
         let frame = self
             .addr2line
-            .find_frames(curr_addr.try_into().unwrap())
+            .find_frames(self.to_static(curr_addr)?.try_into().unwrap())
             .ok()?
             .next()
             .ok()??;
         Some(frame.function?.raw_name().ok()?.to_string())
     }
 
+    /// Returns the function whose code contains `curr_addr`, with its variables. The function's
+    /// own address is as it is in the debugging info, without the load bias.
+    pub fn get_function(&self, curr_addr: usize) -> Option<&Function> {
+        let curr_addr = self.to_static(curr_addr)?;
+        self.files.iter().flat_map(|file| &file.functions).find(|func| {
+            func.address <= curr_addr && curr_addr < func.address + func.text_length
+        })
+    }
+
+    /// Returns the names of the functions defined in the target, leaving out ones that are only
+    /// declared (like library functions).
+    pub fn function_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .files
+            .iter()
+            .flat_map(|file| &file.functions)
+            .filter(|func| func.text_length > 0)
+            .map(|func| func.name.clone())
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    pub fn get_global_variable(&self, name: &str) -> Option<&Variable> {
+        self.files
+            .iter()
+            .flat_map(|file| &file.global_variables)
+            .find(|var| var.name == name)
+    }
+
     #[allow(dead_code)]
     pub fn print(&self) {
         for file in &self.files {
@@ -152,17 +245,32 @@ impl DwarfData {
 pub struct Type {
     pub name: String,
     pub size: usize,
+    pub kind: TypeKind,
 }
 
 impl Type {
-    pub fn new(name: String, size: usize) -> Self {
+    pub fn new(name: String, size: usize, kind: TypeKind) -> Self {
         Type {
             name: name,
             size: size,
+            kind: kind,
         }
     }
 }
 
+// How the bytes of a value of some type are to be read
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TypeKind {
+    Signed,
+    Unsigned,
+    Char,
+    Bool,
+    Float,
+    Pointer,
+    #[default]
+    Other,
+}
+
 #[derive(Clone)]
 pub enum Location {
     Address(usize),
@@ -188,6 +296,7 @@ impl fmt::Debug for Location {
 #[derive(Debug, Clone)]
 pub struct Variable {
     pub name: String,
+    pub parameter: bool, // Whether this is one of the function's arguments
     pub entity_type: Type,
     pub location: Location,
     pub line_number: usize, // Line number in source file
@@ -200,6 +309,7 @@ pub struct Function {
     pub text_length: usize,
     pub line_number: usize, // Line number in source file
     pub variables: Vec<Variable>,
+    pub return_type: Option<Type>, // None for a void function
 }
 
 #[derive(Debug, Default, Clone)]
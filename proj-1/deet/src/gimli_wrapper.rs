@@ -10,7 +10,7 @@ use gimli::{UnitOffset, UnitSectionOffset};
 use object::{Object, ObjectSection};
 use std::borrow;
 //use std::io::{BufWriter, Write};
-use crate::dwarf_data::{File, Function, Line, Location, Type, Variable};
+use crate::dwarf_data::{File, Function, Line, Location, Type, TypeKind, Variable};
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fmt::Write;
@@ -52,6 +52,9 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
     while let Some(header) = iter.next()? {
         let unit = dwarf.unit(header)?;
 
+        // Variables can refer to types declared further down, so get those first
+        load_types(&unit, &dwarf, &mut offset_to_type)?;
+
         // Iterate over the Debugging Information Entries (DIEs) in the unit.
         let mut depth = 0;
         let mut entries = unit.entries();
@@ -77,33 +80,6 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                         lines: Vec::new(),
                     });
                 }
-                gimli::DW_TAG_base_type => {
-                    let name = if let Ok(Some(attr)) = entry.attr(gimli::DW_AT_name) {
-                        if let Ok(DebugValue::Str(name)) = get_attr_value(&attr, &unit, &dwarf) {
-                            name
-                        } else {
-                            "<unknown>".to_string()
-                        }
-                    } else {
-                        "<unknown>".to_string()
-                    };
-                    let byte_size = if let Ok(Some(attr)) = entry.attr(gimli::DW_AT_byte_size) {
-                        if let Ok(DebugValue::Uint(byte_size)) =
-                            get_attr_value(&attr, &unit, &dwarf)
-                        {
-                            byte_size
-                        } else {
-                            // TODO: report error?
-                            0
-                        }
-                    } else {
-                        // TODO: report error?
-                        0
-                    };
-                    let type_offset = entry.offset().0;
-                    offset_to_type
-                        .insert(type_offset, Type::new(name, byte_size.try_into().unwrap()));
-                }
                 gimli::DW_TAG_subprogram => {
                     let mut func: Function = Default::default();
                     let mut attrs = entry.attrs();
@@ -132,6 +108,11 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                                     func.line_number = line_number.try_into().unwrap();
                                 }
                             }
+                            gimli::DW_AT_type => {
+                                if let Ok(DebugValue::Size(offset)) = val {
+                                    func.return_type = offset_to_type.get(&offset).cloned();
+                                }
+                            }
                             _ => {}
                         }
                     }
@@ -175,6 +156,7 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                     if entity_type.is_some() && location.is_some() {
                         let var = Variable {
                             name,
+                            parameter: entry.tag() == gimli::DW_TAG_formal_parameter,
                             entity_type: entity_type.unwrap(),
                             location: location.unwrap(),
                             line_number: line_number.try_into().unwrap(),
@@ -250,6 +232,116 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
     Ok(compilation_units)
 }
 
+/// A type DIE, before the types it refers to have been looked up
+enum RawType {
+    Base(Type),
+    Pointer(Option<usize>),
+    // const/volatile qualifiers (with the prefix to add to the name) and typedefs (with the new
+    // name), which are otherwise the same as the type they refer to
+    Qualified(String, Option<usize>),
+    Typedef(String, Option<usize>),
+}
+
+/// Adds the base, pointer, const/volatile and typedef types of the unit to `offset_to_type`,
+/// keyed by their offset in .debug_info like the DW_AT_type references to them.
+fn load_types<R: Reader>(
+    unit: &gimli::Unit<R>,
+    dwarf: &gimli::Dwarf<R>,
+    offset_to_type: &mut HashMap<usize, Type>,
+) -> Result<(), Error> {
+    let mut raw_types: HashMap<usize, RawType> = HashMap::new();
+    let mut entries = unit.entries();
+    while let Some((_, entry)) = entries.next_dfs()? {
+        let name = match entry.attr(gimli::DW_AT_name) {
+            Ok(Some(attr)) => match get_attr_value(&attr, unit, dwarf) {
+                Ok(DebugValue::Str(name)) => Some(name),
+                _ => None,
+            },
+            _ => None,
+        };
+        let target = match entry.attr(gimli::DW_AT_type) {
+            Ok(Some(attr)) => match get_attr_value(&attr, unit, dwarf) {
+                Ok(DebugValue::Size(offset)) => Some(offset),
+                _ => None,
+            },
+            _ => None,
+        };
+        let raw_type = match entry.tag() {
+            gimli::DW_TAG_base_type => {
+                let byte_size = match entry.attr_value(gimli::DW_AT_byte_size) {
+                    Ok(Some(gimli::AttributeValue::Udata(size))) => size as usize,
+                    _ => 0,
+                };
+                let kind = match entry.attr_value(gimli::DW_AT_encoding) {
+                    Ok(Some(gimli::AttributeValue::Encoding(encoding))) => match encoding {
+                        gimli::DW_ATE_signed => TypeKind::Signed,
+                        gimli::DW_ATE_unsigned => TypeKind::Unsigned,
+                        gimli::DW_ATE_signed_char | gimli::DW_ATE_unsigned_char => TypeKind::Char,
+                        gimli::DW_ATE_boolean => TypeKind::Bool,
+                        gimli::DW_ATE_float => TypeKind::Float,
+                        _ => TypeKind::Other,
+                    },
+                    _ => TypeKind::Other,
+                };
+                let name = name.unwrap_or_else(|| "<unknown>".to_string());
+                RawType::Base(Type::new(name, byte_size, kind))
+            }
+            gimli::DW_TAG_pointer_type => RawType::Pointer(target),
+            gimli::DW_TAG_const_type => RawType::Qualified("const".to_string(), target),
+            gimli::DW_TAG_volatile_type => RawType::Qualified("volatile".to_string(), target),
+            gimli::DW_TAG_typedef => {
+                RawType::Typedef(name.unwrap_or_else(|| "<unknown>".to_string()), target)
+            }
+            _ => continue,
+        };
+        let offset = match entry.offset().to_unit_section_offset(unit) {
+            UnitSectionOffset::DebugInfoOffset(offset) => offset.0,
+            UnitSectionOffset::DebugTypesOffset(offset) => offset.0,
+        };
+        raw_types.insert(offset, raw_type);
+    }
+
+    for offset in raw_types.keys() {
+        if let Some(dtype) = resolve_type(*offset, &raw_types, 0) {
+            offset_to_type.insert(*offset, dtype);
+        }
+    }
+    Ok(())
+}
+
+/// Works out the type at `offset`, following pointers, qualifiers and typedefs. `depth` guards
+/// against reference cycles in malformed debug info.
+fn resolve_type(offset: usize, raw_types: &HashMap<usize, RawType>, depth: usize) -> Option<Type> {
+    if depth > 32 {
+        return None;
+    }
+    let resolve_target = |target: &Option<usize>| match target {
+        Some(target) => resolve_type(*target, raw_types, depth + 1),
+        // a reference to nothing means void
+        None => Some(Type::new("void".to_string(), 0, TypeKind::Other)),
+    };
+    match raw_types.get(&offset)? {
+        RawType::Base(dtype) => Some(dtype.clone()),
+        RawType::Pointer(target) => {
+            // pointers to structs and such still get a type, they just can't be followed
+            let pointee = resolve_target(target).map_or("<unknown>".to_string(), |t| t.name);
+            Some(Type::new(
+                if pointee.ends_with('*') { format!("{}*", pointee) } else { format!("{} *", pointee) },
+                std::mem::size_of::<usize>(),
+                TypeKind::Pointer,
+            ))
+        }
+        RawType::Qualified(qualifier, target) => {
+            let dtype = resolve_target(target)?;
+            Some(Type::new(format!("{} {}", qualifier, dtype.name), dtype.size, dtype.kind))
+        }
+        RawType::Typedef(name, target) => {
+            let dtype = resolve_target(target)?;
+            Some(Type::new(name.clone(), dtype.size, dtype.kind))
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum DebugValue {
     Str(String),
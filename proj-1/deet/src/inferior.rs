@@ -10,7 +10,7 @@ use crate::dwarf_data::DwarfData;
 
 use std::mem::size_of;
 
-use crate::debugger::Breakpoint;
+use crate::debugger::{Breakpoint, SignalAction};
 
 pub enum Status {
     /// Indicates inferior stopped. Contains the signal that stopped the process, as well as the
@@ -25,6 +25,19 @@ pub enum Status {
     Signaled(signal::Signal),
 }
 
+/// How many frames print_backtrace shows at most
+const MAX_BACKTRACE_DEPTH: usize = 128;
+
+/// A function call on the stack
+#[derive(Clone, Copy)]
+pub struct Frame {
+    /// Where execution is in the function: rip for the innermost frame, the return address into
+    /// it for the others
+    pub rip: usize,
+    /// The canonical frame address, see `Inferior::frame_address`
+    pub cfa: usize,
+}
+
 /// This function calls ptrace with PTRACE_TRACEME to enable debugging on a process. You should use
 /// pre_exec with Command to call this in the child process.
 fn child_traceme() -> Result<(), std::io::Error> {
@@ -35,13 +48,19 @@ fn child_traceme() -> Result<(), std::io::Error> {
 }
 
 pub struct Inferior {
-    child: Child,
+    /// The process we started, or None if we attached to one that was already running
+    child: Option<Child>,
+    pid: Pid,
+    /// How to handle each signal, see the `handle` command
+    signal_actions: HashMap<signal::Signal, SignalAction>,
+    /// A signal the inferior got that is to be delivered to it when it is next continued
+    pending_signal: Option<signal::Signal>,
 }
 
 impl Inferior {
     /// Attempts to start a new inferior process. Returns Some(Inferior) if successful, or None if
     /// an error is encountered.
-    pub fn new(target: &str, args: &Vec<String>, breakpoints: &mut HashMap<usize,Breakpoint>) -> Option<Inferior> {
+    pub fn new(target: &str, args: &Vec<String>, signal_actions: &HashMap<signal::Signal, SignalAction>) -> Option<Inferior> {
         // TODO: implement me!
 
         let mut command = std::process::Command::new(target);
@@ -55,44 +74,113 @@ impl Inferior {
             Err(_) => return None
         };
 
-        let mut inferior = Inferior{child : child};
-
-        match waitpid(Pid::from_raw(inferior.child.id() as i32), None) {
-            Ok(WaitStatus::Stopped(_, nix::sys::signal::SIGTRAP)) =>  {
-                // store the origal byte and replace it with 0xcc
-                for (addr, breakpoint) in breakpoints {
-                    match inferior.write_byte(*addr, 0xcc) {
-                        Ok(orig_byte) => {
-                            *breakpoint = Breakpoint {
-                                addr: *addr,
-                                orig_byte,
-                            };
-                        }
-                        Err(_) => println!("Inferior::new can't write_byte {}", addr),
-                    }
+        let inferior = Inferior{pid : Pid::from_raw(child.id() as i32), child : Some(child), signal_actions : signal_actions.clone(), pending_signal : None};
+
+        match waitpid(inferior.pid(), None) {
+            Ok(WaitStatus::Stopped(_, nix::sys::signal::SIGTRAP)) => Some(inferior),
+            _ => None
+        }
+    }
+
+    /// Attaches to the already-running process `pid` and waits for it to stop. Prints why and
+    /// returns None if the process can't be traced (it doesn't exist, or we lack the permission).
+    pub fn attach(pid: Pid, signal_actions: &HashMap<signal::Signal, SignalAction>) -> Option<Inferior> {
+        if let Err(e) = ptrace::attach(pid) {
+            println!("Error attaching to process {}: {}", pid, e);
+            return None;
+        }
+        let inferior = Inferior{pid, child : None, signal_actions : signal_actions.clone(), pending_signal : None};
+        match waitpid(pid, None) {
+            Ok(WaitStatus::Stopped(_, _)) => Some(inferior),
+            _ => {
+                println!("Process {} didn't stop after attaching", pid);
+                None
+            }
+        }
+    }
+
+    /// Stores the original byte under each enabled breakpoint and replaces it with 0xcc.
+    pub fn insert_breakpoints(&mut self, breakpoints: &mut HashMap<usize,Breakpoint>) {
+        for (addr, breakpoint) in breakpoints.iter_mut().filter(|(_, bp)| bp.enabled) {
+            match self.write_byte(*addr, 0xcc) {
+                Ok(orig_byte) => {
+                    breakpoint.orig_byte = orig_byte;
                 }
-                Some(inferior)
+                Err(_) => println!("Inferior can't write_byte {}", addr),
             }
-            _ => None
         }
     }
 
+    /// Returns the memory address whose access made the inferior stop with SIGSEGV or SIGBUS.
+    pub fn fault_address(&self) -> Result<usize, nix::Error> {
+        let siginfo = ptrace::getsiginfo(self.pid())?;
+        Ok(unsafe { siginfo.si_addr() } as usize)
+    }
+
+    /// Changes how the inferior's `signal` is handled from now on.
+    pub fn set_signal_action(&mut self, signal: signal::Signal, action: SignalAction) {
+        self.signal_actions.insert(signal, action);
+    }
+
+    /// Returns how `signal` is handled: stopped at and passed on unless told otherwise.
+    fn signal_action(&self, signal: signal::Signal) -> SignalAction {
+        self.signal_actions.get(&signal).copied().unwrap_or(SignalAction { stop: true, pass: true })
+    }
+
+    /// Returns the address the target executable is mapped at in the inferior, which is the load
+    /// bias of a position-independent executable.
+    pub fn load_address(&self) -> Result<usize, std::io::Error> {
+        let exe = std::fs::read_link(format!("/proc/{}/exe", self.pid()))?;
+        let maps = std::fs::read_to_string(format!("/proc/{}/maps", self.pid()))?;
+        // the mappings are in address order, so the first one of the executable is the lowest
+        for mapping in maps.lines() {
+            let fields: Vec<&str> = mapping.split_whitespace().collect();
+            if fields.len() < 6 || std::path::Path::new(fields[5]) != exe {
+                continue;
+            }
+            let start = fields[0].split('-').next().unwrap();
+            return usize::from_str_radix(start, 16)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e));
+        }
+        Err(std::io::Error::new(std::io::ErrorKind::NotFound, "executable isn't mapped"))
+    }
+
+    /// Returns true if this inferior was attached to rather than started by the debugger.
+    pub fn attached(&self) -> bool {
+        self.child.is_none()
+    }
+
+    /// Removes the breakpoints and watchpoints from an attached process and lets it go on
+    /// running without the debugger.
+    pub fn detach(&mut self, breakpoints: &HashMap<usize,Breakpoint>) -> Result<(), nix::Error> {
+        println!("Detaching from process {}", self.pid());
+        for (addr, breakpoint) in breakpoints.iter().filter(|(_, bp)| bp.enabled) {
+            self.write_byte(*addr, breakpoint.orig_byte)?;
+        }
+        self.write_debug_register(7, 0)?;
+        ptrace::detach(self.pid(), None)
+    }
+
     pub fn run(&mut self){
         // ptrace::cont(self.pid(), None).unwrap();
     }
 
     /// Returns the pid of this inferior.
     pub fn pid(&self) -> Pid {
-        nix::unistd::Pid::from_raw(self.child.id() as i32)
+        self.pid
     }
 
     /// Calls waitpid on this inferior and returns a Status to indicate the state of the process
     /// after the waitpid call.
-    pub fn wait(&self, options: Option<WaitPidFlag>) -> Result<Status, nix::Error> {
+    pub fn wait(&mut self, options: Option<WaitPidFlag>) -> Result<Status, nix::Error> {
         Ok(match waitpid(self.pid(), options)? {
             WaitStatus::Exited(_pid, exit_code) => Status::Exited(exit_code),
             WaitStatus::Signaled(_pid, signal, _core_dumped) => Status::Signaled(signal),
             WaitStatus::Stopped(_pid, signal) => {
+                // SIGTRAP is the debugger's own, from breakpoints and single steps
+                if signal != signal::Signal::SIGTRAP && self.signal_action(signal).pass {
+                    self.pending_signal = Some(signal);
+                }
                 let regs = ptrace::getregs(self.pid())?;
                 Status::Stopped(signal, regs.rip as usize)
             }
@@ -101,71 +189,392 @@ impl Inferior {
     }
 
     pub fn continue_exec(&mut self,breakpoints:&mut HashMap<usize,Breakpoint>) -> Result<Status, nix::Error> {
-        // // if the execution is stopped by a breakpoint, restore the original byte
-        if let Some(rip) = self.get_rip() {
-            if self.check_at_breakpoint(rip - 1, breakpoints) {
-                println!("Stopped at breakpoint");
-                let breakpoint = breakpoints.get(&(rip - 1)).unwrap();
-                let orig_byte = breakpoint.orig_byte;
-
-                self.write_byte(rip - 1, orig_byte).ok();
-
-                self.set_rip(rip - 1); 
-
-                ptrace::step(self.pid(), None)?;
-
-                match self.wait(None).unwrap() {
-                    Status::Stopped(_, _) => {
-                        self.write_byte(rip - 1, 0xcc).unwrap();
-                    },
-                    Status::Exited(signal) => {
-                        return Ok(Status::Exited(signal));
-                    },
-                    Status::Signaled(signal) => {
-                        return Ok(Status::Signaled(signal));
-                    },
+        // if the execution is stopped at a breakpoint, get past it first
+        let rip = self.get_rip()?;
+        if self.check_at_breakpoint(rip, breakpoints) {
+            match self.step_instruction(breakpoints)? {
+                Status::Stopped(_, _) => {},
+                status => return Ok(status),
+            }
+        }
+
+        loop {
+            ptrace::cont(self.pid(), self.pending_signal.take())?;
+            let status = self.wait_for_breakpoint(breakpoints)?;
+            if let Status::Stopped(signal, _) = status {
+                if signal != signal::Signal::SIGTRAP && !self.signal_action(signal).stop {
+                    continue;
+                }
+            }
+            if let Status::Stopped(signal::Signal::SIGTRAP, rip) = status {
+                // a breakpoint told to ignore some hits lets the inferior carry on
+                if let Some(breakpoint) = breakpoints.get_mut(&rip).filter(|bp| bp.ignore_count > 0) {
+                    breakpoint.ignore_count -= 1;
+                    match self.step_instruction(breakpoints)? {
+                        Status::Stopped(_, _) => continue,
+                        status => return Ok(status),
+                    }
                 }
-            }   
+            }
+            return Ok(status);
         }
+    }
 
-        ptrace::cont(self.pid(), None)?;
+    /// Waits for the inferior to stop. If it stopped because it ran into a breakpoint, rip is
+    /// moved back onto the breakpoint, so that it points at the instruction the 0xcc replaced,
+    /// and the hit is counted.
+    fn wait_for_breakpoint(&mut self, breakpoints: &mut HashMap<usize, Breakpoint>) -> Result<Status, nix::Error> {
+        match self.wait(None)? {
+            Status::Stopped(signal::Signal::SIGTRAP, rip) if self.check_at_breakpoint(rip - 1, breakpoints) => {
+                self.set_rip(rip - 1)?;
+                breakpoints.get_mut(&(rip - 1)).unwrap().hit_count += 1;
+                Ok(Status::Stopped(signal::Signal::SIGTRAP, rip - 1))
+            }
+            status => Ok(status),
+        }
+    }
+
+    /// Executes exactly one instruction. If rip is sitting on a breakpoint, the original byte is
+    /// put back for the step and the 0xcc reinserted afterwards.
+    fn step_instruction(&mut self, breakpoints: &HashMap<usize, Breakpoint>) -> Result<Status, nix::Error> {
+        let rip = self.get_rip()?;
+        if let Some(breakpoint) = breakpoints.get(&rip).filter(|bp| bp.enabled) {
+            self.write_byte(rip, breakpoint.orig_byte)?;
+            ptrace::step(self.pid(), None)?;
+            let status = self.wait(None)?;
+            if let Status::Stopped(_, _) = status {
+                self.write_byte(rip, 0xcc)?;
+            }
+            return Ok(status);
+        }
+        ptrace::step(self.pid(), None)?;
         self.wait(None)
     }
 
+    /// Runs until execution gets back to `addr` with the stack pointer at or above `frame_sp`
+    /// (so a recursive call passing through `addr` doesn't count), using a temporary breakpoint.
+    /// Stops early if the inferior hits one of the user's breakpoints, gets a signal or exits.
+    fn run_to(&mut self, addr: usize, frame_sp: usize, breakpoints: &mut HashMap<usize, Breakpoint>) -> Result<Status, nix::Error> {
+        let needs_breakpoint = !self.check_at_breakpoint(addr, breakpoints);
+        // the user may have a disabled breakpoint there, which is switched on for the while
+        let disabled = breakpoints.contains_key(&addr);
+        if needs_breakpoint {
+            let orig_byte = self.write_byte(addr, 0xcc)?;
+            if disabled {
+                breakpoints.get_mut(&addr).unwrap().enabled = true;
+            } else {
+                // the id is never shown, as the breakpoint is gone again before we return
+                breakpoints.insert(addr, Breakpoint { id: 0, addr, orig_byte, enabled: true, temporary: false, hit_count: 0, ignore_count: 0, relocatable: false });
+            }
+        }
+        let status = loop {
+            let status = self.continue_exec(breakpoints);
+            if let Ok(Status::Stopped(signal::Signal::SIGTRAP, rip)) = status {
+                if rip == addr && (ptrace::getregs(self.pid())?.rsp as usize) < frame_sp {
+                    continue;
+                }
+            }
+            break status;
+        };
+        if needs_breakpoint {
+            let orig_byte = if disabled {
+                let breakpoint = breakpoints.get_mut(&addr).unwrap();
+                breakpoint.enabled = false;
+                breakpoint.orig_byte
+            } else {
+                breakpoints.remove(&addr).unwrap().orig_byte
+            };
+            // the inferior may have exited in the meantime
+            self.write_byte(addr, orig_byte).ok();
+        }
+        status
+    }
+
+    /// If the instruction that was just stepped (from `rip_before`, with the stack pointer at
+    /// `sp_before`) was a call, returns the address it will return to. A call pushes the address
+    /// of the instruction after it, which is at most 15 bytes (the longest x86 instruction) on.
+    fn call_return_addr(&self, rip_before: usize, sp_before: usize) -> Result<Option<usize>, nix::Error> {
+        let sp = ptrace::getregs(self.pid())?.rsp as usize;
+        if sp != sp_before - 8 {
+            return Ok(None);
+        }
+        let pushed = ptrace::read(self.pid(), sp as ptrace::AddressType)? as usize;
+        if pushed > rip_before && pushed <= rip_before + 15 {
+            Ok(Some(pushed))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns the canonical frame address of the current function: the stack pointer just
+    /// before it was called, which is right above its return address and what the locations of
+    /// its variables are relative to. Returns None if the current function isn't known from the
+    /// debugging info.
+    pub fn frame_address(&self, debug_data: &DwarfData) -> Result<Option<usize>, nix::Error> {
+        Ok(self.innermost_frame(debug_data)?.map(|(frame, _)| frame.cfa))
+    }
+
+    /// Returns the frame of the current function, along with the caller's rbp.
+    fn innermost_frame(&self, debug_data: &DwarfData) -> Result<Option<(Frame, usize)>, nix::Error> {
+        let regs = ptrace::getregs(self.pid())?;
+        let (rip, rsp, rbp) = (regs.rip as usize, regs.rsp as usize, regs.rbp as usize);
+        let mut frame_setup = match debug_data.get_function(rip) {
+            Some(func) => func.address + debug_data.load_bias(),
+            None => return Ok(None),
+        };
+        // skip the endbr64 some compilers start functions with
+        if ptrace::read(self.pid(), frame_setup as ptrace::AddressType)? as u32 == 0xfa1e0ff3 {
+            frame_setup += 4;
+        }
+        // until the function has done `push %rbp; mov %rsp,%rbp`, rbp still belongs to the
+        // caller, so work from rsp instead
+        let cfa = if rip <= frame_setup {
+            rsp + 8
+        } else if rip == frame_setup + 1 {
+            rsp + 16
+        } else {
+            rbp + 16
+        };
+        let caller_rbp = if rip <= frame_setup {
+            rbp
+        } else {
+            ptrace::read(self.pid(), (cfa - 16) as ptrace::AddressType)? as usize
+        };
+        Ok(Some((Frame { rip, cfa }, caller_rbp)))
+    }
+
+    /// Walks the stack from the current function out to main, following the saved rbp chain.
+    /// Stops early at a function without debugging info or a null rbp, and after one frame more
+    /// than `MAX_BACKTRACE_DEPTH` (so that print_backtrace can tell there are more), since a
+    /// corrupted stack could otherwise be followed forever. A saved rbp or return address that
+    /// can't be read ends the walk too, keeping the frames found so far.
+    pub fn frames(&self, debug_data: &DwarfData) -> Result<Vec<Frame>, nix::Error> {
+        let (mut frame, mut caller_rbp) = match self.innermost_frame(debug_data)? {
+            Some(innermost) => innermost,
+            None => return Ok(Vec::new()),
+        };
+        let mut frames = Vec::new();
+        loop {
+            frames.push(frame);
+            if frames.len() > MAX_BACKTRACE_DEPTH
+                || caller_rbp == 0
+                || debug_data.get_function(frame.rip).is_none_or(|func| func.name == "main")
+            {
+                return Ok(frames);
+            }
+            let return_addr = match ptrace::read(self.pid(), (frame.cfa - 8) as ptrace::AddressType) {
+                Ok(addr) => addr as usize,
+                Err(_) => return Ok(frames),
+            };
+            if debug_data.get_function(return_addr).is_none() {
+                return Ok(frames);
+            }
+            frame = Frame { rip: return_addr, cfa: caller_rbp + 16 };
+            caller_rbp = match ptrace::read(self.pid(), caller_rbp as ptrace::AddressType) {
+                Ok(rbp) => rbp as usize,
+                // the caller's frame is known, just not where the one outside it is
+                Err(_) => {
+                    frames.push(frame);
+                    return Ok(frames);
+                }
+            };
+        }
+    }
+
+    /// Runs until the current function returns to its caller. Returns None if the current
+    /// function isn't known from the debugging info, and otherwise the status along with the
+    /// address the function returns to (which it stops at unless something else stops it first).
+    pub fn finish(&mut self, breakpoints: &mut HashMap<usize, Breakpoint>, debug_data: &DwarfData) -> Result<Option<(Status, usize)>, nix::Error> {
+        let caller_sp = match self.frame_address(debug_data)? {
+            Some(addr) => addr,
+            None => return Ok(None),
+        };
+        let return_addr = ptrace::read(self.pid(), (caller_sp - 8) as ptrace::AddressType)? as usize;
+        self.run_to(return_addr, caller_sp, breakpoints).map(|status| Some((status, return_addr)))
+    }
+
+    /// Returns the bytes of the register a function returns its value in: xmm0 for floating
+    /// point values, rax for anything else.
+    pub fn return_value(&self, float: bool) -> Result<[u8; 8], nix::Error> {
+        if !float {
+            return Ok(ptrace::getregs(self.pid())?.rax.to_le_bytes());
+        }
+        // nix has no wrapper for PTRACE_GETFPREGS
+        let mut fpregs = std::mem::MaybeUninit::<libc::user_fpregs_struct>::uninit();
+        let result = unsafe { libc::ptrace(libc::PTRACE_GETFPREGS, self.pid().as_raw(), std::ptr::null_mut::<libc::c_void>(), fpregs.as_mut_ptr()) };
+        nix::errno::Errno::result(result)?;
+        let xmm = unsafe { fpregs.assume_init() }.xmm_space;
+        Ok(((xmm[1] as u64) << 32 | xmm[0] as u64).to_le_bytes())
+    }
+
+    /// Executes a single machine instruction. If `over_calls` is set and the instruction is a
+    /// call, runs until the callee returns.
+    pub fn step_single(&mut self, breakpoints: &mut HashMap<usize, Breakpoint>, over_calls: bool) -> Result<Status, nix::Error> {
+        let regs_before = ptrace::getregs(self.pid())?;
+        let status = self.step_instruction(breakpoints)?;
+        if let Status::Stopped(signal::Signal::SIGTRAP, rip) = status {
+            // a breakpoint right at the start of the callee still stops us
+            if over_calls && !self.check_at_breakpoint(rip, breakpoints) {
+                if let Some(return_addr) = self.call_return_addr(regs_before.rip as usize, regs_before.rsp as usize)? {
+                    return self.run_to(return_addr, regs_before.rsp as usize, breakpoints);
+                }
+            }
+        }
+        Ok(status)
+    }
+
+    /// Steps until execution reaches a different source line than the one it started on. Calls
+    /// into code without debugging info (like libc) are run through until they return, and so are
+    /// all other calls if `over_calls` is set.
+    pub fn step_line(&mut self, breakpoints: &mut HashMap<usize, Breakpoint>, debug_data: &DwarfData, over_calls: bool) -> Result<Status, nix::Error> {
+        let start_line = debug_data
+            .get_line_from_addr(self.get_rip()?)
+            .map(|line| (line.file, line.number));
+        loop {
+            let regs_before = ptrace::getregs(self.pid())?;
+            let (rip_before, sp_before) = (regs_before.rip as usize, regs_before.rsp as usize);
+            let mut status = self.step_instruction(breakpoints)?;
+            if let Status::Stopped(signal::Signal::SIGTRAP, rip) = status {
+                if let Some(return_addr) = self.call_return_addr(rip_before, sp_before)? {
+                    // a breakpoint right at the start of the callee still stops us
+                    if self.check_at_breakpoint(rip, breakpoints) {
+                        return Ok(status);
+                    }
+                    if over_calls || debug_data.get_line_from_addr(rip).is_none() {
+                        status = self.run_to(return_addr, sp_before, breakpoints)?;
+                    }
+                }
+            }
+            match status {
+                // a watchpoint went off
+                Status::Stopped(signal::Signal::SIGTRAP, _) if self.watchpoint_hit()?.is_some() => return Ok(status),
+                Status::Stopped(signal::Signal::SIGTRAP, rip) => match debug_data.get_line_from_addr(rip) {
+                    Some(line) => {
+                        if Some((line.file, line.number)) != start_line {
+                            return Ok(status);
+                        }
+                    }
+                    // outside of any code we have lines for (e.g. main returned into libc), so
+                    // there's no next line to step to
+                    None => return self.continue_exec(breakpoints),
+                },
+                status => return Ok(status),
+            }
+        }
+    }
+
     pub fn kill(&mut self) -> Result<(),std::io::Error>{
         println!("Killing running inferior (pid {})", self.pid());              
-        self.child.kill()
+        match self.child.as_mut() {
+            Some(child) => child.kill(),
+            None => signal::kill(self.pid, signal::SIGKILL).map_err(std::io::Error::from),
+        }
     }
 
     pub fn alive(&mut self) -> bool {
-        match self.child.try_wait() {
-            Ok(None) => true,
-            _ => false
+        match self.child.as_mut() {
+            Some(child) => matches!(child.try_wait(), Ok(None)),
+            None => signal::kill(self.pid, None).is_ok(),
         }
     } 
 
+    /// Prints the chain of calls that led to the current function, out to main, as found by
+    /// `frames`. Shows at most `MAX_BACKTRACE_DEPTH` frames.
     pub fn print_backtrace(&self, debug_data: &DwarfData) -> Result<(),nix::Error> {
-        // println!("Hello world!");
-        let regs = ptrace::getregs(self.pid()).unwrap();
-
-        let mut instrction_ptr: usize = regs.rip as usize;
-        let mut base_ptr:usize = regs.rbp as usize; 
-
-        while true {
-            let line  = debug_data.get_line_from_addr(instrction_ptr).unwrap();
-            let func = debug_data.get_function_from_addr(instrction_ptr).unwrap();
-            
-            println!("{} ({})",func,line);
+        let frames = self.frames(debug_data)?;
+        if frames.is_empty() {
+            let rip = ptrace::getregs(self.pid())?.rip as usize;
+            println!("<unknown> ({:#x})", rip);
+            return Ok(());
+        }
 
-            if func == "main" {
-                break;
+        for (depth, frame) in frames.iter().take(MAX_BACKTRACE_DEPTH).enumerate() {
+            // the return address can be on the line after the call
+            let rip = if depth == 0 { frame.rip } else { frame.rip - 1 };
+            let func = debug_data.get_function_from_addr(rip).unwrap_or("<unknown>".to_string());
+            match debug_data.get_line_from_addr(rip) {
+                Some(line) => println!("{} ({})", func, line),
+                None => println!("{} (<unknown>)", func),
             }
+        }
 
-            instrction_ptr =  ptrace::read(self.pid(), (base_ptr + 8) as ptrace::AddressType)? as usize; 
-            
-            base_ptr = ptrace::read(self.pid(), base_ptr as ptrace::AddressType)? as usize; 
+        if frames.len() > MAX_BACKTRACE_DEPTH {
+            println!("(more stack frames follow...)");
+        }
+        Ok(())
+    }
+
+    /// Programs debug register `slot` (DR0 to DR3) so that the inferior traps right after writing
+    /// to any of the `len` bytes at `addr`. `len` has to be 1, 2, 4 or 8, and `addr` aligned to it.
+    pub fn set_watchpoint(&self, slot: usize, addr: usize, len: usize) -> Result<(), nix::Error> {
+        let len_bits = match len {
+            1 => 0b00,
+            2 => 0b01,
+            4 => 0b11,
+            8 => 0b10,
+            _ => return Err(nix::Error::EINVAL),
         };
+        if !addr.is_multiple_of(len) {
+            return Err(nix::Error::EINVAL);
+        }
+        self.write_debug_register(slot, addr)?;
+        let mut control = self.read_debug_register(7)?;
+        // local enable bit, then the condition (01 = data writes) and length bits
+        control &= !((0b11 << (2 * slot)) | (0b1111 << (16 + 4 * slot)));
+        control |= (1 << (2 * slot)) | ((0b01 | len_bits << 2) << (16 + 4 * slot));
+        self.write_debug_register(7, control)
+    }
+
+    pub fn clear_watchpoint(&self, slot: usize) -> Result<(), nix::Error> {
+        let control = self.read_debug_register(7)? & !(0b11 << (2 * slot));
+        self.write_debug_register(7, control)
+    }
+
+    /// Returns the debug register slot of the watchpoint that made the inferior stop, if any.
+    /// It keeps being reported until `clear_watchpoint_hit` is called.
+    pub fn watchpoint_hit(&self) -> Result<Option<usize>, nix::Error> {
+        let status = self.read_debug_register(6)?;
+        Ok((0..4).find(|slot| status & (1 << slot) != 0))
+    }
+
+    pub fn clear_watchpoint_hit(&self) -> Result<(), nix::Error> {
+        self.write_debug_register(6, 0)
+    }
+
+    fn read_debug_register(&self, index: usize) -> Result<usize, nix::Error> {
+        let offset = std::mem::offset_of!(libc::user, u_debugreg) + index * size_of::<usize>();
+        Ok(ptrace::read_user(self.pid(), offset as ptrace::AddressType)? as usize)
+    }
 
+    fn write_debug_register(&self, index: usize, value: usize) -> Result<(), nix::Error> {
+        let offset = std::mem::offset_of!(libc::user, u_debugreg) + index * size_of::<usize>();
+        unsafe {
+            ptrace::write_user(self.pid(), offset as ptrace::AddressType, value as *mut std::ffi::c_void)
+        }
+    }
+
+    /// Reads `len` bytes of the inferior's memory, starting at `addr`.
+    pub fn read_memory(&self, addr: usize, len: usize) -> Result<Vec<u8>, nix::Error> {
+        // a range running off the end of the address space can't be mapped
+        let end = addr.checked_add(len).ok_or(nix::errno::Errno::EFAULT)?;
+        let mut bytes = Vec::with_capacity(len);
+        let mut word_addr = align_addr_to_word(addr);
+        while word_addr < end {
+            let word = ptrace::read(self.pid(), word_addr as ptrace::AddressType)? as u64;
+            bytes.extend_from_slice(&word.to_le_bytes());
+            word_addr = match word_addr.checked_add(size_of::<usize>()) {
+                Some(next) => next,
+                None => break,
+            };
+        }
+        let start = addr - align_addr_to_word(addr);
+        Ok(bytes[start..start + len].to_vec())
+    }
+
+    pub fn write_memory(&mut self, addr: usize, bytes: &[u8]) -> Result<(), nix::Error> {
+        for (i, byte) in bytes.iter().enumerate() {
+            self.write_byte(addr + i, *byte)?;
+        }
         Ok(())
     }
 
@@ -186,22 +595,21 @@ impl Inferior {
         Ok(orig_byte as u8)
     }
 
-   pub fn get_rip(&self) -> Option<usize> {
-        let regs = ptrace::getregs(self.pid()).unwrap();
-        Some(regs.rip as usize)
+    pub fn get_rip(&self) -> Result<usize, nix::Error> {
+        Ok(ptrace::getregs(self.pid())?.rip as usize)
     }
 
-    pub fn set_rip(&self, rip: usize) {
-        let mut regs = ptrace::getregs(self.pid()).unwrap();
+    pub fn set_rip(&self, rip: usize) -> Result<(), nix::Error> {
+        let mut regs = ptrace::getregs(self.pid())?;
         regs.rip = rip as u64;
-        ptrace::setregs(self.pid(), regs).unwrap();
-    } 
+        ptrace::setregs(self.pid(), regs)
+    }
 
     pub fn check_at_breakpoint(&self, rip: usize, breakpoints: &HashMap<usize, Breakpoint>) -> bool {
-        if let Some(breakpoint) = breakpoints.get(&rip) {
-            return true;
+        match breakpoints.get(&rip) {
+            Some(breakpoint) => breakpoint.enabled,
+            None => false,
         }
-        false
     }
 }
 
@@ -4,22 +4,62 @@ mod inferior;
 
 use crate::debugger::Debugger;
 use nix::sys::signal::{signal, SigHandler, Signal};
+use nix::unistd::Pid;
 use std::env;
 
 mod dwarf_data;
 mod gimli_wrapper;
 
+fn usage(program: &str) -> ! {
+    println!("Usage: {} [--list-size <lines>] <target program>", program);
+    println!("       {} [--list-size <lines>] --pid <pid> [<target program>]", program);
+    std::process::exit(1);
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: {} <target program>", args[0]);
-        std::process::exit(1);
+    // how many lines of source to show around where the inferior stops
+    let mut list_size = 5;
+    let mut target = None;
+    let mut pid = None;
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        if arg == "--list-size" {
+            list_size = match rest.next().and_then(|size| size.parse().ok()) {
+                Some(size) => size,
+                None => usage(&args[0]),
+            };
+        } else if arg == "--pid" {
+            pid = match rest.next().and_then(|pid| pid.parse().ok()) {
+                Some(pid) => Some(Pid::from_raw(pid)),
+                None => usage(&args[0]),
+            };
+        } else if target.is_none() {
+            target = Some(arg.clone());
+        } else {
+            usage(&args[0]);
+        }
     }
-    let target = &args[1];
+    // when attaching, the program can be found from the process itself
+    let target = match (target, pid) {
+        (Some(target), _) => target,
+        (None, Some(pid)) => match std::fs::read_link(format!("/proc/{}/exe", pid)) {
+            Ok(path) => path.to_string_lossy().into_owned(),
+            Err(e) => {
+                println!("Can't find the program of process {}: {}", pid, e);
+                std::process::exit(1);
+            }
+        },
+        (None, None) => usage(&args[0]),
+    };
 
     // Disable handling of ctrl+c in this process (so that ctrl+c only gets delivered to child
     // processes)
     unsafe { signal(Signal::SIGINT, SigHandler::SigIgn) }.expect("Error disabling SIGINT handling");
 
-    Debugger::new(target).run();
+    let mut debugger = Debugger::new(&target, list_size);
+    if let Some(pid) = pid {
+        debugger.attach(pid);
+    }
+    debugger.run();
 }
@@ -0,0 +1,89 @@
+use std::time::Duration;
+
+use time::macros::format_description;
+use time::OffsetDateTime;
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::{mpsc, oneshot};
+
+/// How often buffered access log lines are written out to the file
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+enum Message {
+    Line(String),
+    /// Write out everything buffered so far, then signal the sender
+    Flush(oneshot::Sender<()>),
+}
+
+/// Writes an access log in Common Log Format. The file is written by a background task, so
+/// recording a request never waits on disk.
+#[derive(Clone)]
+pub struct AccessLog {
+    sender: mpsc::UnboundedSender<Message>,
+}
+
+impl AccessLog {
+    /// Opens (or creates) the log file at `path` for appending, and starts the task that writes it.
+    pub async fn open(path: &str) -> Result<AccessLog, std::io::Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut writer = BufWriter::new(file);
+            let mut flush_interval = tokio::time::interval(FLUSH_INTERVAL);
+            loop {
+                let result = tokio::select! {
+                    message = receiver.recv() => match message {
+                        Some(Message::Line(line)) => writer.write_all(line.as_bytes()).await,
+                        Some(Message::Flush(done)) => {
+                            let result = writer.flush().await;
+                            let _ = done.send(());
+                            result
+                        }
+                        None => break,
+                    },
+                    _ = flush_interval.tick() => writer.flush().await,
+                };
+                if let Err(err) = result {
+                    log::error!("Failed to write access log: {}", err);
+                }
+            }
+            let _ = writer.flush().await;
+        });
+        Ok(AccessLog { sender })
+    }
+
+    /// Records that `response` was sent to `client_ip` in answer to `request`.
+    pub fn record(
+        &self,
+        client_ip: &str,
+        request: &http::Request<Vec<u8>>,
+        response: &http::Response<Vec<u8>>,
+    ) {
+        let time = OffsetDateTime::now_utc()
+            .format(format_description!(
+                "[day]/[month repr:short]/[year]:[hour]:[minute]:[second] +0000"
+            ))
+            .unwrap();
+        let line = format!(
+            "{} - - [{}] \"{}\" {} {}\n",
+            client_ip,
+            time,
+            crate::request::format_request_line(request),
+            response.status().as_u16(),
+            crate::response::serialized_len(response)
+        );
+        let _ = self.sender.send(Message::Line(line));
+    }
+
+    /// Waits until everything recorded so far has been written to the file.
+    pub async fn flush(&self) {
+        let (done, flushed) = oneshot::channel();
+        if self.sender.send(Message::Flush(done)).is_ok() {
+            let _ = flushed.await;
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+
+/// A response kept in the cache, and when it stops being fresh
+struct CachedResponse {
+    status: http::StatusCode,
+    version: http::Version,
+    headers: http::HeaderMap,
+    body: Vec<u8>,
+    stored_at: Instant,
+    expires_at: Instant,
+}
+
+/// In-memory LRU cache of upstream responses for `--cache-size`. Only 200 responses to GET and
+/// HEAD requests are stored, and only if the upstream allows it with `Cache-Control: max-age`;
+/// they are served until that age runs out.
+pub struct ResponseCache {
+    entries: Mutex<LruCache<String, CachedResponse>>,
+}
+
+/// Returns the key a request's response is cached under: its method, host and path. This should be
+/// worked out before the Host header is rewritten for the upstream.
+pub fn cache_key(request: &http::Request<Vec<u8>>) -> String {
+    let host = request
+        .headers()
+        .get("host")
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or("");
+    format!("{} {}{}", request.method(), host, request.uri())
+}
+
+/// Returns how long the response may be cached for according to its Cache-Control header, or
+/// None if it must not be cached.
+fn max_age(response: &http::Response<Vec<u8>>) -> Option<Duration> {
+    let mut max_age = None;
+    for value in response.headers().get_all("cache-control") {
+        for directive in value.to_str().ok()?.split(',') {
+            let directive = directive.trim().to_ascii_lowercase();
+            match directive.split_once('=') {
+                Some(("max-age", secs)) => {
+                    max_age = Some(Duration::from_secs(secs.trim_matches('"').parse().ok()?))
+                }
+                None if matches!(directive.as_str(), "no-store" | "no-cache" | "private") => {
+                    return None
+                }
+                _ => {}
+            }
+        }
+    }
+    max_age.filter(|age| !age.is_zero())
+}
+
+impl ResponseCache {
+    /// Creates a cache holding up to `capacity` responses.
+    pub fn new(capacity: NonZeroUsize) -> ResponseCache {
+        ResponseCache {
+            entries: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    /// Returns a copy of the response cached under `key`, if there is a fresh one. The copy has
+    /// an Age header saying how long ago it was fetched from the upstream.
+    pub fn get(&self, key: &str) -> Option<http::Response<Vec<u8>>> {
+        let mut entries = self.entries.lock().unwrap();
+        let cached = entries.get(key)?;
+        if cached.expires_at <= Instant::now() {
+            entries.pop(key);
+            return None;
+        }
+        let mut response = http::Response::new(cached.body.clone());
+        *response.status_mut() = cached.status;
+        *response.version_mut() = cached.version;
+        *response.headers_mut() = cached.headers.clone();
+        response.headers_mut().insert(
+            "age",
+            http::HeaderValue::from(cached.stored_at.elapsed().as_secs()),
+        );
+        Some(response)
+    }
+
+    /// Stores the upstream's `response` to `request` under `key`, if it is cacheable.
+    pub fn store(
+        &self,
+        key: String,
+        request: &http::Request<Vec<u8>>,
+        response: &http::Response<Vec<u8>>,
+    ) {
+        if !(request.method() == http::Method::GET || request.method() == http::Method::HEAD)
+            || response.status() != http::StatusCode::OK
+            || response.headers().contains_key("set-cookie")
+            || response.headers().contains_key("vary")
+        {
+            return;
+        }
+        let max_age = match max_age(response) {
+            Some(max_age) => max_age,
+            None => return,
+        };
+        let now = Instant::now();
+        self.entries.lock().unwrap().put(
+            key,
+            CachedResponse {
+                status: response.status(),
+                version: response.version(),
+                headers: response.headers().clone(),
+                body: response.body().clone(),
+                stored_at: now,
+                expires_at: now + max_age,
+            },
+        );
+    }
+}
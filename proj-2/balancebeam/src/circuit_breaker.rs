@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Circuit breaker state of one upstream
+#[derive(Default)]
+struct Breaker {
+    /// Requests in a row that have failed on the upstream
+    consecutive_failures: usize,
+    /// While the breaker is open, when it half-opens to let a trial request through
+    open_until: Option<Instant>,
+    /// Whether the trial request of a half-open breaker has been sent and not finished yet
+    trial_in_progress: bool,
+}
+
+/// Per-upstream circuit breakers for `--circuit-breaker-threshold`. Once `threshold` requests in a
+/// row fail on an upstream, its breaker opens and no requests are sent there for `cooldown`. After
+/// that a single trial request is let through: if it succeeds the breaker closes again, and if it
+/// fails the breaker opens for another cooldown.
+pub struct CircuitBreakers {
+    /// Consecutive failures that open a breaker (0 = breakers never open)
+    threshold: usize,
+    cooldown: Duration,
+    breakers: Mutex<HashMap<String, Breaker>>,
+}
+
+impl CircuitBreakers {
+    pub fn new(threshold: usize, cooldown: Duration) -> CircuitBreakers {
+        CircuitBreakers {
+            threshold,
+            cooldown,
+            breakers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns whether a request could be sent to `upstream` right now: its breaker is closed, or
+    /// half-open with no trial request under way.
+    pub fn allows(&self, upstream: &str) -> bool {
+        match self.breakers.lock().unwrap().get(upstream) {
+            Some(breaker) => match breaker.open_until {
+                Some(open_until) => Instant::now() >= open_until && !breaker.trial_in_progress,
+                None => true,
+            },
+            None => true,
+        }
+    }
+
+    /// Claims the right to send a request to `upstream`. This is like `allows`, except that if the
+    /// breaker is half-open, the request becomes its trial request and no others are let through
+    /// until it finishes.
+    pub fn try_acquire(&self, upstream: &str) -> bool {
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = match breakers.get_mut(upstream) {
+            Some(breaker) => breaker,
+            None => return true,
+        };
+        match breaker.open_until {
+            Some(open_until) => {
+                if Instant::now() < open_until || breaker.trial_in_progress {
+                    return false;
+                }
+                log::info!("Circuit breaker for {} half-open, sending a trial request", upstream);
+                breaker.trial_in_progress = true;
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Records that a request to `upstream` succeeded, closing its breaker.
+    pub fn record_success(&self, upstream: &str) {
+        if let Some(breaker) = self.breakers.lock().unwrap().remove(upstream) {
+            if breaker.open_until.is_some() {
+                log::info!("Circuit breaker for {} closed", upstream);
+            }
+        }
+    }
+
+    /// Records that a request to `upstream` failed, opening its breaker if that makes too many
+    /// failures in a row (or if it was the trial request of a half-open breaker).
+    pub fn record_failure(&self, upstream: &str) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.entry(upstream.to_string()).or_default();
+        breaker.consecutive_failures += 1;
+        if breaker.trial_in_progress || breaker.consecutive_failures >= self.threshold {
+            log::warn!(
+                "Circuit breaker for {} open after {} failures in a row, retrying in {:?}",
+                upstream,
+                breaker.consecutive_failures,
+                self.cooldown
+            );
+            breaker.open_until = Some(Instant::now() + self.cooldown);
+            breaker.trial_in_progress = false;
+        }
+    }
+}
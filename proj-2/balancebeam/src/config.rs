@@ -0,0 +1,209 @@
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use serde::Deserialize;
+
+use crate::{CmdOptions, ConnectionOverflow, LoadBalanceStrategy, LogFormat, RateLimitAlgo};
+
+/// Settings read from a `--config` TOML file. Every field is optional and is named like the
+/// matching command-line flag, e.g.
+///
+/// ```toml
+/// bind = ["0.0.0.0:8080", "[::]:8080"]
+/// max-requests-per-minute = 100
+///
+/// [[upstream]]
+/// address = "10.0.0.1:80"
+/// weight = 2
+/// health = "/healthz"
+///
+/// [[upstream]]
+/// address = "10.0.0.2:80"
+/// ```
+///
+/// Upstreams can also be given as strings in the same format as `--upstream`, and a single bind
+/// address as a plain string.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct FileConfig {
+    bind: Option<BindConfig>,
+    upstream: Option<Vec<UpstreamConfig>>,
+    route: Option<Vec<String>>,
+    vhost: Option<Vec<String>>,
+    reject_unknown_hosts: Option<bool>,
+    active_health_check_interval: Option<usize>,
+    active_health_check_path: Option<String>,
+    health_check_timeout: Option<usize>,
+    max_requests_per_minute: Option<usize>,
+    time_reset: Option<usize>,
+    rate_limit_algo: Option<RateLimitAlgo>,
+    rate_limit_burst: Option<usize>,
+    rate_limit_key_header: Option<String>,
+    rate_limit_exempt: Option<Vec<String>>,
+    strategy: Option<LoadBalanceStrategy>,
+    sticky_cookie: Option<String>,
+    max_idle_per_upstream: Option<usize>,
+    preserve_host: Option<bool>,
+    cache_size: Option<usize>,
+    gzip_min_size: Option<usize>,
+    error_page_dir: Option<String>,
+    max_header_bytes: Option<usize>,
+    max_request_body_bytes: Option<usize>,
+    max_retries: Option<usize>,
+    slow_start: Option<usize>,
+    max_connect_failures: Option<usize>,
+    circuit_breaker_threshold: Option<usize>,
+    circuit_breaker_cooldown: Option<usize>,
+    upstream_timeout: Option<usize>,
+    client_idle_timeout: Option<usize>,
+    max_connections: Option<usize>,
+    connection_overflow: Option<ConnectionOverflow>,
+    access_log: Option<String>,
+    log_format: Option<LogFormat>,
+    metrics_bind: Option<String>,
+    shutdown_timeout: Option<usize>,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    upstream_insecure: Option<bool>,
+}
+
+/// The addresses to bind to in the config file, either just one or a list
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum BindConfig {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// An upstream in the config file, either as an `--upstream` style string or as a table
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum UpstreamConfig {
+    Spec(String),
+    Table {
+        address: String,
+        weight: Option<u32>,
+        health: Option<String>,
+        expect: Option<u16>,
+    },
+}
+
+impl UpstreamConfig {
+    /// Converts the upstream into the `host:port[=weight][;health=/path][;expect=status]` format
+    /// taken by `--upstream`, so that it gets validated the same way.
+    fn into_spec(self) -> String {
+        match self {
+            UpstreamConfig::Spec(spec) => spec,
+            UpstreamConfig::Table {
+                address,
+                weight,
+                health,
+                expect,
+            } => {
+                let mut spec = address;
+                if let Some(weight) = weight {
+                    spec += &format!("={}", weight);
+                }
+                if let Some(health) = health {
+                    spec += &format!(";health={}", health);
+                }
+                if let Some(expect) = expect {
+                    spec += &format!(";expect={}", expect);
+                }
+                spec
+            }
+        }
+    }
+}
+
+/// Reads and parses the config file at `path`.
+pub fn load(path: &str) -> Result<FileConfig, String> {
+    let contents = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+    toml::from_str(&contents).map_err(|err| err.to_string())
+}
+
+impl FileConfig {
+    /// Copies the settings from the file into `options`, except for the ones that were given on
+    /// the command line, which take priority.
+    pub fn apply(self, options: &mut CmdOptions, matches: &ArgMatches) -> Result<(), String> {
+        let from_command_line =
+            |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        macro_rules! apply {
+            ($field:ident) => {
+                if let Some(value) = self.$field {
+                    if !from_command_line(stringify!($field)) {
+                        options.$field = value;
+                    }
+                }
+            };
+            (optional $field:ident) => {
+                if self.$field.is_some() && !from_command_line(stringify!($field)) {
+                    options.$field = self.$field;
+                }
+            };
+        }
+
+        apply!(route);
+        apply!(vhost);
+        apply!(reject_unknown_hosts);
+        apply!(active_health_check_interval);
+        apply!(active_health_check_path);
+        apply!(health_check_timeout);
+        apply!(max_requests_per_minute);
+        apply!(time_reset);
+        apply!(rate_limit_algo);
+        apply!(optional rate_limit_burst);
+        apply!(optional rate_limit_key_header);
+        apply!(strategy);
+        apply!(optional sticky_cookie);
+        apply!(max_idle_per_upstream);
+        apply!(preserve_host);
+        apply!(cache_size);
+        apply!(gzip_min_size);
+        apply!(optional error_page_dir);
+        apply!(max_header_bytes);
+        apply!(max_request_body_bytes);
+        apply!(max_retries);
+        apply!(slow_start);
+        apply!(max_connect_failures);
+        apply!(circuit_breaker_threshold);
+        apply!(circuit_breaker_cooldown);
+        apply!(upstream_timeout);
+        apply!(client_idle_timeout);
+        apply!(max_connections);
+        apply!(connection_overflow);
+        apply!(optional access_log);
+        apply!(log_format);
+        apply!(optional metrics_bind);
+        apply!(shutdown_timeout);
+        apply!(optional tls_cert);
+        apply!(optional tls_key);
+        apply!(upstream_insecure);
+
+        if let Some(bind) = self.bind {
+            if !from_command_line("bind") {
+                options.bind = match bind {
+                    BindConfig::One(address) => vec![address],
+                    BindConfig::Many(addresses) => addresses,
+                };
+            }
+        }
+        if let Some(upstreams) = self.upstream {
+            if !from_command_line("upstream") {
+                options.upstream = upstreams.into_iter().map(UpstreamConfig::into_spec).collect();
+            }
+        }
+        if let Some(ranges) = self.rate_limit_exempt {
+            if !from_command_line("rate_limit_exempt") {
+                options.rate_limit_exempt = ranges
+                    .iter()
+                    .map(|range| {
+                        range.parse().map_err(|err| {
+                            format!("invalid rate-limit-exempt range {}: {}", range, err)
+                        })
+                    })
+                    .collect::<Result<_, _>>()?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+use std::io::Write;
+
+use env_logger::filter::Filter;
+use log::kv::{Key, Value, VisitSource};
+use serde_json::{Map, Number};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// Logger for `--log-format json`. Each log line is a JSON object with the time, level, target
+/// and message of the event, plus any key-values attached to it (like `client_ip` or `status`).
+/// Which events are logged is controlled by `RUST_LOG`, the same as with the text logger.
+struct JsonLogger {
+    filter: Filter,
+}
+
+/// Collects the key-values of a log record into a JSON object
+struct JsonFields<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let value = if let Some(number) = value.to_u64() {
+            serde_json::Value::Number(number.into())
+        } else if let Some(number) = value.to_i64() {
+            serde_json::Value::Number(number.into())
+        } else if let Some(number) = value.to_f64().and_then(Number::from_f64) {
+            serde_json::Value::Number(number)
+        } else if let Some(flag) = value.to_bool() {
+            serde_json::Value::Bool(flag)
+        } else {
+            serde_json::Value::String(value.to_string())
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+impl log::Log for JsonLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        let mut fields = Map::new();
+        let time = OffsetDateTime::now_utc().format(&Rfc3339).unwrap();
+        fields.insert("time".to_string(), time.into());
+        fields.insert("level".to_string(), record.level().as_str().into());
+        fields.insert("target".to_string(), record.target().into());
+        fields.insert("message".to_string(), record.args().to_string().into());
+        let _ = record.key_values().visit(&mut JsonFields(&mut fields));
+        let line = serde_json::Value::Object(fields).to_string();
+        let _ = writeln!(std::io::stderr().lock(), "{}", line);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the JSON logger as the global logger.
+pub fn init() {
+    let filter = env_logger::filter::Builder::from_env("RUST_LOG").build();
+    log::set_max_level(filter.filter());
+    log::set_boxed_logger(Box::new(JsonLogger { filter })).expect("logger already initialized");
+}
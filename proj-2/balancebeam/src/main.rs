@@ -1,40 +1,262 @@
+mod access_log;
+mod cache;
+mod circuit_breaker;
+mod config;
+mod json_logger;
+mod metrics;
 mod request;
 mod response;
 
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::num::NonZeroUsize;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
-use clap::Parser;
+use clap::{ArgAction, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use ipnet::IpNet;
+use serde::Deserialize;
 use rand::{Rng, SeedableRng};
-use tokio::time::sleep;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, timeout};
+use tokio::net::{TcpListener, TcpStream, UnixStream};
 use tokio::sync::RwLock;
 use tokio::sync::Mutex;
+use tokio::sync::{watch, Notify, Semaphore};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
 
 /// Contains information parsed from the command-line invocation of balancebeam. The Clap macros
 /// provide a fancy way to automatically construct a command-line argument parser.
 #[derive(Parser, Debug, Clone)]
 #[command(about = "Fun with load balancing")]
 struct CmdOptions {
-    /// "IP/port to bind to"
+    /// "TOML file to read settings from; command-line options take priority over it"
+    #[arg(long)]
+    config: Option<String>,
+    /// "IP/port to bind to (can be given more than once to listen on several addresses)"
     #[arg(short, long, default_value = "0.0.0.0:1100")]
-    bind: String,
-    /// "Upstream host to forward requests to"
+    bind: Vec<String>,
+    /// "Upstream host to forward requests to, optionally with a weight and its own health check
+    /// settings ([https://]host:port[=weight][;health=/path][;expect=status]), or a Unix socket
+    /// given as unix:/path/to.sock"
     #[arg(short, long)]
     upstream: Vec<String>,
+    /// "Send requests whose path starts with a prefix to their own upstreams instead, e.g.
+    /// /api=10.0.0.1:80,10.0.0.2:80 (can be given more than once; upstreams are in the same
+    /// format as --upstream)"
+    #[arg(long)]
+    route: Vec<String>,
+    /// "Send requests for a host name to their own upstreams instead, e.g.
+    /// api.example.com=10.0.0.1:80,10.0.0.2:80 (can be given more than once; takes priority over
+    /// --route)"
+    #[arg(long)]
+    vhost: Vec<String>,
+    /// "Answer requests for hosts not given with --vhost with 404, instead of sending them to the
+    /// default upstreams"
+    #[arg(long)]
+    reject_unknown_hosts: bool,
     /// "Perform active health checks on this interval (in seconds)"
     #[arg(long, default_value = "10")]
     active_health_check_interval: usize,
     /// "Path to send request to for active health checks"
     #[arg(long, default_value = "/")]
     active_health_check_path: String,
+    /// "How long to wait for an upstream to answer an active health check (in seconds)"
+    #[arg(long, default_value = "5")]
+    health_check_timeout: usize,
     /// "Maximum number of requests to accept per IP per minute (0 = unlimited)"
     #[arg(long, default_value = "0")]
     max_requests_per_minute: usize,
     /// Fixed Window to limit rate per second
     #[arg(short, default_value = "60")]
     time_reset:usize,
+    /// "Algorithm used to enforce --max-requests-per-minute"
+    #[arg(long, value_enum, default_value = "fixed-window")]
+    rate_limit_algo: RateLimitAlgo,
+    /// "Most requests an IP can make in a burst with the token-bucket algorithm (defaults to
+    /// --max-requests-per-minute)"
+    #[arg(long)]
+    rate_limit_burst: Option<usize>,
+    /// "Rate limit by the value of this request header (e.g. X-Api-Key) instead of by client IP,
+    /// falling back to the client IP when the header is missing"
+    #[arg(long)]
+    rate_limit_key_header: Option<String>,
+    /// "Never rate limit clients in this CIDR range, e.g. 10.0.0.0/8 or fd00::/8 (can be given
+    /// more than once)"
+    #[arg(long)]
+    rate_limit_exempt: Vec<IpNet>,
+    /// "How to pick the upstream server for a new connection"
+    #[arg(long, value_enum, default_value = "random")]
+    strategy: LoadBalanceStrategy,
+    /// "Name of a cookie used to pin each client to the same upstream (disabled if not set)"
+    #[arg(long)]
+    sticky_cookie: Option<String>,
+    /// "Maximum number of idle connections to keep open to each upstream (0 = no pooling)"
+    #[arg(long, default_value = "8")]
+    max_idle_per_upstream: usize,
+    /// "Pass the client's Host header through to upstreams; if false, it is replaced with the
+    /// upstream's own host:port"
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    preserve_host: bool,
+    /// "Number of GET/HEAD responses to keep in an in-memory cache, for upstreams that allow it
+    /// with Cache-Control: max-age (0 = no caching)"
+    #[arg(long, default_value = "0")]
+    cache_size: usize,
+    /// "Gzip responses at least this many bytes long for clients that accept it"
+    #[arg(long, default_value = "1024")]
+    gzip_min_size: usize,
+    /// "Directory of custom error pages named by status code, e.g. 502.html (a built-in page is
+    /// used for statuses without one)"
+    #[arg(long)]
+    error_page_dir: Option<String>,
+    /// "Reject requests whose request line and headers take up more than this many bytes with 400
+    /// Bad Request"
+    #[arg(long, default_value = "8000")]
+    max_header_bytes: usize,
+    /// "Reject requests with a body bigger than this many bytes with 413 Payload Too Large"
+    #[arg(long, default_value = "10485760")]
+    max_request_body_bytes: usize,
+    /// "How many other upstreams to try when forwarding a GET or HEAD request fails"
+    #[arg(long, default_value = "0")]
+    max_retries: usize,
+    /// "Ramp traffic to an upstream back up over this long after health checks find it alive again
+    /// (in seconds, 0 = send it a full share straight away)"
+    #[arg(long, default_value = "0")]
+    slow_start: usize,
+    /// "Take an upstream out of rotation after this many attempts to connect to it fail in a row
+    /// (until a health check finds it alive again)"
+    #[arg(long, default_value = "3")]
+    max_connect_failures: usize,
+    /// "Stop sending requests to an upstream after this many fail in a row (0 = never)"
+    #[arg(long, default_value = "0")]
+    circuit_breaker_threshold: usize,
+    /// "How long to leave an upstream alone after its circuit breaker opens before trying it
+    /// again (in seconds)"
+    #[arg(long, default_value = "30")]
+    circuit_breaker_cooldown: usize,
+    /// "How long to wait for an upstream to answer a request before giving up on it (in seconds,
+    /// 0 = wait forever)"
+    #[arg(long, default_value = "30")]
+    upstream_timeout: usize,
+    /// "Close client connections that don't send a (complete) request within this long (in
+    /// seconds, 0 = wait forever)"
+    #[arg(long, default_value = "60")]
+    client_idle_timeout: usize,
+    /// "Maximum number of client connections to handle at once (0 = unlimited)"
+    #[arg(long, default_value = "0")]
+    max_connections: usize,
+    /// "What to do with new connections while --max-connections are already open"
+    #[arg(long, value_enum, default_value = "queue")]
+    connection_overflow: ConnectionOverflow,
+    /// "File to append an access log to, in Common Log Format (disabled if not set)"
+    #[arg(long)]
+    access_log: Option<String>,
+    /// "How to format log messages"
+    #[arg(long, value_enum, default_value = "text")]
+    log_format: LogFormat,
+    /// "IP/port to serve Prometheus metrics on at /metrics, and upstream status at /upstreams
+    /// (disabled if not set)"
+    #[arg(long)]
+    metrics_bind: Option<String>,
+    /// "How long to wait for open connections to finish when shutting down (in seconds)"
+    #[arg(long, default_value = "30")]
+    shutdown_timeout: usize,
+    /// "PEM file with the certificate chain to serve HTTPS with (requires --tls-key)"
+    #[arg(long)]
+    tls_cert: Option<String>,
+    /// "PEM file with the private key for --tls-cert"
+    #[arg(long)]
+    tls_key: Option<String>,
+    /// "Don't verify the certificates of https:// upstreams (for self-signed dev backends)"
+    #[arg(long)]
+    upstream_insecure: bool,
+}
+
+/// The ways balancebeam can enforce the per-IP rate limit
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum RateLimitAlgo {
+    /// Count requests per IP, and reset all the counts every `time_reset` seconds
+    FixedWindow,
+    /// Give each IP a bucket of tokens that refills steadily; each request takes one token
+    TokenBucket,
+    /// Keep the times of each IP's requests over the last minute, and count those
+    SlidingWindow,
+}
+
+/// Length of the window that the sliding-window rate limiter counts requests over
+const SLIDING_WINDOW: Duration = Duration::from_secs(60);
+
+/// How often rate limiting state for keys that haven't been seen lately is thrown away
+const RATE_LIMIT_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Per-IP state for the token-bucket rate limiter
+struct TokenBucket {
+    /// Requests the IP can still make right now
+    tokens: f64,
+    /// When tokens were last added to the bucket
+    last_refill: Instant,
+}
+
+/// The ways balancebeam can choose which upstream a new client connection is forwarded to
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum LoadBalanceStrategy {
+    /// Pick a random upstream, honoring upstream weights
+    Random,
+    /// Cycle through the upstreams in order
+    RoundRobin,
+    /// Pick the upstream with the fewest requests in flight
+    LeastConnections,
+    /// Always send a given client IP to the same upstream
+    IpHash,
+    /// Pick a random upstream, favoring the ones that have been answering fastest
+    LatencyWeighted,
+}
+
+/// What balancebeam does with new client connections once --max-connections is reached
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum ConnectionOverflow {
+    /// Stop accepting connections until one of the open ones closes
+    Queue,
+    /// Accept the connection, answer with a 503, and close it
+    Reject,
+}
+
+/// The ways balancebeam can write its log messages
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum LogFormat {
+    /// Human-readable lines
+    Text,
+    /// One JSON object per line, with request details as separate fields
+    Json,
+}
+
+/// Anything a client can be connected to us over: a plain TcpStream, or a TLS stream wrapping one
+trait ClientStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ClientStream for T {}
+
+/// A connection from a client
+struct ClientConnection {
+    stream: Box<dyn ClientStream>,
+    /// IP address the client is connecting from
+    ip: String,
+    /// Whether the client connected over TLS
+    tls: bool,
+    /// When the request currently being handled was received
+    request_started: Instant,
+    /// How long the upstream took to answer the current request, once it has
+    upstream_latency: Option<Duration>,
+    /// X-Request-ID of the current request, once it has been read
+    request_id: Option<String>,
+    /// If the current request accepts gzipped responses, the smallest body worth gzipping
+    gzip_min_size: Option<usize>,
 }
 
 /// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
@@ -49,167 +271,1723 @@ struct ProxyState {
     /// Where we should send requests when doing active health checks (Milestone 4)
     #[allow(dead_code)]
     active_health_check_path: String,
+    /// How long an upstream has to answer an active health check before it is considered down
+    health_check_timeout: Duration,
     /// Maximum number of requests an individual IP can make in a minute (Milestone 5)
     #[allow(dead_code)]
     max_requests_per_minute: usize,
     /// Addresses of servers that we are proxying to
     upstream_addresses: Vec<String>,
+    /// Upstreams that requests matching no route are sent to
+    default_upstreams: Vec<String>,
+    /// Path prefixes that are sent to their own upstreams, longest prefix first
+    routes: Vec<Route>,
+    /// Upstreams for each host name (in lowercase) that has its own
+    vhosts: HashMap<String, Vec<String>>,
+    /// Whether requests for hosts without their own upstreams are refused
+    reject_unknown_hosts: bool,
+    /// Relative weight of each upstream (same order as upstream_addresses)
+    weights: Vec<u32>,
+    /// Path that active health checks are sent to for each upstream
+    health_paths: Vec<String>,
+    /// Status each upstream is expected to answer active health checks with
+    expected_statuses: Vec<u16>,
     /// Active upstream that can be connected
     active_upstream:Arc<RwLock<Vec<String>>>,
+    /// Consistent hash ring over active_upstream for the IpHash strategy, rebuilt whenever that
+    /// changes
+    hash_ring: Arc<RwLock<Vec<(u64, String)>>>,
     /// Count the IP send times for Rate limiting
     ip_count:Arc<Mutex<HashMap<String,usize>>>,
     /// time to reset ip count,
     time_reset:usize,
+    /// Which algorithm enforces max_requests_per_minute
+    rate_limit_algo: RateLimitAlgo,
+    /// Capacity of each IP's token bucket
+    rate_limit_burst: usize,
+    /// Request header whose value requests are rate limited by, instead of the client IP
+    rate_limit_key_header: Option<String>,
+    /// Client IP ranges that are never rate limited
+    rate_limit_exempt: Vec<IpNet>,
+    /// When count_reset will next clear ip_count
+    window_reset_at: Arc<Mutex<Instant>>,
+    /// Token buckets for rate limiting, by IP
+    token_buckets: Arc<Mutex<HashMap<String, TokenBucket>>>,
+    /// Times of each IP's requests within the last SLIDING_WINDOW, oldest first
+    request_log: Arc<Mutex<HashMap<String, VecDeque<Instant>>>>,
+    /// How upstreams are selected for new connections
+    strategy: LoadBalanceStrategy,
+    /// Number of requests currently being handled by each upstream
+    in_flight: Arc<Mutex<HashMap<String, usize>>>,
+    /// Position of the round-robin strategy in the upstream list
+    round_robin_counter: Arc<AtomicUsize>,
+    /// Name of the cookie recording which upstream (by index into upstream_addresses) a client is
+    /// pinned to, if sticky sessions are enabled
+    sticky_cookie: Option<String>,
+    /// Idle connections to each upstream, kept open so later requests can reuse them
+    idle_connections: Arc<Mutex<HashMap<String, Vec<UpstreamStream>>>>,
+    /// Maximum number of idle connections kept per upstream
+    max_idle_per_upstream: usize,
+    /// Whether the client's Host header is passed through, rather than rewritten to the upstream
+    preserve_host: bool,
+    /// Recent upstream responses that can be served again without asking the upstream, if
+    /// caching is enabled
+    response_cache: Option<Arc<cache::ResponseCache>>,
+    /// Smallest response body that is gzipped for clients that accept it
+    gzip_min_size: usize,
+    /// Largest request line and headers accepted from clients
+    max_header_bytes: usize,
+    /// Largest request body accepted from clients
+    max_request_body_bytes: usize,
+    /// How many other upstreams an idempotent request is retried on after a failure
+    max_retries: usize,
+    /// How long an upstream has to answer a forwarded request, if there is a limit
+    upstream_timeout: Option<Duration>,
+    /// How long a client connection may sit without sending a request, if there is a limit
+    client_idle_timeout: Option<Duration>,
+    /// Attempts to connect to each upstream that have failed in a row
+    connect_failures: Arc<Mutex<HashMap<String, usize>>>,
+    /// How many connection failures in a row take an upstream out of rotation
+    max_connect_failures: usize,
+    /// Keep requests away from upstreams that keep failing
+    circuit_breakers: Arc<circuit_breaker::CircuitBreakers>,
+    /// How long upstreams that come back take to ramp up to a full share of requests, if they
+    /// ramp up at all
+    slow_start: Option<Duration>,
+    /// When each upstream still ramping up was brought back by the active health checks
+    readmitted_at: Arc<Mutex<HashMap<String, Instant>>>,
+    /// Used to open connections to https:// upstreams
+    tls_connector: TlsConnector,
+    /// Permits for the client connections we may handle at once, if there is a limit
+    connection_limit: Option<Arc<Semaphore>>,
+    /// What to do with connections beyond the limit
+    connection_overflow: ConnectionOverflow,
+    /// Moving average of how long each upstream takes to answer a request, in milliseconds
+    upstream_latency_ms: Arc<Mutex<HashMap<String, f64>>>,
+    /// Result of the most recent active health check of each upstream, and when it finished
+    last_health_checks: Arc<Mutex<HashMap<String, (bool, Instant)>>>,
+    /// Counters exported on the metrics listener
+    metrics: Arc<metrics::Metrics>,
+    /// Where completed requests are logged, if anywhere
+    access_log: Option<access_log::AccessLog>,
+    /// Number of client connections currently being handled
+    open_connections: Arc<AtomicUsize>,
+    /// Notified when the last open connection finishes
+    connections_drained: Arc<Notify>,
+    /// Becomes true once balancebeam starts shutting down
+    shutting_down: watch::Receiver<bool>,
 }
 
 #[tokio::main]
 async fn main() {
+    // Parse the command line arguments passed to this program, filling in anything that wasn't
+    // given from the config file (if there is one)
+    let matches = CmdOptions::command().get_matches();
+    let mut options = CmdOptions::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    let config_result = match options.config.clone() {
+        Some(path) => config::load(&path)
+            .and_then(|file| file.apply(&mut options, &matches))
+            .map_err(|err| format!("Could not load config file {}: {}", path, err)),
+        None => Ok(()),
+    };
+
     // Initialize the logging library. You can print log messages using the `log` macros:
     // https://docs.rs/log/0.4.8/log/ You are welcome to continue using print! statements; this
     // just looks a little prettier.
     if let Err(_) = std::env::var("RUST_LOG") {
         std::env::set_var("RUST_LOG", "debug");
     }
-    pretty_env_logger::init();
-
-    // Parse the command line arguments passed to this program
-    let options = CmdOptions::parse();
+    match options.log_format {
+        LogFormat::Text => pretty_env_logger::init(),
+        LogFormat::Json => json_logger::init(),
+    }
+    if let Err(err) = config_result {
+        log::error!("{}", err);
+        std::process::exit(1);
+    }
     if options.upstream.len() < 1 {
         log::error!("At least one upstream server must be specified using the --upstream option.");
         std::process::exit(1);
     }
 
-    // Start listening for connections
-    let listener = match TcpListener::bind(&options.bind).await {
-        Ok(listener) => listener,
-        Err(err) => {
-            log::error!("Could not bind to {}: {}", options.bind, err);
+    let mut default_specs = Vec::new();
+    for upstream in &options.upstream {
+        match parse_upstream(upstream) {
+            Ok(spec) => default_specs.push(spec),
+            Err(err) => {
+                log::error!("Invalid upstream {}: {}", upstream, err);
+                std::process::exit(1);
+            }
+        }
+    }
+    let mut route_specs = Vec::new();
+    for route in &options.route {
+        match parse_route(route) {
+            Ok(route_spec) => route_specs.push(route_spec),
+            Err(err) => {
+                log::error!("Invalid route {}: {}", route, err);
+                std::process::exit(1);
+            }
+        }
+    }
+    let mut vhost_specs = Vec::new();
+    for vhost in &options.vhost {
+        match parse_vhost(vhost) {
+            Ok(vhost_spec) => vhost_specs.push(vhost_spec),
+            Err(err) => {
+                log::error!("Invalid vhost {}: {}", vhost, err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Every upstream, whether it is a default one or only used by routes, is health checked and
+    // tracked the same way. An upstream listed more than once keeps the settings it was first
+    // given with.
+    let mut upstream_addresses = Vec::new();
+    let mut weights = Vec::new();
+    let mut health_paths = Vec::new();
+    let mut expected_statuses = Vec::new();
+    let mut add_upstream = |spec: UpstreamSpec| {
+        if !upstream_addresses.contains(&spec.address) {
+            upstream_addresses.push(spec.address.clone());
+            weights.push(spec.weight);
+            health_paths.push(
+                spec.health_path
+                    .unwrap_or_else(|| options.active_health_check_path.clone()),
+            );
+            expected_statuses.push(spec.expected_status.unwrap_or(200));
+        }
+        spec.address
+    };
+    let default_upstreams: Vec<String> = default_specs.into_iter().map(&mut add_upstream).collect();
+    let mut routes: Vec<Route> = route_specs
+        .into_iter()
+        .map(|(prefix, specs)| Route {
+            prefix,
+            upstreams: specs.into_iter().map(&mut add_upstream).collect(),
+        })
+        .collect();
+    routes.sort_by_key(|route| Reverse(route.prefix.len()));
+    let vhosts: HashMap<String, Vec<String>> = vhost_specs
+        .into_iter()
+        .map(|(host, specs)| (host, specs.into_iter().map(&mut add_upstream).collect()))
+        .collect();
+
+    let tls_acceptor = match (&options.tls_cert, &options.tls_key) {
+        (Some(cert_path), Some(key_path)) => match load_tls_acceptor(cert_path, key_path) {
+            Ok(acceptor) => Some(acceptor),
+            Err(err) => {
+                log::error!("Could not set up TLS: {}", err);
+                std::process::exit(1);
+            }
+        },
+        (None, None) => None,
+        _ => {
+            log::error!("--tls-cert and --tls-key must be given together.");
             std::process::exit(1);
         }
     };
-    log::info!("Listening for requests on {}", options.bind);
+
+    if let Some(dir) = &options.error_page_dir {
+        match response::load_error_pages(dir) {
+            Ok(count) => log::info!("Loaded {} error pages from {}", count, dir),
+            Err(err) => {
+                log::error!("Could not load error pages from {}: {}", dir, err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let access_log = match &options.access_log {
+        Some(path) => match access_log::AccessLog::open(path).await {
+            Ok(access_log) => Some(access_log),
+            Err(err) => {
+                log::error!("Could not open access log {}: {}", path, err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    // Start listening for connections. An address that can't be bound is skipped, as long as
+    // there is at least one that can
+    let mut listeners = Vec::new();
+    for bind in &options.bind {
+        match TcpListener::bind(bind).await {
+            Ok(listener) => {
+                log::info!("Listening for requests on {}", bind);
+                listeners.push(listener);
+            }
+            Err(err) => log::error!("Could not bind to {}: {}", bind, err),
+        }
+    }
+    if listeners.is_empty() {
+        log::error!("Could not bind to any address, exiting");
+        std::process::exit(1);
+    }
 
     // Handle incoming connections
+    let (shutting_down_tx, shutting_down_rx) = watch::channel(false);
     let state = ProxyState {
-        upstream_addresses: options.upstream.clone(),
+        upstream_addresses: upstream_addresses.clone(),
+        default_upstreams,
+        routes,
+        vhosts,
+        reject_unknown_hosts: options.reject_unknown_hosts,
+        weights,
+        health_paths,
+        expected_statuses,
         active_health_check_interval: options.active_health_check_interval,
         active_health_check_path: options.active_health_check_path,
+        health_check_timeout: Duration::from_secs(options.health_check_timeout as u64),
         max_requests_per_minute: options.max_requests_per_minute,
-        active_upstream: Arc::new(RwLock::new(options.upstream.clone())),
+        active_upstream: Arc::new(RwLock::new(upstream_addresses.clone())),
+        hash_ring: Arc::new(RwLock::new(build_hash_ring(&upstream_addresses))),
         ip_count: Arc::new(Mutex::new(HashMap::new())),
         time_reset: options.time_reset,
+        rate_limit_algo: options.rate_limit_algo,
+        rate_limit_burst: options
+            .rate_limit_burst
+            .unwrap_or(options.max_requests_per_minute),
+        rate_limit_key_header: options.rate_limit_key_header,
+        rate_limit_exempt: options.rate_limit_exempt,
+        window_reset_at: Arc::new(Mutex::new(
+            Instant::now() + Duration::from_secs(options.time_reset as u64),
+        )),
+        token_buckets: Arc::new(Mutex::new(HashMap::new())),
+        request_log: Arc::new(Mutex::new(HashMap::new())),
+        strategy: options.strategy,
+        in_flight: Arc::new(Mutex::new(HashMap::new())),
+        round_robin_counter: Arc::new(AtomicUsize::new(0)),
+        sticky_cookie: options.sticky_cookie,
+        idle_connections: Arc::new(Mutex::new(HashMap::new())),
+        max_idle_per_upstream: options.max_idle_per_upstream,
+        preserve_host: options.preserve_host,
+        response_cache: NonZeroUsize::new(options.cache_size)
+            .map(|capacity| Arc::new(cache::ResponseCache::new(capacity))),
+        gzip_min_size: options.gzip_min_size,
+        max_header_bytes: options.max_header_bytes,
+        max_request_body_bytes: options.max_request_body_bytes,
+        max_retries: options.max_retries,
+        upstream_timeout: match options.upstream_timeout {
+            0 => None,
+            secs => Some(Duration::from_secs(secs as u64)),
+        },
+        client_idle_timeout: match options.client_idle_timeout {
+            0 => None,
+            secs => Some(Duration::from_secs(secs as u64)),
+        },
+        connect_failures: Arc::new(Mutex::new(HashMap::new())),
+        max_connect_failures: options.max_connect_failures,
+        circuit_breakers: Arc::new(circuit_breaker::CircuitBreakers::new(
+            options.circuit_breaker_threshold,
+            Duration::from_secs(options.circuit_breaker_cooldown as u64),
+        )),
+        slow_start: match options.slow_start {
+            0 => None,
+            secs => Some(Duration::from_secs(secs as u64)),
+        },
+        readmitted_at: Arc::new(Mutex::new(HashMap::new())),
+        tls_connector: build_tls_connector(options.upstream_insecure),
+        connection_limit: match options.max_connections {
+            0 => None,
+            max => Some(Arc::new(Semaphore::new(max))),
+        },
+        connection_overflow: options.connection_overflow,
+        upstream_latency_ms: Arc::new(Mutex::new(HashMap::new())),
+        last_health_checks: Arc::new(Mutex::new(HashMap::new())),
+        metrics: Arc::new(metrics::Metrics::new(upstream_addresses.len())),
+        access_log,
+        open_connections: Arc::new(AtomicUsize::new(0)),
+        connections_drained: Arc::new(Notify::new()),
+        shutting_down: shutting_down_rx,
     };
 
+    if let Some(metrics_bind) = &options.metrics_bind {
+        let metrics_listener = match TcpListener::bind(metrics_bind).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                log::error!("Could not bind metrics listener to {}: {}", metrics_bind, err);
+                std::process::exit(1);
+            }
+        };
+        log::info!("Serving metrics on {}", metrics_bind);
+        let metrics_state = state.clone();
+        tokio::spawn(async move {
+            serve_metrics(metrics_listener, &metrics_state).await;
+        });
+    }
+
     let state_healthcheck = state.clone();
     tokio::spawn(async move {
         active_health_check(&state_healthcheck).await;
     });
 
-    let ip_count = state.clone();
-    tokio::spawn(async move {
-        count_reset(&ip_count).await;
-    });
+    if state.rate_limit_algo == RateLimitAlgo::FixedWindow {
+        let ip_count = state.clone();
+        tokio::spawn(async move {
+            count_reset(&ip_count).await;
+        });
+    }
+    if state.rate_limit_algo == RateLimitAlgo::TokenBucket {
+        let token_buckets = state.clone();
+        tokio::spawn(async move {
+            sweep_token_buckets(&token_buckets).await;
+        });
+    }
+    if state.rate_limit_algo == RateLimitAlgo::SlidingWindow {
+        let request_log = state.clone();
+        tokio::spawn(async move {
+            sweep_request_log(&request_log).await;
+        });
+    }
 
-    while let Ok((stream,_)) = listener.accept().await {
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        // When queueing connections beyond --max-connections, wait for a free slot before
+        // accepting, so that waiting clients stay in the listen backlog
+        let mut permit = None;
+        if let (Some(limit), ConnectionOverflow::Queue) =
+            (&state.connection_limit, state.connection_overflow)
+        {
+            permit = tokio::select! {
+                acquired = limit.clone().acquire_owned() => Some(acquired.unwrap()),
+                _ = &mut shutdown => break,
+            };
+        }
+        let (stream, peer) = tokio::select! {
+            accepted = accept_any(&listeners) => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    // Errors like running out of file descriptors or a client aborting the
+                    // connection early are transient, so log, back off briefly and keep accepting
+                    log::error!("Failed to accept a connection: {}", err);
+                    sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+        let mut rejected = false;
+        if let (Some(limit), ConnectionOverflow::Reject) =
+            (&state.connection_limit, state.connection_overflow)
+        {
+            permit = limit.clone().try_acquire_owned().ok();
+            rejected = permit.is_none();
+        }
         // Handle the connection!
         let spawn_state = state.clone();
+        let tls_acceptor = tls_acceptor.clone();
+        spawn_state.open_connections.fetch_add(1, Ordering::SeqCst);
         tokio::spawn(async move {
-            handle_connection(stream, &spawn_state).await;
+            // Hold the permit (if any) until the connection is done with
+            let _permit = permit;
+            let ip = client_address(&peer);
+            // Do the TLS handshake (if there is one) here rather than in the accept loop, so a slow
+            // client can't hold up everyone else
+            let tls = tls_acceptor.is_some();
+            let stream: Option<Box<dyn ClientStream>> = match tls_acceptor {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(tls_stream) => Some(Box::new(tls_stream)),
+                    Err(err) => {
+                        log::info!("TLS handshake with {} failed: {}", ip, err);
+                        None
+                    }
+                },
+                None => Some(Box::new(stream)),
+            };
+            if let Some(stream) = stream {
+                let mut client_conn = ClientConnection {
+                    stream,
+                    ip,
+                    tls,
+                    request_started: Instant::now(),
+                    upstream_latency: None,
+                    request_id: None,
+                    gzip_min_size: None,
+                };
+                if rejected {
+                    log::warn!("Too many open connections, rejecting {}", client_conn.ip);
+                    // Wait (briefly) for the request, so the client gets the 503 as the answer to
+                    // it rather than having the connection closed under it mid-send
+                    let _ = timeout(
+                        Duration::from_secs(5),
+                        request::read_from_stream(
+                            &mut client_conn.stream,
+                            spawn_state.max_header_bytes,
+                            spawn_state.max_request_body_bytes,
+                        ),
+                    )
+                    .await;
+                    let mut response =
+                        response::make_http_error(http::StatusCode::SERVICE_UNAVAILABLE);
+                    send_response(&mut client_conn, &mut response).await;
+                } else {
+                    handle_connection(client_conn, &spawn_state).await;
+                }
+            }
+            if spawn_state.open_connections.fetch_sub(1, Ordering::SeqCst) == 1 {
+                spawn_state.connections_drained.notify_waiters();
+            }
         });
     }
+
+    // Stop accepting new connections, tell idle ones to close, and give the rest a chance to finish
+    drop(listeners);
+    log::info!(
+        "Shutting down, waiting for {} open connection(s) to finish",
+        state.open_connections.load(Ordering::SeqCst)
+    );
+    let _ = shutting_down_tx.send(true);
+    let shutdown_timeout = Duration::from_secs(options.shutdown_timeout as u64);
+    if timeout(shutdown_timeout, wait_for_connections_to_drain(&state)).await.is_err() {
+        log::warn!("Connections still open after {:?}, exiting anyway", shutdown_timeout);
+    }
+    if let Some(access_log) = &state.access_log {
+        access_log.flush().await;
+    }
+}
+
+/// Returns the client's IP address the way it is written in logs and X-Forwarded-For. A client
+/// reaching a dual-stack listener over IPv4 shows up as an IPv4-mapped IPv6 address
+/// (::ffff:127.0.0.1), which is turned back into plain IPv4, so each client has one spelling no
+/// matter which listener it came in on. IPv6 addresses are written without brackets, since
+/// X-Forwarded-For doesn't carry ports.
+fn client_address(peer: &std::net::SocketAddr) -> String {
+    peer.ip().to_canonical().to_string()
+}
+
+/// How long the accept loop waits after failing to accept a connection before trying again
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Accepts the next connection to arrive on any of the listeners.
+async fn accept_any(
+    listeners: &[TcpListener],
+) -> std::io::Result<(TcpStream, std::net::SocketAddr)> {
+    std::future::poll_fn(|cx| {
+        for listener in listeners {
+            if let Poll::Ready(accepted) = listener.poll_accept(cx) {
+                return Poll::Ready(accepted);
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// Builds a TLS acceptor that serves the certificate chain and private key in the given PEM files.
+fn load_tls_acceptor(cert_path: &str, key_path: &str) -> Result<TlsAcceptor, String> {
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| format!("could not read certificates from {}: {}", cert_path, err))?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|err| format!("could not read private key from {}: {}", key_path, err))?;
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .map_err(|err| err.to_string())?
+    .with_no_client_auth()
+    .with_single_cert(certs, key)
+    .map_err(|err| err.to_string())?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Builds the TLS connector used for https:// upstreams. Normally upstream certificates are checked
+/// against the Mozilla root certificates; with `insecure`, any certificate is accepted.
+fn build_tls_connector(insecure: bool) -> TlsConnector {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .expect("ring supports the default protocol versions");
+    let config = if insecure {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoCertificateVerification(provider)))
+            .with_no_client_auth()
+    } else {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        builder.with_root_certificates(roots).with_no_client_auth()
+    };
+    TlsConnector::from(Arc::new(config))
+}
+
+/// Certificate verifier for --upstream-insecure. Handshake signatures are still checked, but the
+/// certificate itself is accepted whoever it belongs to and whoever signed it.
+#[derive(Debug)]
+struct NoCertificateVerification(Arc<rustls::crypto::CryptoProvider>);
+
+impl rustls::client::danger::ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::pki_types::CertificateDer<'_>,
+        _intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        _server_name: &rustls::pki_types::ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::danger::ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Answers requests to the metrics listener: Prometheus scrapes of /metrics, and /upstreams for a
+/// JSON summary of upstream health. Every connection gets a single response and is closed.
+async fn serve_metrics(listener: TcpListener, state: &ProxyState) {
+    while let Ok((mut stream, _)) = listener.accept().await {
+        let state = state.clone();
+        tokio::spawn(async move {
+            let request = match request::read_from_stream(
+                &mut stream,
+                state.max_header_bytes,
+                state.max_request_body_bytes,
+            )
+            .await
+            {
+                Ok(request) => request,
+                Err(_) => return,
+            };
+            let (content_type, body) = match request.uri().path() {
+                "/metrics" => ("text/plain; version=0.0.4", state.metrics.render()),
+                "/upstreams" => ("application/json", upstream_status(&state).await.to_string()),
+                _ => ("", String::new()),
+            };
+            let response = if body.is_empty() {
+                response::make_http_error(http::StatusCode::NOT_FOUND)
+            } else {
+                let body = body.into_bytes();
+                http::Response::builder()
+                    .status(http::StatusCode::OK)
+                    .header("Content-Type", content_type)
+                    .header("Content-Length", body.len().to_string())
+                    .header("Connection", "close")
+                    .version(http::Version::HTTP_11)
+                    .body(body)
+                    .unwrap()
+            };
+            if let Err(error) = response::write_to_stream(&response, &mut stream).await {
+                log::debug!("Failed to send metrics: {}", error);
+            }
+        });
+    }
+}
+
+/// Describes every configured upstream: whether it is in rotation, how many requests it is
+/// handling, and how its last active health check went.
+async fn upstream_status(state: &ProxyState) -> serde_json::Value {
+    let active = state.active_upstream.read().await.clone();
+    let in_flight = state.in_flight.lock().await.clone();
+    let last_health_checks = state.last_health_checks.lock().await.clone();
+    let upstreams: Vec<serde_json::Value> = state
+        .upstream_addresses
+        .iter()
+        .map(|address| {
+            let last_check = last_health_checks.get(address).map(|(healthy, at)| {
+                serde_json::json!({
+                    "healthy": healthy,
+                    "seconds_ago": at.elapsed().as_secs(),
+                })
+            });
+            serde_json::json!({
+                "address": address,
+                "active": active.contains(address),
+                "in_flight": in_flight.get(address).copied().unwrap_or(0),
+                "last_check": last_check,
+            })
+        })
+        .collect();
+    serde_json::json!({ "upstreams": upstreams })
+}
+
+/// Resolves when balancebeam is asked to stop, by Ctrl-C or SIGTERM.
+async fn shutdown_signal() {
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("Could not install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = sigterm.recv() => {}
+    }
+}
+
+/// Waits until every client connection has been handled.
+async fn wait_for_connections_to_drain(state: &ProxyState) {
+    loop {
+        // Register for the notification before checking the count, so a connection finishing in
+        // between can't be missed
+        let drained = state.connections_drained.notified();
+        if state.open_connections.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        drained.await;
+    }
+}
+
+/// An upstream as given on the command line
+struct UpstreamSpec {
+    address: String,
+    weight: u32,
+    /// Path to send active health checks to, if it differs from the global one
+    health_path: Option<String>,
+    /// Status that a healthy upstream answers health checks with, if not 200
+    expected_status: Option<u16>,
+}
+
+/// Parses an upstream given on the command line. Apart from the address, everything is optional:
+/// `[https://]host:port[=weight][;health=/path][;expect=status]`. The weight defaults to 1.
+fn parse_upstream(spec: &str) -> Result<UpstreamSpec, String> {
+    let mut parts = spec.split(';');
+    let address_and_weight = parts.next().unwrap();
+    let (address, weight) = match address_and_weight.split_once('=') {
+        Some((address, weight)) => {
+            let weight = weight
+                .parse::<u32>()
+                .map_err(|_| format!("weight \"{}\" is not a positive integer", weight))?;
+            if weight == 0 {
+                return Err("weight must be at least 1".to_string());
+            }
+            (address.to_string(), weight)
+        }
+        None => (address_and_weight.to_string(), 1),
+    };
+    if unix_socket_path(&address) == Some("") {
+        return Err("missing the path of the Unix socket".to_string());
+    }
+    let mut upstream = UpstreamSpec {
+        address,
+        weight,
+        health_path: None,
+        expected_status: None,
+    };
+    for option in parts {
+        match option.split_once('=') {
+            Some(("health", path)) if path.starts_with('/') => {
+                upstream.health_path = Some(path.to_string());
+            }
+            Some(("expect", status)) => {
+                let status = http::StatusCode::from_bytes(status.as_bytes())
+                    .map_err(|_| format!("\"{}\" is not a valid HTTP status", status))?;
+                upstream.expected_status = Some(status.as_u16());
+            }
+            _ => return Err(format!("unrecognized option \"{}\"", option)),
+        }
+    }
+    Ok(upstream)
+}
+
+/// Requests whose path starts with `prefix` are sent to `upstreams` rather than the default ones
+#[derive(Clone)]
+struct Route {
+    prefix: String,
+    upstreams: Vec<String>,
+}
+
+/// Parses a comma-separated list of upstreams, each in the format parse_upstream takes.
+fn parse_upstream_list(upstreams: &str) -> Result<Vec<UpstreamSpec>, String> {
+    upstreams
+        .split(',')
+        .map(|upstream| {
+            parse_upstream(upstream)
+                .map_err(|err| format!("invalid upstream {}: {}", upstream, err))
+        })
+        .collect()
+}
+
+/// Parses a route given on the command line: `/prefix=upstream[,upstream...]`.
+fn parse_route(route: &str) -> Result<(String, Vec<UpstreamSpec>), String> {
+    let (prefix, upstreams) = route
+        .split_once('=')
+        .ok_or_else(|| "expected /prefix=upstream[,upstream...]".to_string())?;
+    if !prefix.starts_with('/') {
+        return Err(format!("prefix \"{}\" must start with /", prefix));
+    }
+    Ok((prefix.to_string(), parse_upstream_list(upstreams)?))
+}
+
+/// Parses a virtual host given on the command line: `hostname=upstream[,upstream...]`. The host
+/// name is returned in lowercase.
+fn parse_vhost(vhost: &str) -> Result<(String, Vec<UpstreamSpec>), String> {
+    let (host, upstreams) = vhost
+        .split_once('=')
+        .ok_or_else(|| "expected hostname=upstream[,upstream...]".to_string())?;
+    if host.is_empty() {
+        return Err("host name is empty".to_string());
+    }
+    Ok((host.to_ascii_lowercase(), parse_upstream_list(upstreams)?))
+}
+
+/// Returns the host name in a Host header value, without the port (if any) and in lowercase.
+fn host_name(host: &str) -> String {
+    let name = match host.rsplit_once(':') {
+        // A colon inside brackets is part of an IPv6 address, not a port separator
+        Some((name, port)) if !port.contains(']') => name,
+        _ => host,
+    };
+    name.to_ascii_lowercase()
+}
+
+/// Returns whether `path` falls under the route `prefix`. The prefix has to end at a segment
+/// boundary, so /api matches /api and /api/users but not /apis.
+fn route_matches(prefix: &str, path: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
 }
 
 async fn count_reset(state: &ProxyState) {
+    let window = Duration::from_secs(state.time_reset.try_into().unwrap());
     loop {
-        sleep(Duration::from_secs(state.time_reset.try_into().unwrap())).await;
+        sleep(window).await;
         let mut ip_count = state.ip_count.lock().await;
         ip_count.clear();
+        *state.window_reset_at.lock().await = Instant::now() + window;
+    }
+}
+
+impl ProxyState {
+    /// Returns the upstreams that `request` may be sent to: those of its host, if that was given
+    /// with --vhost, else those of the longest route matching its path, else the default ones.
+    /// Returns None if the request is for an unknown host and those are being rejected.
+    fn upstream_pool(&self, request: &http::Request<Vec<u8>>) -> Option<&[String]> {
+        if !self.vhosts.is_empty() {
+            let host = request
+                .headers()
+                .get("host")
+                .and_then(|host| host.to_str().ok())
+                .map(host_name);
+            if let Some(upstreams) = host.and_then(|host| self.vhosts.get(&host)) {
+                return Some(upstreams);
+            }
+            if self.reject_unknown_hosts {
+                return None;
+            }
+        }
+        let path = request.uri().path();
+        Some(
+            self.routes
+                .iter()
+                .find(|route| route_matches(&route.prefix, path))
+                .map_or(&self.default_upstreams, |route| &route.upstreams),
+        )
     }
+
+    /// Chooses which of the `active` upstreams a new connection from `client_ip` should be
+    /// forwarded to, according to the configured strategy. Returns an index into `active`, or None
+    /// if there are no active upstreams.
+    async fn select_upstream(&self, active: &[String], client_ip: &str) -> Option<usize> {
+        if active.is_empty() {
+            return None;
+        }
+        // Upstreams that are ramping back up are only in the running some of the time
+        let warm = self.slow_start_candidates(active).await;
+        let candidates: Vec<String> = warm.iter().map(|&idx| active[idx].clone()).collect();
+        let idx = match self.strategy {
+            LoadBalanceStrategy::Random => self.weighted_index(&candidates),
+            LoadBalanceStrategy::RoundRobin => self.round_robin_index(&candidates),
+            LoadBalanceStrategy::LeastConnections => {
+                self.least_connections_index(&candidates).await
+            }
+            LoadBalanceStrategy::IpHash => {
+                ip_hash_index(&self.hash_ring.read().await, &candidates, client_ip)
+            }
+            LoadBalanceStrategy::LatencyWeighted => self.latency_weighted_index(&candidates).await,
+        };
+        Some(warm[idx])
+    }
+
+    /// Returns the indices into `active` of the upstreams to choose between for one request.
+    /// Upstreams in their slow start window are each left out at random, with a chance of being
+    /// kept that grows linearly from SLOW_START_INITIAL_SHARE to 1 over the window. If that
+    /// leaves nothing, every upstream is kept.
+    async fn slow_start_candidates(&self, active: &[String]) -> Vec<usize> {
+        let all: Vec<usize> = (0..active.len()).collect();
+        let window = match self.slow_start {
+            Some(window) => window,
+            None => return all,
+        };
+        let readmitted_at = self.readmitted_at.lock().await;
+        if readmitted_at.is_empty() {
+            return all;
+        }
+        let mut rng = rand::rngs::StdRng::from_entropy();
+        let warm: Vec<usize> = all
+            .iter()
+            .copied()
+            .filter(|&idx| match readmitted_at.get(&active[idx]) {
+                Some(readmitted) => {
+                    let progress = readmitted.elapsed().as_secs_f64() / window.as_secs_f64();
+                    let share =
+                        SLOW_START_INITIAL_SHARE + (1.0 - SLOW_START_INITIAL_SHARE) * progress;
+                    rng.gen_bool(share.min(1.0))
+                }
+                None => true,
+            })
+            .collect();
+        if warm.is_empty() {
+            all
+        } else {
+            warm
+        }
+    }
+
+    /// Returns the weight the upstream at `address` was configured with.
+    fn weight(&self, address: &str) -> u32 {
+        self.upstream_addresses
+            .iter()
+            .position(|upstream| upstream == address)
+            .map_or(1, |i| self.weights[i])
+    }
+
+    /// Picks an index into `active` at random, where the chance of picking each upstream is
+    /// proportional to its weight. Upstreams with equal weights are picked uniformly.
+    fn weighted_index(&self, active: &[String]) -> usize {
+        let weights: Vec<u64> = active
+            .iter()
+            .map(|address| u64::from(self.weight(address)))
+            .collect();
+        // summed as u64 so that large weights can't overflow
+        let total: u64 = weights.iter().sum();
+        let mut point = rand::rngs::StdRng::from_entropy().gen_range(0..total);
+        for (idx, weight) in weights.iter().enumerate() {
+            if point < *weight {
+                return idx;
+            }
+            point -= weight;
+        }
+        active.len() - 1
+    }
+
+    /// Picks an index into `active` at random like weighted_index, but with each upstream's weight
+    /// divided by its average latency, so that faster upstreams get more of the requests. An
+    /// upstream that hasn't answered a request yet is given the average latency of the others.
+    async fn latency_weighted_index(&self, active: &[String]) -> usize {
+        let averages = self.upstream_latency_ms.lock().await;
+        let known: Vec<f64> = active
+            .iter()
+            .filter_map(|address| averages.get(address).copied())
+            .collect();
+        let neutral = if known.is_empty() {
+            1.0
+        } else {
+            known.iter().sum::<f64>() / known.len() as f64
+        };
+        let weights: Vec<f64> = active
+            .iter()
+            .map(|address| {
+                let latency = averages.get(address).copied().unwrap_or(neutral);
+                self.weight(address) as f64 / latency.max(MIN_LATENCY_MS)
+            })
+            .collect();
+        drop(averages);
+        let total: f64 = weights.iter().sum();
+        let mut point = rand::rngs::StdRng::from_entropy().gen_range(0.0..total);
+        for (idx, weight) in weights.iter().enumerate() {
+            if point < *weight {
+                return idx;
+            }
+            point -= weight;
+        }
+        active.len() - 1
+    }
+
+    /// Cycles through the upstreams in order, one upstream per call. The counter walks the full
+    /// `upstream_addresses` list rather than `active`, since upstreams that fail are removed from
+    /// `active` (shifting the remaining indices around); upstreams that aren't active are skipped.
+    fn round_robin_index(&self, active: &[String]) -> usize {
+        let num_upstreams = self.upstream_addresses.len();
+        for _ in 0..num_upstreams {
+            let turn = self.round_robin_counter.fetch_add(1, Ordering::Relaxed) % num_upstreams;
+            let address = &self.upstream_addresses[turn];
+            if let Some(idx) = active.iter().position(|upstream| upstream == address) {
+                return idx;
+            }
+        }
+        0
+    }
+
+    /// Picks the index into `active` of the upstream with the fewest requests in flight. Ties are
+    /// broken at random so that idle upstreams share the load evenly.
+    async fn least_connections_index(&self, active: &[String]) -> usize {
+        let in_flight = self.in_flight.lock().await;
+        let counts: Vec<usize> = active
+            .iter()
+            .map(|address| *in_flight.get(address).unwrap_or(&0))
+            .collect();
+        drop(in_flight);
+        let min_count = *counts.iter().min().unwrap();
+        let candidates: Vec<usize> = (0..active.len())
+            .filter(|&idx| counts[idx] == min_count)
+            .collect();
+        candidates[rand::rngs::StdRng::from_entropy().gen_range(0..candidates.len())]
+    }
+}
+
+/// Share of its usual requests that an upstream gets at the start of its slow start window
+const SLOW_START_INITIAL_SHARE: f64 = 0.1;
+
+/// Number of points each upstream occupies on the consistent hash ring. More points spread clients
+/// more evenly across upstreams.
+const HASH_RING_POINTS_PER_UPSTREAM: usize = 100;
+
+/// Builds the consistent hash ring for the `active` upstreams: each one is placed on the ring at
+/// points derived only from its own address, sorted by position.
+fn build_hash_ring(active: &[String]) -> Vec<(u64, String)> {
+    let mut ring: Vec<(u64, String)> = active
+        .iter()
+        .flat_map(|address| {
+            (0..HASH_RING_POINTS_PER_UPSTREAM).map(move |point| {
+                let hash = stable_hash(format!("{}#{}", address, point).as_bytes());
+                (hash, address.clone())
+            })
+        })
+        .collect();
+    ring.sort_unstable();
+    ring
+}
+
+/// Maps `client_ip` onto one of the `candidates` using the consistent hash `ring`, so that the
+/// same client keeps landing on the same upstream. Returns an index into `candidates`.
+///
+/// A client goes to the first upstream point at or after the client's hash, skipping upstreams
+/// that aren't candidates for this request. When an upstream drops out, only the clients that
+/// mapped to its points move; everyone else stays put.
+fn ip_hash_index(ring: &[(u64, String)], candidates: &[String], client_ip: &str) -> usize {
+    let client_hash = stable_hash(client_ip.as_bytes());
+    let pos = ring.partition_point(|(point, _)| *point < client_hash);
+    // Wrap around to the start of the ring if the client hashes past the last point
+    ring[pos..]
+        .iter()
+        .chain(&ring[..pos])
+        .find_map(|(_, address)| candidates.iter().position(|candidate| candidate == address))
+        // The ring can lag behind a change to the active upstreams for a moment
+        .unwrap_or(client_hash as usize % candidates.len())
+}
+
+/// 64-bit FNV-1a followed by a final avalanche step (from MurmurHash3) so that similar inputs,
+/// like addresses differing in one digit, spread out across the ring. Unlike `DefaultHasher`, the
+/// output is the same across runs and Rust versions.
+fn stable_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+/// Records that a request is about to be forwarded to `upstream`.
+async fn start_in_flight(state: &ProxyState, upstream: &str) {
+    let mut in_flight = state.in_flight.lock().await;
+    *in_flight.entry(upstream.to_string()).or_insert(0) += 1;
+}
+
+/// Records that `upstream` is done with a request (successfully or not).
+async fn finish_in_flight(state: &ProxyState, upstream: &str) {
+    let mut in_flight = state.in_flight.lock().await;
+    if let Some(count) = in_flight.get_mut(upstream) {
+        *count = count.saturating_sub(1);
+    }
+}
+
+/// A stream to an upstream server, which is encrypted for https:// upstreams
+enum UpstreamStream {
+    Plain(TcpStream),
+    Tls(Box<tokio_rustls::client::TlsStream<TcpStream>>),
+    Unix(UnixStream),
 }
 
-// 可以考虑优化随机算法，如 Fisher-Yates
-// 故障转移 + 选择
-async fn connect_to_upstream(state: &ProxyState) -> Result<TcpStream, std::io::Error> {
-    let mut rng = rand::rngs::StdRng::from_entropy();
+impl UpstreamStream {
+    /// Reads whatever is waiting on the underlying socket without blocking, bypassing TLS.
+    fn try_read(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            UpstreamStream::Plain(stream) => stream.try_read(buf),
+            UpstreamStream::Tls(stream) => stream.get_ref().0.try_read(buf),
+            UpstreamStream::Unix(stream) => stream.try_read(buf),
+        }
+    }
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Splits an upstream address into its `host:port` and whether it is an https:// upstream.
+fn split_upstream_scheme(address: &str) -> (&str, bool) {
+    if let Some(host_port) = address.strip_prefix("https://") {
+        (host_port, true)
+    } else {
+        (address.strip_prefix("http://").unwrap_or(address), false)
+    }
+}
+
+/// The token balancebeam adds to the Via header of requests and responses passing through it
+/// (RFC 7230 section 5.7.1), with the version in a comment since pseudonyms can't contain a "/"
+const VIA: &str = concat!("1.1 balancebeam (balancebeam/", env!("CARGO_PKG_VERSION"), ")");
+
+/// Returns the path of the socket for a `unix:/path/to.sock` upstream address.
+fn unix_socket_path(address: &str) -> Option<&str> {
+    address.strip_prefix("unix:")
+}
+
+/// Returns the value of the Host header that addresses the upstream at `address` itself.
+fn upstream_host(address: &str) -> &str {
+    if unix_socket_path(address).is_some() {
+        // A Unix socket has no host name of its own
+        return "localhost";
+    }
+    split_upstream_scheme(address).0
+}
+
+/// Opens a new connection to the upstream at `address`, doing the TLS handshake for https://
+/// upstreams.
+async fn connect_upstream_stream(
+    address: &str,
+    tls_connector: &TlsConnector,
+) -> Result<UpstreamStream, std::io::Error> {
+    if let Some(path) = unix_socket_path(address) {
+        return Ok(UpstreamStream::Unix(UnixStream::connect(path).await?));
+    }
+    let (host_port, tls) = split_upstream_scheme(address);
+    let stream = TcpStream::connect(host_port).await?;
+    if !tls {
+        return Ok(UpstreamStream::Plain(stream));
+    }
+    // Send the host (without the port, or the brackets around an IPv6 address) as the SNI name
+    let host = host_port
+        .rsplit_once(':')
+        .map_or(host_port, |(host, _)| host)
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let server_name = rustls::pki_types::ServerName::try_from(host.to_string())
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    let stream = tls_connector.connect(server_name, stream).await?;
+    Ok(UpstreamStream::Tls(Box::new(stream)))
+}
+
+/// How much each new latency sample moves an upstream's moving average
+const UPSTREAM_LATENCY_SMOOTHING: f64 = 0.2;
+
+/// Latency that faster upstreams are rounded up to by the latency-weighted strategy, so that one
+/// answering in next to no time doesn't take all of the requests
+const MIN_LATENCY_MS: f64 = 1.0;
+
+/// Folds how long `upstream` took to answer a request into its moving average latency.
+async fn record_upstream_latency(state: &ProxyState, upstream: &str, latency: Duration) {
+    let sample = latency.as_secs_f64() * 1000.0;
+    let mut averages = state.upstream_latency_ms.lock().await;
+    averages
+        .entry(upstream.to_string())
+        .and_modify(|average| {
+            *average += UPSTREAM_LATENCY_SMOOTHING * (sample - *average);
+        })
+        .or_insert(sample);
+}
+
+/// An open connection to an upstream server
+struct UpstreamConnection {
+    stream: UpstreamStream,
+    /// Address of the upstream this connection goes to
+    address: String,
+    /// Whether this connection was taken from the idle pool rather than freshly opened. Pooled
+    /// connections may have been closed by the upstream while they sat idle.
+    reused: bool,
+}
+
+/// Takes an idle connection to `address` out of the pool, if there is a usable one. Connections
+/// the upstream has closed (or that have unexpected data waiting on them) are thrown away.
+async fn take_idle_connection(state: &ProxyState, address: &str) -> Option<UpstreamStream> {
+    let mut idle_connections = state.idle_connections.lock().await;
+    let idle = idle_connections.get_mut(address)?;
+    while let Some(stream) = idle.pop() {
+        let mut buf = [0_u8; 1];
+        match stream.try_read(&mut buf) {
+            // Nothing to read means the connection is still open and quiet
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => return Some(stream),
+            _ => log::debug!("Discarding stale idle connection to {}", address),
+        }
+    }
+    None
+}
+
+/// Puts a connection back in the idle pool once a response has been fully read from it, unless
+/// the pool for that upstream is already full.
+async fn release_connection(state: &ProxyState, upstream: UpstreamConnection) {
+    let mut idle_connections = state.idle_connections.lock().await;
+    let idle = idle_connections.entry(upstream.address).or_default();
+    if idle.len() < state.max_idle_per_upstream {
+        idle.push(upstream.stream);
+    }
+}
+
+/// Opens a connection to `address`, reusing an idle pooled connection if one is available.
+async fn open_connection(
+    state: &ProxyState,
+    address: &str,
+) -> Result<UpstreamConnection, std::io::Error> {
+    if let Some(stream) = take_idle_connection(state, address).await {
+        return Ok(UpstreamConnection {
+            stream,
+            address: address.to_string(),
+            reused: true,
+        });
+    }
+    Ok(UpstreamConnection {
+        stream: connect_upstream_stream(address, &state.tls_connector).await?,
+        address: address.to_string(),
+        reused: false,
+    })
+}
+
+/// Takes an upstream out of rotation after connecting or forwarding a request to it failed, and
+/// throws away its idle connections. The next successful active health check puts it back.
+async fn mark_upstream_down(state: &ProxyState, upstream_ip: &str) {
+    let mut active_upstream_writer = state.active_upstream.write().await;
+    if let Some(idx) = active_upstream_writer.iter().position(|upstream| upstream == upstream_ip) {
+        log::warn!("Removing upstream {} from rotation", upstream_ip);
+        active_upstream_writer.swap_remove(idx);
+    }
+    state.metrics.set_upstreams_active(active_upstream_writer.len());
+    *state.hash_ring.write().await = build_hash_ring(&active_upstream_writer);
+    drop(active_upstream_writer);
+    state.idle_connections.lock().await.remove(upstream_ip);
+}
+
+/// Records whether connecting to `upstream` worked. A single failure could just be a blip, so the
+/// upstream is only taken out of rotation once max_connect_failures attempts in a row have failed.
+async fn record_connect_result(state: &ProxyState, upstream: &str, connected: bool) {
+    let mut connect_failures = state.connect_failures.lock().await;
+    if connected {
+        connect_failures.remove(upstream);
+        return;
+    }
+    let failures = connect_failures.entry(upstream.to_string()).or_insert(0);
+    *failures += 1;
+    if *failures >= state.max_connect_failures {
+        connect_failures.remove(upstream);
+        drop(connect_failures);
+        mark_upstream_down(state, upstream).await;
+    }
+}
+
+/// Connects to an active upstream in `pool` chosen by the configured strategy, skipping any
+/// upstream listed in `exclude` or whose circuit breaker is open. If connecting fails, another
+/// upstream is picked, until each one has been tried once. An upstream that can't be connected
+/// to several times in a row is taken out of rotation until the health checks bring it back.
+async fn connect_to_upstream(
+    state: &ProxyState,
+    client_ip: &str,
+    pool: &[String],
+    exclude: &[String],
+) -> Result<UpstreamConnection, std::io::Error> {
+    // Upstreams already tried for this request (or whose half-open circuit breaker another
+    // request got to first). Each retry leaves these out and asks the strategy again, rather than
+    // going through a shuffled list, so that retries still follow the weights, IP hash and so on.
+    let mut tried: Vec<String> = Vec::new();
     loop {
-        let active_stream_reader = state.active_upstream.read().await;
-        let idx = rng.gen_range(0..active_stream_reader.len());
-        let upstream_ip = &active_stream_reader.get(idx).unwrap().clone();
-        drop(active_stream_reader);
+        let candidates: Vec<String> = state
+            .active_upstream
+            .read()
+            .await
+            .iter()
+            .filter(|upstream| {
+                pool.contains(upstream)
+                    && !exclude.contains(upstream)
+                    && !tried.contains(upstream)
+                    && state.circuit_breakers.allows(upstream)
+            })
+            .cloned()
+            .collect();
+        let idx = match state.select_upstream(&candidates, client_ip).await {
+            Some(idx) => idx,
+            None if tried.is_empty() => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionRefused,
+                    "No active upstream to connect to",
+                ));
+            }
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionRefused,
+                    "Failed to connect to any upstream",
+                ));
+            }
+        };
+        let upstream_ip = &candidates[idx];
+        tried.push(upstream_ip.clone());
+        if !state.circuit_breakers.try_acquire(upstream_ip) {
+            continue;
+        }
 
-        match TcpStream::connect(upstream_ip).await {
-            Ok(stream) => {
-                return Ok(stream);
+        match open_connection(state, upstream_ip).await {
+            Ok(upstream) => {
+                record_connect_result(state, upstream_ip, true).await;
+                return Ok(upstream);
             }
             Err(err) => {
                 log::error!("Failed to connect to upstream {}: {}", &upstream_ip, err);
-                let mut active_upstream_writer = state.active_upstream.write().await;
-                active_upstream_writer.swap_remove(idx);
-                if active_upstream_writer.len() == 0 {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::ConnectionRefused,
-                        "Failed to connect to any upstream",
-                    ));
-                }
-                drop(active_upstream_writer);
+                state.circuit_breakers.record_failure(upstream_ip);
+                record_connect_result(state, upstream_ip, false).await;
             }
         }
     }
 }
 
-async fn send_response(client_conn: &mut TcpStream, response: &http::Response<Vec<u8>>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
+/// Returns the address of the upstream that the request's sticky session cookie pins the client
+/// to, as long as sticky sessions are enabled and that upstream is still active.
+async fn pinned_upstream(state: &ProxyState, request: &http::Request<Vec<u8>>) -> Option<String> {
+    let cookie_name = state.sticky_cookie.as_ref()?;
+    let idx = request::get_cookie(request, cookie_name)?
+        .parse::<usize>()
+        .ok()?;
+    let address = state.upstream_addresses.get(idx)?;
+    if state.active_upstream.read().await.contains(address) {
+        Some(address.clone())
+    } else {
+        None
+    }
+}
+
+/// Logs a line about the request with ID `$request_id`, both in the message (for the text logger)
+/// and as a `request_id` field (for the JSON logger), so that the lines about a request can be
+/// picked out of the log.
+macro_rules! request_log {
+    ($level:ident, $request_id:expr; $($arg:tt)+) => {
+        log::$level!(
+            request_id = $request_id;
+            "[{}] {}",
+            $request_id,
+            format_args!($($arg)+)
+        )
+    };
+}
+
+/// Longest X-Request-ID accepted from a client; longer ones are replaced with a generated ID
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Returns the ID to tag the request with: the X-Request-ID the client (or a proxy in front of
+/// us) sent, if it is reasonable, or else a new random one.
+fn choose_request_id(request: &http::Request<Vec<u8>>) -> String {
+    match request
+        .headers()
+        .get("x-request-id")
+        .and_then(|id| id.to_str().ok())
+    {
+        Some(id) if !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN => id.to_string(),
+        _ => format!("{:016x}", rand::random::<u64>()),
+    }
+}
+
+/// Returns the X-Request-ID that handle_connection tagged the request with.
+fn request_id(request: &http::Request<Vec<u8>>) -> &str {
+    request
+        .headers()
+        .get("x-request-id")
+        .and_then(|id| id.to_str().ok())
+        .unwrap_or("-")
+}
+
+/// Opens a connection to the upstream that should handle `request`: the client's pinned upstream
+/// if it has a valid sticky session cookie, or else one picked by connect_to_upstream from `pool`,
+/// the upstreams the request is routed to. Upstreams listed in `exclude` (because the request
+/// already failed there) are never picked.
+async fn connect_for_request(
+    state: &ProxyState,
+    client_ip: &str,
+    request: &http::Request<Vec<u8>>,
+    pool: &[String],
+    exclude: &[String],
+) -> Result<UpstreamConnection, std::io::Error> {
+    if let Some(address) = pinned_upstream(state, request)
+        .await
+        .filter(|address| pool.contains(address) && !exclude.contains(address))
+        .filter(|address| state.circuit_breakers.try_acquire(address))
+    {
+        match open_connection(state, &address).await {
+            Ok(upstream) => return Ok(upstream),
+            Err(err) => {
+                request_log!(
+                    warn, request_id(request);
+                    "Failed to connect to pinned upstream {}, picking another: {}",
+                    address,
+                    err
+                );
+                state.circuit_breakers.record_failure(&address);
+            }
+        }
+    }
+    connect_to_upstream(state, client_ip, pool, exclude).await
+}
+
+/// Ways that forwarding a request to an upstream can fail
+#[derive(Debug)]
+enum ForwardError {
+    /// The request couldn't be written to the upstream
+    Request(std::io::Error),
+    /// No valid response could be read back from the upstream
+    Response(response::Error),
+    /// The upstream didn't answer within the upstream timeout
+    Timeout,
+}
+
+/// Sends `request` to the upstream and reads back its response, giving up if that takes longer
+/// than `upstream_timeout`.
+async fn forward_request(
+    request: &http::Request<Vec<u8>>,
+    upstream: &mut UpstreamConnection,
+    upstream_timeout: Option<Duration>,
+) -> Result<http::Response<Vec<u8>>, ForwardError> {
+    let exchange = async {
+        request::write_to_stream(request, &mut upstream.stream)
+            .await
+            .map_err(ForwardError::Request)?;
+        request_log!(debug, request_id(request); "Forwarded request to server");
+        response::read_from_stream(&mut upstream.stream, request.method())
+            .await
+            .map_err(ForwardError::Response)
+    };
+    match upstream_timeout {
+        Some(limit) => timeout(limit, exchange)
+            .await
+            .unwrap_or(Err(ForwardError::Timeout)),
+        None => exchange.await,
+    }
+}
+
+/// Returns whether requests with this method can safely be sent again if the first attempt fails.
+fn is_idempotent(method: &http::Method) -> bool {
+    method == http::Method::GET || method == http::Method::HEAD
+}
+
+/// Returns whether the Connection header says the connection will be closed after this message.
+fn connection_close(headers: &http::HeaderMap) -> bool {
+    headers
+        .get_all("connection")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|option| option.trim().eq_ignore_ascii_case("close"))
+}
+
+/// Returns whether the client is asking to switch the connection over to the WebSocket protocol.
+fn is_websocket_upgrade(request: &http::Request<Vec<u8>>) -> bool {
+    request
+        .headers()
+        .get_all("upgrade")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|protocol| protocol.trim().eq_ignore_ascii_case("websocket"))
+}
+
+/// Sends `response` to the client, tagged with the X-Request-ID of the request it answers, and
+/// gzipped if the client accepts that.
+async fn send_response(
+    client_conn: &mut ClientConnection,
+    response: &mut http::Response<Vec<u8>>,
+) {
+    if let Some(min_size) = client_conn.gzip_min_size {
+        response::gzip(response, min_size);
+    }
+    if let Some(request_id) = &client_conn.request_id {
+        response
+            .headers_mut()
+            .insert("x-request-id", http::HeaderValue::from_str(request_id).unwrap());
+    }
+    let upstream_latency_ms = client_conn
+        .upstream_latency
+        .map(|latency| latency.as_millis() as u64);
     log::info!(
-        "{} <- {}",
-        client_ip,
-        response::format_response_line(&response)
+        request_id = client_conn.request_id.as_deref(),
+        client_ip = client_conn.ip.as_str(),
+        status = response.status().as_u16(),
+        latency_ms = client_conn.request_started.elapsed().as_millis() as u64,
+        upstream_latency_ms = upstream_latency_ms;
+        "{}{} <- {}{}",
+        client_conn
+            .request_id
+            .as_ref()
+            .map_or(String::new(), |id| format!("[{}] ", id)),
+        client_conn.ip,
+        response::format_response_line(response),
+        upstream_latency_ms.map_or(String::new(), |ms| format!(" (upstream took {} ms)", ms))
     );
-    if let Err(error) = response::write_to_stream(&response, client_conn).await {
+    if let Err(error) = response::write_to_stream(response, &mut client_conn.stream).await {
         log::warn!("Failed to send response to client: {}", error);
-        return;
     }
 }
 
-async fn update_ip_info(ip: &str, state: &ProxyState) {
-    let mut ip_counter = state.ip_count.clone().lock_owned().await;
-    if state.max_requests_per_minute == 0 {
-        return;
+/// Records the answer to a client's request in the access log, if there is one.
+fn log_access(
+    state: &ProxyState,
+    client_ip: &str,
+    request: &http::Request<Vec<u8>>,
+    response: &http::Response<Vec<u8>>,
+) {
+    if let Some(access_log) = &state.access_log {
+        access_log.record(client_ip, request, response);
     }
-    let cnt = ip_counter.entry(ip.to_string()).or_insert(0);
-    *cnt += 1;
 }
 
-async fn check_ip_rate_limit(ip: &String, state: &ProxyState) -> bool {
-    let ip_info = state.ip_count.clone().lock_owned().await;
-    if ip_info.get(ip).is_none() || state.max_requests_per_minute == 0{
-        return false;
+/// Refills the IP's token bucket for the time since it was last used, then tries to take a token
+/// out of it. Returns false if the bucket is empty, meaning the request should be rejected.
+async fn take_token(ip: &str, state: &ProxyState) -> bool {
+    let mut token_buckets = state.token_buckets.lock().await;
+    let now = Instant::now();
+    let capacity = state.rate_limit_burst as f64;
+    let bucket = token_buckets.entry(ip.to_string()).or_insert(TokenBucket {
+        tokens: capacity,
+        last_refill: now,
+    });
+    let tokens_per_second = state.max_requests_per_minute as f64 / 60.0;
+    let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * tokens_per_second).min(capacity);
+    bucket.last_refill = now;
+    if bucket.tokens >= 1.0 {
+        bucket.tokens -= 1.0;
+        true
+    } else {
+        false
     }
-    *ip_info.get(ip).unwrap() > state.max_requests_per_minute
 }
 
+/// Every RATE_LIMIT_SWEEP_INTERVAL, drops the token buckets that have refilled all the way. A
+/// full bucket is the same as no bucket, and without this a client could grow the map without
+/// bound by rate limiting on a different key every time (e.g. with --rate-limit-key-header).
+async fn sweep_token_buckets(state: &ProxyState) {
+    let capacity = state.rate_limit_burst as f64;
+    let tokens_per_second = state.max_requests_per_minute as f64 / 60.0;
+    loop {
+        sleep(RATE_LIMIT_SWEEP_INTERVAL).await;
+        let now = Instant::now();
+        state.token_buckets.lock().await.retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+            bucket.tokens + elapsed * tokens_per_second < capacity
+        });
+    }
+}
 
-async fn handle_connection(mut client_conn: TcpStream, state: &ProxyState) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    log::info!("Connection received from {}", client_ip);
+/// Forgets the IP's requests that have slid out of the window, then records this request if the
+/// IP still has fewer than max_requests_per_minute requests in the window. Returns false if it
+/// doesn't, meaning the request should be rejected.
+async fn log_request_in_window(ip: &str, state: &ProxyState) -> bool {
+    let mut request_log = state.request_log.lock().await;
+    let now = Instant::now();
+    let requests = request_log.entry(ip.to_string()).or_default();
+    while let Some(oldest) = requests.front() {
+        if now.duration_since(*oldest) < SLIDING_WINDOW {
+            break;
+        }
+        requests.pop_front();
+    }
+    if requests.len() < state.max_requests_per_minute {
+        requests.push_back(now);
+        true
+    } else {
+        if requests.is_empty() {
+            request_log.remove(ip);
+        }
+        false
+    }
+}
 
+/// Every RATE_LIMIT_SWEEP_INTERVAL, drops the request log of each key whose requests have all
+/// slid out of the window. Otherwise keys that are seen once (e.g. a different
+/// --rate-limit-key-header value every time) would stay in the map for good.
+async fn sweep_request_log(state: &ProxyState) {
+    loop {
+        sleep(RATE_LIMIT_SWEEP_INTERVAL).await;
+        let now = Instant::now();
+        state.request_log.lock().await.retain(|_, requests| {
+            requests.back().is_some_and(|newest| now.duration_since(*newest) < SLIDING_WINDOW)
+        });
+    }
+}
+
+/// Works out how long a rate-limited IP has to wait before its next request would be allowed,
+/// rounded up to whole seconds (and at least one second).
+async fn rate_limit_retry_after(ip: &str, state: &ProxyState) -> Duration {
+    let now = Instant::now();
+    let wait = match state.rate_limit_algo {
+        RateLimitAlgo::FixedWindow => state.window_reset_at.lock().await.saturating_duration_since(now),
+        RateLimitAlgo::TokenBucket => {
+            let tokens = state.token_buckets.lock().await.get(ip).map_or(0.0, |b| b.tokens);
+            let tokens_per_second = state.max_requests_per_minute as f64 / 60.0;
+            Duration::from_secs_f64((1.0 - tokens).max(0.0) / tokens_per_second)
+        }
+        RateLimitAlgo::SlidingWindow => match state.request_log.lock().await.get(ip) {
+            Some(requests) => requests.front().map_or(Duration::ZERO, |oldest| {
+                SLIDING_WINDOW.saturating_sub(now.duration_since(*oldest))
+            }),
+            None => Duration::ZERO,
+        },
+    };
+    Duration::from_secs(wait.as_secs_f64().ceil().max(1.0) as u64)
+}
 
+/// Returns the key that a request is counted under for rate limiting: the value of
+/// --rate-limit-key-header if it was given and the request has it, otherwise the client IP.
+fn rate_limit_key(state: &ProxyState, request: &http::Request<Vec<u8>>, client_ip: &str) -> String {
+    state
+        .rate_limit_key_header
+        .as_ref()
+        .and_then(|name| request.headers().get(name))
+        .and_then(|value| value.to_str().ok())
+        .map_or_else(|| client_ip.to_string(), |value| value.to_string())
+}
 
-    // Open a connection to a random destination server
-    let mut upstream_conn = match connect_to_upstream(state).await {
-        Ok(stream) => stream,
-        Err(_error) => {
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+/// Returns true if the client IP falls in one of the --rate-limit-exempt ranges.
+fn is_rate_limit_exempt(client_ip: &str, state: &ProxyState) -> bool {
+    match client_ip.parse::<std::net::IpAddr>() {
+        Ok(addr) => state.rate_limit_exempt.iter().any(|range| range.contains(&addr)),
+        Err(_) => false,
+    }
+}
+
+async fn check_ip_rate_limit(ip: &String, client_ip: &str, state: &ProxyState) -> bool {
+    if is_rate_limit_exempt(client_ip, state) || state.max_requests_per_minute == 0 {
+        return false;
+    }
+    let limited = match state.rate_limit_algo {
+        RateLimitAlgo::TokenBucket => !take_token(ip, state).await,
+        RateLimitAlgo::SlidingWindow => !log_request_in_window(ip, state).await,
+        RateLimitAlgo::FixedWindow => {
+            // Count this request and compare under the same lock, so that concurrent requests from
+            // one IP can't both slip in under the limit. The request that makes the count reach
+            // max_requests_per_minute is still allowed; the one after it is the first to be
+            // rejected.
+            let mut ip_count = state.ip_count.lock().await;
+            let count = ip_count.entry(ip.to_string()).or_insert(0);
+            *count += 1;
+            *count > state.max_requests_per_minute
         }
     };
-    let upstream_ip = upstream_conn.peer_addr().unwrap().ip().to_string();
+    if limited {
+        state.metrics.record_rate_limited();
+    }
+    limited
+}
+
+
+async fn handle_connection(mut client_conn: ClientConnection, state: &ProxyState) {
+    let client_ip = client_conn.ip.clone();
+    log::info!("Connection received from {}", client_ip);
+
+
 
     // The client may now send us one or more requests. Keep trying to read requests until the
     // client hangs up or we get an error.
     loop {
-        // Read a request from the client
-        let mut request = match request::read_from_stream(&mut client_conn).await {
+        // Read a request from the client. If balancebeam starts shutting down while we're waiting,
+        // or the client takes too long to send anything, close the connection instead of waiting
+        // for another request.
+        let mut shutting_down = state.shutting_down.clone();
+        let idle_timeout = async {
+            match state.client_idle_timeout {
+                Some(limit) => sleep(limit).await,
+                None => std::future::pending().await,
+            }
+        };
+        let read_result = tokio::select! {
+            result = request::read_from_stream(
+                &mut client_conn.stream,
+                state.max_header_bytes,
+                state.max_request_body_bytes,
+            ) => result,
+            _ = shutting_down.wait_for(|shutting_down| *shutting_down) => {
+                log::debug!("Shutting down, closing connection from {}", client_ip);
+                return;
+            }
+            _ = idle_timeout => {
+                log::debug!("Connection from {} idle for too long, closing it", client_ip);
+                return;
+            }
+        };
+        client_conn.request_started = Instant::now();
+        client_conn.upstream_latency = None;
+        client_conn.request_id = None;
+        client_conn.gzip_min_size = None;
+        let mut request = match read_result {
             Ok(request) => request,
             // Handle case where client closed connection and is no longer sending requests
             Err(request::Error::IncompleteRequest(0)) => {
@@ -223,7 +2001,7 @@ async fn handle_connection(mut client_conn: TcpStream, state: &ProxyState) {
             }
             Err(error) => {
                 log::debug!("Error parsing request: {:?}", error);
-                let response = response::make_http_error(match error {
+                let mut response = response::make_http_error(match error {
                     request::Error::IncompleteRequest(_)
                     | request::Error::MalformedRequest(_)
                     | request::Error::InvalidContentLength
@@ -231,25 +2009,51 @@ async fn handle_connection(mut client_conn: TcpStream, state: &ProxyState) {
                     request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
                     request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
                 });
-                send_response(&mut client_conn, &response).await;
+                // After an oversized body or headers we couldn't make sense of, there's no telling
+                // where the next request starts, so the connection can't be used again
+                let close = matches!(
+                    error,
+                    request::Error::RequestBodyTooLarge | request::Error::MalformedRequest(_)
+                );
+                if close {
+                    response
+                        .headers_mut()
+                        .insert("connection", http::HeaderValue::from_static("close"));
+                }
+                send_response(&mut client_conn, &mut response).await;
+                if close {
+                    return;
+                }
                 continue;
             }
         };
-        log::info!(
-            "{} -> {}: {}",
-            client_ip,
-            upstream_ip,
-            request::format_request_line(&request)
-        );
-
-        // update ip request times
-        update_ip_info(&client_ip, state).await;
+        // Tag the request with an ID, so that it can be followed through our logs and the upstream's
+        let request_id = choose_request_id(&request);
+        request
+            .headers_mut()
+            .insert("x-request-id", http::HeaderValue::from_str(&request_id).unwrap());
+        client_conn.request_id = Some(request_id.clone());
+        if request::accepts_gzip(&request) {
+            client_conn.gzip_min_size = Some(state.gzip_min_size);
+        }
 
-        // check if ip request times is illgeal
-        if check_ip_rate_limit(&client_ip,state).await {
-            log::warn!("{} too many requests in {} second",client_ip,state.time_reset);
-            let response = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
-            send_response(&mut client_conn, &response).await;
+        // count this request against the client's rate limit, and reject it if it is over
+        let rate_limit_key = rate_limit_key(state, &request, &client_ip);
+        if check_ip_rate_limit(&rate_limit_key, &client_ip, state).await {
+            request_log!(
+                warn, request_id;
+                "{} too many requests in {} second",
+                rate_limit_key,
+                state.time_reset
+            );
+            let retry_after = rate_limit_retry_after(&rate_limit_key, state).await;
+            let mut response = response::make_http_error_with_headers(
+                http::StatusCode::TOO_MANY_REQUESTS,
+                &[("Retry-After", retry_after.as_secs().to_string())],
+            );
+            send_response(&mut client_conn, &mut response).await;
+            log_access(state, &client_ip, &request, &response);
+            state.metrics.record_request("", response.status());
             return;
         }
 
@@ -257,93 +2061,362 @@ async fn handle_connection(mut client_conn: TcpStream, state: &ProxyState) {
         // (We're the ones connecting directly to the upstream server, so without this header, the
         // upstream server will only know our IP, not the client's.)
         request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
+        // Likewise, let it know how the client reached us, for building absolute URLs. Values set
+        // by proxies in front of us are kept, the same way as for X-Forwarded-For.
+        let proto = if client_conn.tls { "https" } else { "http" };
+        request::extend_header_value(&mut request, "x-forwarded-proto", proto);
+        if let Some(host) = request
+            .headers()
+            .get("host")
+            .and_then(|host| host.to_str().ok())
+            .map(|host| host.to_string())
+        {
+            request::extend_header_value(&mut request, "x-forwarded-host", &host);
+        }
+        request::extend_header_value(&mut request, "via", VIA);
 
-        // Forward the request to the server
-        if let Err(error) = request::write_to_stream(&request, &mut upstream_conn).await {
-            log::error!(
-                "Failed to send request to upstream {}: {}",
+        // Work out which upstreams the request may go to (before the Host header is rewritten for
+        // the upstream), refusing requests for hosts we don't serve
+        let pool = match state.upstream_pool(&request) {
+            Some(pool) => pool,
+            None => {
+                request_log!(info, request_id; "No upstreams for this host, refusing request");
+                let mut response = response::make_http_error(http::StatusCode::NOT_FOUND);
+                send_response(&mut client_conn, &mut response).await;
+                log_access(state, &client_ip, &request, &response);
+                state.metrics.record_request("", response.status());
+                continue;
+            }
+        };
+
+        // Answer from the cache if we have a fresh copy of the response (unless the client wants
+        // to upgrade the connection, which only the upstream can agree to)
+        let cache_key = cache::cache_key(&request);
+        if let Some(mut response) = state
+            .response_cache
+            .as_ref()
+            .filter(|_| !is_websocket_upgrade(&request))
+            .and_then(|cache| cache.get(&cache_key))
+        {
+            request_log!(info, request_id; "Serving {} from cache", request.uri());
+            response::extend_header_value(&mut response, "via", VIA);
+            send_response(&mut client_conn, &mut response).await;
+            log_access(state, &client_ip, &request, &response);
+            state.metrics.record_request("", response.status());
+            continue;
+        }
+
+        // Forward the request to an upstream and read its response. If that fails, requests that
+        // are safe to repeat get retried on other upstreams, up to max_retries times.
+        let max_attempts = if is_idempotent(request.method()) {
+            state.max_retries + 1
+        } else {
+            1
+        };
+        let mut failed_upstreams: Vec<String> = Vec::new();
+        let (upstream_ip, mut response, upgraded) = loop {
+            // Open a connection to a destination server (possibly one left idle by an earlier
+            // request)
+            let mut upstream =
+                match connect_for_request(state, &client_ip, &request, pool, &failed_upstreams)
+                    .await
+                {
+                    Ok(upstream) => upstream,
+                    Err(_error) => {
+                        let mut response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
+                        send_response(&mut client_conn, &mut response).await;
+                        log_access(state, &client_ip, &request, &response);
+                        state.metrics.record_request("", response.status());
+                        return;
+                    }
+                };
+            let upstream_ip = upstream.address.clone();
+            log::info!(
+                request_id = request_id.as_str(),
+                client_ip = client_ip.as_str(),
+                upstream = upstream_ip.as_str(),
+                method = request.method().as_str(),
+                path = request.uri().to_string().as_str();
+                "[{}] {} -> {}: {}",
+                request_id,
+                client_ip,
                 upstream_ip,
-                error
+                request::format_request_line(&request)
             );
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
-        }
-        log::debug!("Forwarded request to server");
 
-        // Read the server's response
-        let response = match response::read_from_stream(&mut upstream_conn, request.method()).await {
-            Ok(response) => response,
-            Err(error) => {
-                log::error!("Error reading response from server: {:?}", error);
-                let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-                send_response(&mut client_conn, &response).await;
+            if !state.preserve_host {
+                request.headers_mut().insert(
+                    "host",
+                    http::HeaderValue::from_str(upstream_host(&upstream_ip)).unwrap(),
+                );
+            }
+
+            start_in_flight(state, &upstream_ip).await;
+            let mut forwarded_at = Instant::now();
+            let mut result = forward_request(&request, &mut upstream, state.upstream_timeout).await;
+            // Only send the request again if that can't make the upstream act on it twice: it's
+            // idempotent, or writing it failed. An upstream that hung up without answering may
+            // still have run the whole request, so that isn't enough (RFC 7230 section 6.3.1)
+            let replayable = is_idempotent(request.method())
+                || matches!(result, Err(ForwardError::Request(_)));
+            if matches!(result, Err(ForwardError::Request(_) | ForwardError::Response(_)))
+                && upstream.reused
+                && replayable
+            {
+                // The upstream probably closed the pooled connection while it sat idle, so try
+                // again over a brand new connection before giving up on the upstream
+                request_log!(
+                    debug, request_id;
+                    "Pooled connection to {} failed, reconnecting",
+                    upstream_ip
+                );
+                result = match connect_upstream_stream(&upstream_ip, &state.tls_connector).await {
+                    Ok(stream) => {
+                        upstream.stream = stream;
+                        upstream.reused = false;
+                        forwarded_at = Instant::now();
+                        forward_request(&request, &mut upstream, state.upstream_timeout).await
+                    }
+                    Err(err) => Err(ForwardError::Request(err)),
+                };
+            }
+            let mut timed_out = false;
+            match result {
+                Ok(response) => {
+                    let latency = forwarded_at.elapsed();
+                    client_conn.upstream_latency = Some(latency);
+                    record_upstream_latency(state, &upstream_ip, latency).await;
+                    state.circuit_breakers.record_success(&upstream_ip);
+                    if response.status() == http::StatusCode::SWITCHING_PROTOCOLS
+                        && is_websocket_upgrade(&request)
+                    {
+                        // This connection now belongs to the client, so it mustn't be pooled
+                        break (upstream_ip, response, Some(upstream));
+                    }
+                    if connection_close(request.headers()) || connection_close(response.headers())
+                    {
+                        // One side means to close the connection after this exchange, so don't
+                        // send another request down it
+                        request_log!(
+                            debug, request_id;
+                            "Not reusing connection to {}: Connection: close",
+                            upstream_ip
+                        );
+                    } else {
+                        release_connection(state, upstream).await;
+                    }
+                    if let Some(cache) = &state.response_cache {
+                        cache.store(cache_key.clone(), &request, &response);
+                    }
+                    break (upstream_ip, response, None);
+                }
+                Err(ForwardError::Timeout) => {
+                    request_log!(
+                        error, request_id;
+                        "Upstream {} timed out answering the request",
+                        upstream_ip
+                    );
+                    timed_out = true;
+                }
+                Err(ForwardError::Request(error)) => request_log!(
+                    error, request_id;
+                    "Failed to send request to upstream {}: {}",
+                    upstream_ip,
+                    error
+                ),
+                Err(ForwardError::Response(error)) => request_log!(
+                    error, request_id;
+                    "Error reading response from server: {:?}",
+                    error
+                ),
+            }
+            state.circuit_breakers.record_failure(&upstream_ip);
+            mark_upstream_down(state, &upstream_ip).await;
+            finish_in_flight(state, &upstream_ip).await;
+            failed_upstreams.push(upstream_ip);
+            if failed_upstreams.len() >= max_attempts {
+                let mut response = response::make_http_error(if timed_out {
+                    http::StatusCode::GATEWAY_TIMEOUT
+                } else {
+                    http::StatusCode::BAD_GATEWAY
+                });
+                send_response(&mut client_conn, &mut response).await;
+                log_access(state, &client_ip, &request, &response);
+                state
+                    .metrics
+                    .record_request(failed_upstreams.last().unwrap(), response.status());
                 return;
             }
+            request_log!(info, request_id; "Retrying request on another upstream");
         };
+
+        // Let the client know which upstream it is pinned to from now on
+        if let Some(cookie_name) = &state.sticky_cookie {
+            if pinned_upstream(state, &request).await.as_ref() != Some(&upstream_ip) {
+                let idx = state
+                    .upstream_addresses
+                    .iter()
+                    .position(|upstream| *upstream == upstream_ip)
+                    .unwrap();
+                let cookie = format!("{}={}; Path=/; HttpOnly", cookie_name, idx);
+                response
+                    .headers_mut()
+                    .append("set-cookie", http::HeaderValue::from_str(&cookie).unwrap());
+            }
+        }
+
         // Forward the response to the client
-        send_response(&mut client_conn, &response).await;
-        log::debug!("Forwarded response to client");
+        response::extend_header_value(&mut response, "via", VIA);
+        send_response(&mut client_conn, &mut response).await;
+        log_access(state, &client_ip, &request, &response);
+        state.metrics.record_request(&upstream_ip, response.status());
+
+        // The upstream agreed to switch protocols, so from now on just pass bytes back and forth
+        // between the client and upstream until either side hangs up
+        if let Some(mut upstream) = upgraded {
+            request_log!(info, request_id; "Upgraded to WebSocket, tunneling to {}", upstream_ip);
+            match tokio::io::copy_bidirectional(&mut client_conn.stream, &mut upstream.stream).await
+            {
+                Ok((to_upstream, to_client)) => request_log!(
+                    debug, request_id;
+                    "WebSocket closed after {} bytes to upstream, {} bytes to client",
+                    to_upstream,
+                    to_client
+                ),
+                Err(err) => request_log!(info, request_id; "WebSocket tunnel closed: {}", err),
+            }
+            finish_in_flight(state, &upstream_ip).await;
+            return;
+        }
+
+        finish_in_flight(state, &upstream_ip).await;
+        request_log!(debug, request_id; "Forwarded response to client");
+
+        if connection_close(request.headers()) {
+            log::debug!("Client asked to close the connection");
+            return;
+        }
     }
 }
 
-#[allow(while_true)]
-async fn active_health_check(state: &ProxyState){
-    while true {
+/// Sends a health check request for `path` to a single upstream, returning whether it responded
+/// with `expected_status`.
+async fn check_upstream(
+    upstream_ip: &str,
+    path: &str,
+    expected_status: u16,
+    tls_connector: &TlsConnector,
+) -> bool {
+    let request = http::Request::builder()
+        .method(http::Method::GET)
+        .uri(path)
+        .header("Host", upstream_host(upstream_ip))
+        .body(Vec::new())
+        .unwrap();
+
+    let mut upstream_conn = match connect_upstream_stream(upstream_ip, tls_connector).await {
+        Ok(stream) => stream,
+        Err(error) => {
+            log::error!("Failed to connect to upstream {}: {}", upstream_ip, error);
+            return false;
+        }
+    };
+    if let Err(error) = request::write_to_stream(&request, &mut upstream_conn).await {
+        log::error!(
+            "Failed to send request to upstream {}: {}",
+            upstream_ip,
+            error
+        );
+        return false;
+    }
+    let response = match response::read_from_stream(&mut upstream_conn, request.method()).await {
+        Ok(response) => response,
+        Err(error) => {
+            log::error!("Error reading response from server: {:?}", error);
+            return false;
+        }
+    };
+    match response.status().as_u16() {
+        status if status == expected_status => true,
+        status => {
+            log::error!(
+                "upstream server {} is not working: {}",
+                upstream_ip,
+                status
+            );
+            false
+        }
+    }
+}
+
+async fn active_health_check(state: &ProxyState) {
+    loop {
         // if this sleep is down the while, cannot pass the test
         sleep(Duration::from_secs(
             state.active_health_check_interval.try_into().unwrap(),
         ))
         .await;
-        let stream_address = &state.upstream_addresses;
-        let mut active_upstream_writer = state.active_upstream.write().await;
-        active_upstream_writer.clear();
-        for upstream_ip in stream_address {
-            let request = http::Request::builder()
-                    .method(http::Method::GET)
-                    .uri(&state.active_health_check_path)
-                    .header("Host", upstream_ip)
-                    .body(Vec::new())
-                    .unwrap();
 
-            match TcpStream::connect(upstream_ip).await{
-                Ok(mut upstream_conn) => {
-                    if let Err(error) = request::write_to_stream(&request, &mut upstream_conn).await{ 
-                        log::error!(
-                            "Failed to send request to upstream {}: {}",
-                            upstream_ip,
-                            error
-                        ); 
-                        return;
-                    }
-                    let response = match response::read_from_stream(&mut upstream_conn, &request.method()).await {
-                        //if OK, update this stream to active stream
-                        Ok(response) => 
-                            response,
-                        Err(error) => {
-                            log::error!("Error reading response from server: {:?}", error);
-                            return;
-                        }
-                    };
-                    match response.status().as_u16(){
-                        200 => {
-                            active_upstream_writer.push(upstream_ip.clone());
-                        }
-                        status @ _ => {
-                            log::error!(
-                                "upstream server {} is not working: {}",
-                                upstream_ip,
-                                status
-                            );
-                            return;
+        // Probe every upstream at once, so that a few slow upstreams don't hold up the others
+        let checks: Vec<_> = (0..state.upstream_addresses.len())
+            .map(|idx| {
+                let upstream_ip = state.upstream_addresses[idx].clone();
+                let path = state.health_paths[idx].clone();
+                let expected_status = state.expected_statuses[idx];
+                let check_timeout = state.health_check_timeout;
+                let tls_connector = state.tls_connector.clone();
+                tokio::spawn(async move {
+                    // An upstream that accepts the connection but never answers is as good as down
+                    match timeout(
+                        check_timeout,
+                        check_upstream(&upstream_ip, &path, expected_status, &tls_connector),
+                    )
+                    .await
+                    {
+                        Ok(healthy) => healthy,
+                        Err(_) => {
+                            log::error!("Health check of upstream {} timed out", upstream_ip);
+                            false
                         }
                     }
-                }
-                Err(_) => {
-                    // delete_unactive_stream(&upstream_ip, state).await;
-                    return;
+                })
+            })
+            .collect();
+        let mut healthy_upstreams = Vec::new();
+        let mut results = HashMap::new();
+        for (upstream_ip, check) in state.upstream_addresses.iter().zip(checks) {
+            let healthy = matches!(check.await, Ok(true));
+            if healthy {
+                healthy_upstreams.push(upstream_ip.clone());
+            }
+            results.insert(upstream_ip.clone(), (healthy, Instant::now()));
+        }
+        *state.last_health_checks.lock().await = results;
+
+        // Upstreams that have just come back start out with a small share of the requests
+        if let Some(window) = state.slow_start {
+            let previously_active = state.active_upstream.read().await.clone();
+            let now = Instant::now();
+            let mut readmitted_at = state.readmitted_at.lock().await;
+            readmitted_at.retain(|_, readmitted| now.duration_since(*readmitted) < window);
+            for upstream_ip in &healthy_upstreams {
+                if !previously_active.contains(upstream_ip) {
+                    log::info!(
+                        "Upstream {} is back, ramping it up over {:?}",
+                        upstream_ip,
+                        window
+                    );
+                    readmitted_at.insert(upstream_ip.clone(), now);
                 }
             }
         }
+
+        // Only hold the lock long enough to swap in the results, so connect_to_upstream isn't
+        // blocked while the checks are running
+        state.metrics.set_upstreams_active(healthy_upstreams.len());
+        let hash_ring = build_hash_ring(&healthy_upstreams);
+        let mut active_upstream = state.active_upstream.write().await;
+        *active_upstream = healthy_upstreams;
+        *state.hash_ring.write().await = hash_ring;
     }
-}
\ No newline at end of file
+}
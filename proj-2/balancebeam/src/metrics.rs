@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::RwLock;
+
+/// Counters and gauges exported on the metrics listener. Everything is updated with atomics, so
+/// recording a metric never waits on the metrics endpoint being scraped (the lock around the
+/// request counters is only taken for writing the first time an upstream/status pair is seen).
+#[derive(Default)]
+pub struct Metrics {
+    /// Responses sent to clients, by the upstream that produced them and status code
+    requests: RwLock<HashMap<(String, u16), AtomicU64>>,
+    /// Requests rejected by the rate limiter
+    rate_limited: AtomicU64,
+    /// Upstreams currently considered alive
+    upstreams_active: AtomicUsize,
+}
+
+impl Metrics {
+    pub fn new(upstreams_active: usize) -> Metrics {
+        Metrics {
+            upstreams_active: AtomicUsize::new(upstreams_active),
+            ..Default::default()
+        }
+    }
+
+    /// Counts a response with `status` sent on behalf of `upstream`.
+    pub fn record_request(&self, upstream: &str, status: http::StatusCode) {
+        let key = (upstream.to_string(), status.as_u16());
+        if let Some(counter) = self.requests.read().unwrap().get(&key) {
+            counter.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.requests
+            .write()
+            .unwrap()
+            .entry(key)
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_upstreams_active(&self, count: usize) {
+        self.upstreams_active.store(count, Ordering::Relaxed);
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out += "# HELP balancebeam_requests_total Responses sent to clients, by upstream and status.\n";
+        out += "# TYPE balancebeam_requests_total counter\n";
+        let requests = self.requests.read().unwrap();
+        let mut keys: Vec<_> = requests.keys().collect();
+        keys.sort();
+        for key in keys {
+            let (upstream, status) = key;
+            writeln!(
+                out,
+                "balancebeam_requests_total{{upstream=\"{}\",status=\"{}\"}} {}",
+                upstream.replace('\\', "\\\\").replace('"', "\\\""),
+                status,
+                requests[key].load(Ordering::Relaxed)
+            )
+            .unwrap();
+        }
+        out += "# HELP balancebeam_rate_limited_total Requests rejected by the rate limiter.\n";
+        out += "# TYPE balancebeam_rate_limited_total counter\n";
+        writeln!(
+            out,
+            "balancebeam_rate_limited_total {}",
+            self.rate_limited.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        out += "# HELP balancebeam_upstreams_active Upstreams currently considered alive.\n";
+        out += "# TYPE balancebeam_upstreams_active gauge\n";
+        writeln!(
+            out,
+            "balancebeam_upstreams_active {}",
+            self.upstreams_active.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        out
+    }
+}
@@ -1,10 +1,7 @@
 use std::cmp::min;
 #[allow(unused)]
 use std::io::{Read, Write};
-use tokio::net::TcpStream;
-use tokio::io::{AsyncReadExt,AsyncWriteExt};
-const MAX_HEADERS_SIZE: usize = 8000;
-const MAX_BODY_SIZE: usize = 10000000;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 const MAX_NUM_HEADERS: usize = 32;
 
 #[derive(Debug)]
@@ -18,7 +15,7 @@ pub enum Error {
     InvalidContentLength,
     /// The Content-Length header does not match the size of the request body that was sent
     ContentLengthMismatch,
-    /// The request body is bigger than MAX_BODY_SIZE
+    /// The request body is bigger than the limit passed to read_from_stream
     RequestBodyTooLarge,
     /// Encountered an I/O error when reading/writing a TcpStream
     ConnectionError(std::io::Error),
@@ -67,6 +64,42 @@ pub fn extend_header_value(
         .insert(name, http::HeaderValue::from_bytes(&new_value).unwrap());
 }
 
+/// Returns whether the client said it can take gzipped responses, i.e. listed gzip (or x-gzip) in
+/// Accept-Encoding without giving it a weight of q=0.
+pub fn accepts_gzip(request: &http::Request<Vec<u8>>) -> bool {
+    request
+        .headers()
+        .get_all("accept-encoding")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().unwrap_or("");
+            let disabled = params.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    == Some(0.0)
+            });
+            (name.eq_ignore_ascii_case("gzip") || name.eq_ignore_ascii_case("x-gzip")) && !disabled
+        })
+}
+
+/// Returns the value of the cookie called `name` sent with the request, if there is one. Cookies
+/// may be spread across several Cookie headers, each holding `name=value` pairs separated by `;`.
+pub fn get_cookie(request: &http::Request<Vec<u8>>, name: &str) -> Option<String> {
+    request
+        .headers()
+        .get_all("cookie")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(cookie_name, _)| *cookie_name == name)
+        .map(|(_, value)| value.to_string())
+}
+
 /// Attempts to parse the data in the supplied buffer as an HTTP request. Returns one of the
 /// following:
 ///
@@ -99,16 +132,26 @@ fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)
 /// This function only reads the request line and headers; the read_body function can subsequently
 /// be called in order to read the request body (for a POST request).
 ///
-/// Returns Ok(http::Request) if a valid request is received, or Error if not.
+/// Returns Ok(http::Request) if a valid request is received, or Error if not. A request line and
+/// headers that don't fit in max_header_bytes are treated as malformed.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+async fn read_headers(
+    stream: &mut (impl AsyncRead + Unpin),
+    max_header_bytes: usize,
+) -> Result<http::Request<Vec<u8>>, Error> {
     // Try reading the headers from the request. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a request, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP request
-    let mut request_buffer = [0_u8; MAX_HEADERS_SIZE];
+    let mut request_buffer = vec![0_u8; max_header_bytes];
     let mut bytes_read = 0;
     loop {
+        // Don't let a client keep sending headers forever
+        if bytes_read == request_buffer.len() {
+            log::debug!("Client sent more than {} bytes of headers", max_header_bytes);
+            return Err(Error::MalformedRequest(httparse::Error::TooManyHeaders));
+        }
+
         // Read bytes from the connection into the buffer, starting at position bytes_read
         let new_bytes = stream
             .read(&mut request_buffer[bytes_read..])
@@ -141,7 +184,7 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>,
 ///
 /// You will need to modify this function in Milestone 2.
 async fn read_body(
-    stream: &mut TcpStream,
+    stream: &mut (impl AsyncRead + Unpin),
     request: &mut http::Request<Vec<u8>>,
     content_length: usize,
 ) -> Result<(), Error> {
@@ -177,18 +220,41 @@ async fn read_body(
     Ok(())
 }
 
+/// Returns whether the client is waiting to hear `100 Continue` before it sends the request body.
+fn expects_continue(request: &http::Request<Vec<u8>>) -> bool {
+    request
+        .headers()
+        .get("expect")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case("100-continue"))
+}
+
 /// This function reads and returns an HTTP request from a stream, returning an Error if the client
-/// closes the connection prematurely or sends an invalid request.
+/// closes the connection prematurely, sends an invalid request (including one whose headers run
+/// past max_header_bytes), or says its body is longer than max_body_size bytes (in which case the
+/// body is left unread). If the client sent
+/// `Expect: 100-continue`, it is told to go ahead with `100 Continue` before the body is read.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+pub async fn read_from_stream(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    max_header_bytes: usize,
+    max_body_size: usize,
+) -> Result<http::Request<Vec<u8>>, Error> {
     // Read headers
-    let mut request = read_headers(stream).await?;
+    let mut request = read_headers(stream, max_header_bytes).await?;
     // Read body if the client supplied the Content-Length header (which it does for POST requests)
     if let Some(content_length) = get_content_length(&request)? {
-        if content_length > MAX_BODY_SIZE {
+        if content_length > max_body_size {
             return Err(Error::RequestBodyTooLarge);
         } else {
+            if request.body().len() < content_length && expects_continue(&request) {
+                stream
+                    .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                    .await
+                    .map_err(Error::ConnectionError)?;
+                stream.flush().await.map_err(Error::ConnectionError)?;
+            }
             read_body(stream, &mut request, content_length).await?;
         }
     }
@@ -200,19 +266,22 @@ pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Ve
 /// You will need to modify this function in Milestone 2.
 pub async fn write_to_stream(
     request: &http::Request<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut (impl AsyncWrite + Unpin),
 ) -> Result<(), std::io::Error> {
-    stream.write(&format_request_line(request).into_bytes()).await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    // The upstream connection may be encrypted, in which case a single write isn't guaranteed to
+    // take everything we give it, and data may sit in a buffer until the stream is flushed
+    stream.write_all(&format_request_line(request).into_bytes()).await?;
+    stream.write_all(&['\r' as u8, '\n' as u8]).await?; // \r\n
     for (header_name, header_value) in request.headers() {
-        stream.write(&format!("{}: ", header_name).as_bytes()).await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+        stream.write_all(&format!("{}: ", header_name).as_bytes()).await?;
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(&['\r' as u8, '\n' as u8]).await?; // \r\n
     }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
+    stream.write_all(&['\r' as u8, '\n' as u8]).await?;
     if request.body().len() > 0 {
-        stream.write(request.body()).await?;
+        stream.write_all(request.body()).await?;
     }
+    stream.flush().await?;
     Ok(())
 }
 
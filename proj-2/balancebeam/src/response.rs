@@ -1,16 +1,23 @@
+use std::collections::HashMap;
 #[allow(unused)]
 use std::io::{Read, Write};
-use tokio::net::TcpStream;
-use tokio::io::{AsyncReadExt,AsyncWriteExt};
+use std::sync::OnceLock;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 const MAX_HEADERS_SIZE: usize = 8000;
 const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
 
+/// Custom error page bodies by status code, loaded from --error-page-dir at startup
+static ERROR_PAGES: OnceLock<HashMap<u16, Vec<u8>>> = OnceLock::new();
+
 #[derive(Debug)]
 pub enum Error {
-    /// Client hung up before sending a complete request
-    IncompleteResponse,
+    /// Upstream hung up before sending a complete response. IncompleteResponse contains the
+    /// number of bytes that were successfully read before it hung up
+    IncompleteResponse(usize),
     /// Client sent an invalid HTTP request. httparse::Error contains more details
     MalformedResponse(httparse::Error),
     /// The Content-Length header is present, but does not contain a valid numeric value
@@ -77,17 +84,30 @@ fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usiz
 
 /// Reads an HTTP response from the provided stream, waiting until a complete set of headers is
 /// sent. This function only reads the response line and headers; the read_body function can
-/// subsequently be called in order to read the response body.
+/// subsequently be called in order to read the response body. `buffered` holds bytes of the
+/// response that were already read out of the stream.
 ///
 /// Returns Ok(http::Response) if a valid response is received, or Error if not.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>, Error> {
+async fn read_headers(
+    stream: &mut (impl AsyncRead + Unpin),
+    buffered: &[u8],
+) -> Result<http::Response<Vec<u8>>, Error> {
     // Try reading the headers from the response. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a response, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP response
     let mut response_buffer = [0_u8; MAX_HEADERS_SIZE];
-    let mut bytes_read = 0;
+    response_buffer[..buffered.len()].copy_from_slice(buffered);
+    let mut bytes_read = buffered.len();
+    if bytes_read > 0 {
+        if let Some((mut response, headers_len)) = parse_response(&response_buffer[..bytes_read])? {
+            response
+                .body_mut()
+                .extend_from_slice(&response_buffer[headers_len..bytes_read]);
+            return Ok(response);
+        }
+    }
     loop {
         // Read bytes from the connection into the buffer, starting at position bytes_read
         let new_bytes = stream
@@ -96,7 +116,7 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>,
             .or_else(|err| Err(Error::ConnectionError(err)))?;
         if new_bytes == 0 {
             // We didn't manage to read a complete response
-            return Err(Error::IncompleteResponse);
+            return Err(Error::IncompleteResponse(bytes_read));
         }
         bytes_read += new_bytes;
 
@@ -117,7 +137,7 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>,
 /// present, it reads that many bytes; otherwise, it reads bytes until the connection is closed.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_body(stream: &mut TcpStream, response: &mut http::Response<Vec<u8>>) -> Result<(), Error> {
+async fn read_body(stream: &mut (impl AsyncRead + Unpin), response: &mut http::Response<Vec<u8>>) -> Result<(), Error> {
     // The response may or may not supply a Content-Length header. If it provides the header, then
     // we want to read that number of bytes; if it does not, we want to keep reading bytes until
     // the connection is closed.
@@ -159,14 +179,25 @@ async fn read_body(stream: &mut TcpStream, response: &mut http::Response<Vec<u8>
 }
 
 /// This function reads and returns an HTTP response from a stream, returning an Error if the server
-/// closes the connection prematurely or sends an invalid response.
+/// closes the connection prematurely or sends an invalid response. Interim 1xx responses (like the
+/// `100 Continue` an upstream sends when the request carried `Expect: 100-continue`) are skipped,
+/// since the client has already been told to continue by the time its request is forwarded; 101
+/// Switching Protocols is final, and is returned.
 ///
 /// You will need to modify this function in Milestone 2.
 pub async fn read_from_stream(
-    stream: &mut TcpStream,
+    stream: &mut (impl AsyncRead + Unpin),
     request_method: &http::Method,
 ) -> Result<http::Response<Vec<u8>>, Error> {
-    let mut response = read_headers(stream).await?;
+    let mut response = read_headers(stream, &[]).await?;
+    while response.status().is_informational()
+        && response.status() != http::StatusCode::SWITCHING_PROTOCOLS
+    {
+        log::debug!("Skipping interim response {}", response.status());
+        // Whatever was read after the interim response belongs to the next one
+        let buffered = std::mem::take(response.body_mut());
+        response = read_headers(stream, &buffered).await?;
+    }
     // A response may have a body as long as it is not responding to a HEAD request and as long as
     // the response status code is not 1xx, 204 (no content), or 304 (not modified).
     if !(request_method == http::Method::HEAD
@@ -184,22 +215,97 @@ pub async fn read_from_stream(
 /// You will need to modify this function in Milestone 2.
 pub async fn write_to_stream(
     response: &http::Response<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut (impl AsyncWrite + Unpin),
 ) -> Result<(), std::io::Error> {
-    stream.write(&format_response_line(response).into_bytes()).await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    // The client connection may be encrypted, in which case a single write isn't guaranteed to
+    // take everything we give it, and data may sit in a buffer until the stream is flushed
+    stream.write_all(&format_response_line(response).into_bytes()).await?;
+    stream.write_all(&['\r' as u8, '\n' as u8]).await?; // \r\n
     for (header_name, header_value) in response.headers() {
-        stream.write(&format!("{}: ", header_name).as_bytes()).await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+        stream.write_all(&format!("{}: ", header_name).as_bytes()).await?;
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(&['\r' as u8, '\n' as u8]).await?; // \r\n
     }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
+    stream.write_all(&['\r' as u8, '\n' as u8]).await?;
     if response.body().len() > 0 {
-        stream.write(response.body()).await?;
+        stream.write_all(response.body()).await?;
     }
+    stream.flush().await?;
     Ok(())
 }
 
+/// Like request::extend_header_value, but for a response: appends to a header value, or adds the
+/// header if it is not already present.
+pub fn extend_header_value(
+    response: &mut http::Response<Vec<u8>>,
+    name: &'static str,
+    extend_value: &str,
+) {
+    let new_value = match response.headers().get(name) {
+        Some(existing_value) => {
+            [existing_value.as_bytes(), b", ", extend_value.as_bytes()].concat()
+        }
+        None => extend_value.as_bytes().to_owned(),
+    };
+    response
+        .headers_mut()
+        .insert(name, http::HeaderValue::from_bytes(&new_value).unwrap());
+}
+
+/// Content types that are already compressed, so gzipping them again would only waste time
+const COMPRESSED_CONTENT_TYPES: &[&str] = &[
+    "image/",
+    "audio/",
+    "video/",
+    "font/woff",
+    "application/gzip",
+    "application/x-gzip",
+    "application/zip",
+    "application/zstd",
+    "application/x-bzip2",
+    "application/x-7z-compressed",
+    "application/x-xz",
+];
+
+/// Gzips the body of the response, if it is at least `min_size` bytes long and isn't already
+/// encoded or of a compressed content type. Content-Encoding and Content-Length are updated to
+/// match.
+pub fn gzip(response: &mut http::Response<Vec<u8>>, min_size: usize) {
+    let content_type = response
+        .headers()
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    if response.body().is_empty()
+        || response.body().len() < min_size
+        || response.status().is_informational()
+        || response.status() == http::StatusCode::PARTIAL_CONTENT
+        || response.headers().contains_key("content-encoding")
+        || COMPRESSED_CONTENT_TYPES
+            .iter()
+            .any(|compressed| content_type.starts_with(compressed))
+    {
+        return;
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let body = match encoder
+        .write_all(response.body())
+        .and_then(|_| encoder.finish())
+    {
+        Ok(body) => body,
+        Err(err) => {
+            log::warn!("Failed to gzip response: {}", err);
+            return;
+        }
+    };
+    let headers = response.headers_mut();
+    headers.insert("content-encoding", http::HeaderValue::from_static("gzip"));
+    headers.insert("content-length", http::HeaderValue::from(body.len()));
+    *response.body_mut() = body;
+    extend_header_value(response, "vary", "Accept-Encoding");
+}
+
 pub fn format_response_line(response: &http::Response<Vec<u8>>) -> String {
     format!(
         "{:?} {} {}",
@@ -209,20 +315,80 @@ pub fn format_response_line(response: &http::Response<Vec<u8>>) -> String {
     )
 }
 
-/// This is a helper function that creates an http::Response containing an HTTP error that can be
-/// sent to a client.
-pub fn make_http_error(status: http::StatusCode) -> http::Response<Vec<u8>> {
-    let body = format!(
-        "HTTP {} {}",
+/// Returns how many bytes write_to_stream sends for this response, headers included.
+pub fn serialized_len(response: &http::Response<Vec<u8>>) -> usize {
+    let headers_len: usize = response
+        .headers()
+        .iter()
+        .map(|(name, value)| name.as_str().len() + 2 + value.len() + 2)
+        .sum();
+    format_response_line(response).len() + 2 + headers_len + 2 + response.body().len()
+}
+
+/// Loads the error pages in `dir`, named by status code (e.g. `502.html`), to be used by
+/// make_http_error instead of the built-in page. Returns how many were loaded.
+pub fn load_error_pages(dir: &str) -> Result<usize, std::io::Error> {
+    let mut pages = HashMap::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let status = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".html"))
+            .and_then(|status| http::StatusCode::from_bytes(status.as_bytes()).ok());
+        if let Some(status) = status {
+            pages.insert(status.as_u16(), std::fs::read(&path)?);
+        }
+    }
+    let count = pages.len();
+    let _ = ERROR_PAGES.set(pages);
+    Ok(count)
+}
+
+/// Returns the body of the error page for `status`: the one loaded from --error-page-dir, if
+/// there is one, or else a minimal built-in page.
+fn error_page(status: http::StatusCode) -> Vec<u8> {
+    if let Some(page) = ERROR_PAGES.get().and_then(|pages| pages.get(&status.as_u16())) {
+        return page.clone();
+    }
+    let title = format!(
+        "{} {}",
         status.as_u16(),
         status.canonical_reason().unwrap_or("")
+    );
+    format!(
+        concat!(
+            "<!DOCTYPE html>\n",
+            "<html>\n",
+            "<head><title>{0}</title></head>\n",
+            "<body><h1>{0}</h1></body>\n",
+            "</html>\n"
+        ),
+        title.trim_end()
     )
-    .into_bytes();
-    http::Response::builder()
+    .into_bytes()
+}
+
+/// This is a helper function that creates an http::Response containing an HTTP error that can be
+/// sent to a client.
+pub fn make_http_error(status: http::StatusCode) -> http::Response<Vec<u8>> {
+    make_http_error_with_headers(status, &[])
+}
+
+/// Like make_http_error, but also sets the given extra headers on the response (e.g. Retry-After
+/// on a 429).
+pub fn make_http_error_with_headers(
+    status: http::StatusCode,
+    headers: &[(&str, String)],
+) -> http::Response<Vec<u8>> {
+    let body = error_page(status);
+    let mut response = http::Response::builder()
         .status(status)
-        .header("Content-Type", "text/plain")
+        .header("Content-Type", "text/html; charset=utf-8")
         .header("Content-Length", body.len().to_string())
-        .version(http::Version::HTTP_11)
-        .body(body)
-        .unwrap()
+        .version(http::Version::HTTP_11);
+    for (name, value) in headers {
+        response = response.header(*name, value);
+    }
+    response.body(body).unwrap()
 }
@@ -1,7 +1,12 @@
 mod common;
 
 use common::{init_logging, BalanceBeam, EchoServer, Server};
+use flate2::read::GzDecoder;
+use std::io::Read;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UnixListener};
 use std::sync::Arc;
+use std::time::Duration;
 
 async fn setup() -> (BalanceBeam, EchoServer) {
     init_logging();
@@ -99,3 +104,1045 @@ async fn test_multiple_requests_per_connection() {
 
     log::info!("All done :)");
 }
+
+/// Stop balancebeam while a request is in flight, and make sure the request still completes before
+/// balancebeam exits
+#[tokio::test]
+async fn test_graceful_shutdown() {
+    init_logging();
+    let upstream = EchoServer::new_with_delay(Duration::from_secs(2)).await;
+    let mut balancebeam = BalanceBeam::new(&[&upstream.address], Some(60), None).await;
+
+    log::info!("Sending a request that the upstream will take a while to answer");
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/slow", balancebeam.address);
+    let in_flight = tokio::spawn(async move { client.get(url).send().await?.text().await });
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    log::info!("Telling balancebeam to shut down");
+    assert!(
+        balancebeam.terminate(Duration::from_secs(10)).await,
+        "balancebeam did not exit after the in-flight request finished"
+    );
+
+    log::info!("Checking that the in-flight request was answered");
+    let response_text = in_flight
+        .await
+        .unwrap()
+        .expect("In-flight request failed during shutdown");
+    assert!(response_text.contains("GET /slow HTTP/1.1"));
+
+    log::info!("Checking that balancebeam stopped accepting connections");
+    assert!(balancebeam.get("/after-shutdown").await.is_err());
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Load the upstream and rate limit from a config file, and make sure a flag given on the command
+/// line takes priority over the file
+#[tokio::test]
+async fn test_config_file() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let config_path = std::env::temp_dir().join(format!(
+        "balancebeam-test-{}.toml",
+        upstream.address.replace([':', '.'], "-")
+    ));
+    std::fs::write(
+        &config_path,
+        format!(
+            "max-requests-per-minute = 1\n\n[[upstream]]\naddress = \"{}\"\n",
+            upstream.address
+        ),
+    )
+    .expect("Could not write config file");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        Some(60),
+        Some(3),
+        &["--config", config_path.to_str().unwrap()],
+    )
+    .await;
+
+    log::info!("Sending requests up to the rate limit given on the command line");
+    let client = reqwest::Client::new();
+    for i in 0..4 {
+        let response = client
+            .get(format!("http://{}/request-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        let expected_status = if i < 3 { 200 } else { 429 };
+        assert_eq!(response.status().as_u16(), expected_status);
+    }
+
+    std::fs::remove_file(&config_path).unwrap();
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 3);
+    log::info!("All done :)");
+}
+
+/// Serve HTTPS using a self-signed certificate, and make sure requests still make it through to the
+/// (plain HTTP) upstream
+#[tokio::test]
+async fn test_tls_termination() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let data_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data");
+    let cert_path = format!("{}/tls-cert.pem", data_dir);
+    let key_path = format!("{}/tls-key.pem", data_dir);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        Some(60),
+        None,
+        &["--tls-cert", &cert_path, "--tls-key", &key_path],
+    )
+    .await;
+
+    log::info!("Sending a couple of requests over HTTPS");
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    for path in ["/first_url", "/second_url"] {
+        let response_text = client
+            .get(format!("https://{}{}", balancebeam.address, path))
+            .header("x-sent-by", "balancebeam-tests")
+            .send()
+            .await
+            .expect("Error sending HTTPS request to balancebeam")
+            .text()
+            .await
+            .unwrap();
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+        assert!(response_text.contains("x-forwarded-proto: https"));
+    }
+
+    log::info!("Checking that plain HTTP is no longer accepted");
+    assert!(balancebeam.get("/plain").await.is_err());
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 2);
+    log::info!("All done :)");
+}
+
+/// Forward to an upstream that only speaks HTTPS. The HTTPS upstream is itself a balancebeam doing
+/// TLS termination in front of an echo server, with a self-signed certificate.
+#[tokio::test]
+async fn test_https_upstream() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let data_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data");
+    let cert_path = format!("{}/tls-cert.pem", data_dir);
+    let key_path = format!("{}/tls-key.pem", data_dir);
+    let https_upstream = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        Some(60),
+        None,
+        &["--tls-cert", &cert_path, "--tls-key", &key_path],
+    )
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&format!("https://{}", https_upstream.address)],
+        Some(60),
+        None,
+        &["--upstream-insecure"],
+    )
+    .await;
+
+    log::info!("Sending a couple of requests over one connection, to go through both hops");
+    let client = reqwest::Client::new();
+    for path in ["/first_url", "/second_url"] {
+        let response_text = client
+            .get(format!("http://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .unwrap();
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 2);
+    log::info!("All done :)");
+}
+
+/// Send a request to an upstream that takes longer than --upstream-timeout to answer, and make sure
+/// the client gets a 504 instead of waiting forever
+#[tokio::test]
+async fn test_upstream_timeout() {
+    init_logging();
+    let upstream = EchoServer::new_with_delay(Duration::from_secs(5)).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        Some(60),
+        None,
+        &["--upstream-timeout", "1"],
+    )
+    .await;
+    let client = reqwest::Client::new();
+
+    log::info!("Sending a request to the slow upstream");
+    let start = std::time::Instant::now();
+    let response = client
+        .get(format!("http://{}/slow", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 504);
+    assert!(start.elapsed() < Duration::from_secs(4));
+
+    log::info!("Checking that the slow upstream was marked down");
+    // balancebeam closes the connection after a 504, so don't let the client try to reuse it
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/after-timeout", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// With --max-connections 1, hold the only connection open and make sure other clients are either
+/// rejected or kept waiting until it closes, depending on --connection-overflow
+#[tokio::test]
+async fn test_max_connections() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let rejecting = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        Some(60),
+        None,
+        &["--max-connections", "1", "--connection-overflow", "reject"],
+    )
+    .await;
+    let queueing = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        Some(60),
+        None,
+        &["--max-connections", "1", "--connection-overflow", "queue"],
+    )
+    .await;
+
+    log::info!("Filling up the rejecting balancebeam, then sending a request to it");
+    let held = tokio::net::TcpStream::connect(&rejecting.address).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/rejected", rejecting.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 503);
+    drop(held);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(rejecting.get("/accepted").await.is_ok());
+
+    log::info!("Filling up the queueing balancebeam, then sending a request to it");
+    let held = tokio::net::TcpStream::connect(&queueing.address).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let url = format!("http://{}/queued", queueing.address);
+    let queued = tokio::spawn(async move { reqwest::get(url).await?.text().await });
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(!queued.is_finished(), "Request should wait for the held connection to close");
+    drop(held);
+    let response_text = tokio::time::timeout(Duration::from_secs(5), queued)
+        .await
+        .expect("Queued request was never handled")
+        .unwrap()
+        .expect("Error sending queued request to balancebeam");
+    assert!(response_text.contains("GET /queued HTTP/1.1"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Send a couple of requests with --access-log set, and check that each one gets a line in Common
+/// Log Format
+#[tokio::test]
+async fn test_access_log() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let log_path = std::env::temp_dir().join(format!(
+        "balancebeam-test-{}.log",
+        upstream.address.replace([':', '.'], "-")
+    ));
+    let _ = std::fs::remove_file(&log_path);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        Some(60),
+        None,
+        &["--access-log", log_path.to_str().unwrap()],
+    )
+    .await;
+
+    for path in ["/first_url", "/second_url"] {
+        balancebeam
+            .get(path)
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    log::info!("Waiting for the access log to be flushed");
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let contents = std::fs::read_to_string(&log_path).expect("Access log was not written");
+    let lines: Vec<&str> = contents.lines().collect();
+    assert_eq!(lines.len(), 2);
+    for (line, path) in lines.iter().zip(["/first_url", "/second_url"]) {
+        assert!(line.starts_with("127.0.0.1 - - ["));
+        assert!(line.contains(&format!("] \"GET {} HTTP/1.1\" 200 ", path)));
+        let bytes: usize = line.rsplit(' ').next().unwrap().parse().unwrap();
+        assert!(bytes > 0);
+    }
+
+    std::fs::remove_file(&log_path).unwrap();
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Send a few requests with --metrics-bind set, and check that they show up on /metrics and that
+/// /upstreams reports the upstream as alive
+#[tokio::test]
+async fn test_metrics() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let metrics_address = {
+        // Ask the OS for a free port to serve metrics on
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    };
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        Some(60),
+        None,
+        &["--metrics-bind", &metrics_address],
+    )
+    .await;
+
+    for path in ["/first_url", "/second_url"] {
+        balancebeam
+            .get(path)
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let metrics = reqwest::get(format!("http://{}/metrics", metrics_address))
+        .await
+        .expect("Error fetching metrics")
+        .text()
+        .await
+        .unwrap();
+    log::info!("Metrics:\n{}", metrics);
+    assert!(metrics.contains(&format!(
+        "balancebeam_requests_total{{upstream=\"{}\",status=\"200\"}} 2",
+        upstream.address
+    )));
+    assert!(metrics.contains("balancebeam_rate_limited_total 0"));
+    assert!(metrics.contains("balancebeam_upstreams_active 1"));
+
+    log::info!("Checking the upstream status on /upstreams");
+    let status = reqwest::get(format!("http://{}/upstreams", metrics_address))
+        .await
+        .expect("Error fetching upstream status")
+        .text()
+        .await
+        .unwrap();
+    log::info!("Upstream status: {}", status);
+    assert!(status.contains(&format!("\"address\":\"{}\"", upstream.address)));
+    assert!(status.contains("\"active\":true"));
+    assert!(status.contains("\"in_flight\":0"));
+    assert!(status.contains("\"last_check\":null"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure the upstream is told the scheme and host the client used, and that any X-Forwarded-*
+/// values set by proxies in front of balancebeam are kept
+#[tokio::test]
+async fn test_forwarded_headers() {
+    let (balancebeam, upstream) = setup().await;
+    let client = reqwest::Client::new();
+
+    log::info!("Sending a request straight to balancebeam");
+    let response_text = client
+        .get(format!("http://{}/direct", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .unwrap();
+    assert!(response_text.contains("x-forwarded-proto: http\n"));
+    assert!(response_text.contains(&format!("x-forwarded-host: {}\n", balancebeam.address)));
+
+    log::info!("Sending a request that has already been through another proxy");
+    let response_text = client
+        .get(format!("http://{}/proxied", balancebeam.address))
+        .header("x-forwarded-for", "10.0.0.1")
+        .header("x-forwarded-proto", "https")
+        .header("x-forwarded-host", "example.com")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .unwrap();
+    assert!(response_text.contains("x-forwarded-for: 10.0.0.1, 127.0.0.1\n"));
+    assert!(response_text.contains("x-forwarded-proto: https, http\n"));
+    assert!(response_text.contains(&format!(
+        "x-forwarded-host: example.com, {}\n",
+        balancebeam.address
+    )));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// By default the upstream should see the Host the client sent; with --preserve-host false it should
+/// see its own address instead
+#[tokio::test]
+async fn test_preserve_host() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let preserving = BalanceBeam::new(&[&upstream.address], Some(60), None).await;
+    let rewriting = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        Some(60),
+        None,
+        &["--preserve-host", "false"],
+    )
+    .await;
+
+    let response_text = preserving
+        .get("/preserved")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains(&format!("host: {}\n", preserving.address)));
+
+    let response_text = rewriting
+        .get("/rewritten")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains(&format!("host: {}\n", upstream.address)));
+    assert!(response_text.contains(&format!("x-forwarded-host: {}\n", rewriting.address)));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure balancebeam adds itself to the Via header of both the forwarded request and the
+/// response, after any proxies the message already went through
+#[tokio::test]
+async fn test_via_header() {
+    let (balancebeam, upstream) = setup().await;
+
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/via", balancebeam.address))
+        .header("via", "1.1 frontend")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let via = response
+        .headers()
+        .get("via")
+        .expect("Response should have a Via header")
+        .to_str()
+        .unwrap()
+        .to_string();
+    log::info!("Response Via: {}", via);
+    assert!(via.starts_with("1.1 balancebeam"));
+    let response_text = response.text().await.unwrap();
+    assert!(response_text.contains(&format!("via: 1.1 frontend, {}\n", via)));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure each request gets an X-Request-ID that both the upstream and the client see, and that
+/// an ID the client already sent is kept
+#[tokio::test]
+async fn test_request_id() {
+    let (balancebeam, upstream) = setup().await;
+    let client = reqwest::Client::new();
+
+    log::info!("Sending a request without an ID");
+    let response = client
+        .get(format!("http://{}/generated", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let request_id = response
+        .headers()
+        .get("x-request-id")
+        .expect("Response should have an X-Request-ID header")
+        .to_str()
+        .unwrap()
+        .to_string();
+    log::info!("Request was given ID {}", request_id);
+    assert!(!request_id.is_empty());
+    assert!(request_id.chars().all(|c| c.is_ascii_hexdigit()));
+    let response_text = response.text().await.unwrap();
+    assert!(response_text.contains(&format!("x-request-id: {}\n", request_id)));
+
+    log::info!("Sending a request with an ID");
+    let response = client
+        .get(format!("http://{}/provided", balancebeam.address))
+        .header("x-request-id", "my-request-1")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.headers()["x-request-id"], "my-request-1");
+    let response_text = response.text().await.unwrap();
+    assert!(response_text.contains("x-request-id: my-request-1\n"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure responses the upstream allows to be cached are served from the cache, and others are
+/// not
+#[tokio::test]
+async fn test_response_cache() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], None, None, &["--cache-size", "10"])
+            .await;
+    let client = reqwest::Client::new();
+
+    log::info!("Requesting a cacheable response twice");
+    let mut bodies = Vec::new();
+    for i in 0..2 {
+        let response = client
+            .get(format!("http://{}/cached", balancebeam.address))
+            .header("x-echo-cache-control", "public, max-age=60")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.headers().contains_key("age"), i == 1);
+        bodies.push(response.text().await.unwrap());
+    }
+    assert_eq!(bodies[0], bodies[1]);
+
+    log::info!("Requesting a no-store response twice");
+    for _ in 0..2 {
+        let response = client
+            .get(format!("http://{}/uncached", balancebeam.address))
+            .header("x-echo-cache-control", "no-store")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(!response.headers().contains_key("age"));
+    }
+
+    log::info!("Sending a POST, which is never cached");
+    for _ in 0..2 {
+        client
+            .post(format!("http://{}/cached", balancebeam.address))
+            .header("x-echo-cache-control", "max-age=60")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(
+        num_requests_received, 5,
+        "Upstream should only have received the requests that weren't answered from the cache"
+    );
+    log::info!("All done :)");
+}
+
+/// Make sure large responses are gzipped for clients that accept it, and come out the same after
+/// decompressing
+#[tokio::test]
+async fn test_gzip_responses() {
+    let (balancebeam, upstream) = setup().await;
+    let client = reqwest::Client::new();
+    let body = "balancebeam ".repeat(1000);
+
+    log::info!("Sending a request that accepts gzip");
+    let response = client
+        .post(format!("http://{}/compressed", balancebeam.address))
+        .header("accept-encoding", "br;q=1.0, gzip;q=0.8")
+        .body(body.clone())
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.headers()["content-encoding"], "gzip");
+    let compressed = response.bytes().await.unwrap();
+    assert!(compressed.len() < body.len());
+    let mut decompressed = String::new();
+    GzDecoder::new(&compressed[..])
+        .read_to_string(&mut decompressed)
+        .expect("Response should be valid gzip");
+    assert!(decompressed.starts_with("POST /compressed HTTP/1.1\n"));
+    assert!(decompressed.ends_with(&format!("\n\n{}", body)));
+
+    log::info!("Sending requests that don't accept gzip");
+    for accept_encoding in [None, Some("gzip;q=0, identity")] {
+        let mut request = client
+            .post(format!("http://{}/uncompressed", balancebeam.address))
+            .body(body.clone());
+        if let Some(accept_encoding) = accept_encoding {
+            request = request.header("accept-encoding", accept_encoding);
+        }
+        let response = request.send().await.expect("Error sending request to balancebeam");
+        assert!(!response.headers().contains_key("content-encoding"));
+        assert!(response.text().await.unwrap().ends_with(&body));
+    }
+
+    log::info!("Sending a small request that accepts gzip");
+    let response = client
+        .get(format!("http://{}/small", balancebeam.address))
+        .header("accept-encoding", "gzip")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(!response.headers().contains_key("content-encoding"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure an error page from --error-page-dir is served for its status code
+#[tokio::test]
+async fn test_error_pages() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let page_dir = std::env::temp_dir().join(format!(
+        "balancebeam-test-pages-{}",
+        upstream.address.replace([':', '.'], "-")
+    ));
+    std::fs::create_dir_all(&page_dir).expect("Could not create error page directory");
+    let page = "<html><body>Slow down, please</body></html>\n";
+    std::fs::write(page_dir.join("429.html"), page).expect("Could not write error page");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        Some(60),
+        Some(1),
+        &["--error-page-dir", page_dir.to_str().unwrap()],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    for i in 0..2 {
+        let response = client
+            .get(format!("http://{}/request-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        if i == 1 {
+            assert_eq!(response.status().as_u16(), 429);
+            assert_eq!(
+                response.headers()["content-type"],
+                "text/html; charset=utf-8"
+            );
+            assert_eq!(response.text().await.unwrap(), page);
+        }
+    }
+
+    std::fs::remove_dir_all(&page_dir).unwrap();
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Reads the request/status line and headers of an HTTP message, up to the blank line after them.
+async fn read_head(stream: &mut (impl AsyncRead + Unpin)) -> String {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0_u8];
+        stream.read_exact(&mut byte).await.unwrap();
+        head.push(byte[0]);
+    }
+    String::from_utf8(head).unwrap()
+}
+
+/// Make sure a connection is handed over to the upstream after it accepts a WebSocket upgrade, so
+/// that whatever is sent afterwards passes through untouched
+#[tokio::test]
+async fn test_websocket_upgrade() {
+    init_logging();
+    // A bare-bones upstream that accepts the upgrade, then echoes back whatever it receives
+    let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = upstream_listener.local_addr().unwrap().to_string();
+    let upstream_task = tokio::spawn(async move {
+        let (mut stream, _) = upstream_listener.accept().await.unwrap();
+        let request = read_head(&mut stream).await;
+        assert!(request.contains("upgrade: websocket\r\n"));
+        stream
+            .write_all(
+                b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut buffer = [0_u8; 512];
+        loop {
+            let n = stream.read(&mut buffer).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            stream.write_all(&buffer[..n]).await.unwrap();
+        }
+    });
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    log::info!("Asking for an upgrade");
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client
+        .write_all(
+            format!(
+                "GET /socket HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n",
+                balancebeam.address
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    let response = read_head(&mut client).await;
+    log::info!("Upgrade response: {}", response);
+    assert!(response.starts_with("HTTP/1.1 101"));
+
+    log::info!("Sending data through the upgraded connection");
+    for message in ["hello", "GET / HTTP/1.1\r\n\r\n"] {
+        client.write_all(message.as_bytes()).await.unwrap();
+        let mut echoed = vec![0_u8; message.len()];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(echoed, message.as_bytes());
+    }
+
+    drop(client);
+    upstream_task.await.unwrap();
+    log::info!("All done :)");
+}
+
+/// Make sure balancebeam doesn't send another request down an upstream connection after the
+/// upstream said it would close it
+#[tokio::test]
+async fn test_upstream_connection_close() {
+    init_logging();
+    // An upstream that answers one request per connection with Connection: close, but leaves the
+    // connection open afterwards
+    let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = upstream_listener.local_addr().unwrap().to_string();
+    let upstream_task = tokio::spawn(async move {
+        let mut connections = Vec::new();
+        for _ in 0..2 {
+            let (mut stream, _) = upstream_listener.accept().await.unwrap();
+            read_head(&mut stream).await;
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok")
+                .await
+                .unwrap();
+            connections.push(stream);
+        }
+        connections.len()
+    });
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream_address], None, None, &["--upstream-timeout", "5"])
+            .await;
+
+    log::info!("Sending two requests");
+    let client = reqwest::Client::new();
+    for i in 0..2 {
+        let response = client
+            .get(format!("http://{}/request-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.text().await.unwrap(), "ok");
+    }
+
+    let num_connections = upstream_task.await.unwrap();
+    assert_eq!(num_connections, 2, "Each request should get its own upstream connection");
+    log::info!("All done :)");
+}
+
+/// Make sure requests with a body over --max-request-body-bytes are turned away without reaching
+/// the upstream
+#[tokio::test]
+async fn test_max_request_body_bytes() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--max-request-body-bytes", "16"],
+    )
+    .await;
+
+    log::info!("Sending a request with a small body");
+    let response_text = balancebeam
+        .post("/small", "just a few bytes")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("just a few bytes"));
+
+    log::info!("Sending a request with a body over the limit");
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(b"POST /big HTTP/1.1\r\nHost: example.com\r\nContent-Length: 17\r\n\r\n")
+        .await
+        .unwrap();
+    let head = read_head(&mut stream).await;
+    log::info!("Response head: {:?}", head);
+    assert!(head.starts_with("HTTP/1.1 413"), "Expected a 413 response, got {:?}", head);
+    assert!(head.to_ascii_lowercase().contains("connection: close\r\n"));
+
+    let num_requests = Box::new(upstream).stop().await;
+    assert_eq!(num_requests, 1, "The oversized request shouldn't reach the upstream");
+    log::info!("All done :)");
+}
+
+/// Make sure a client that sends Expect: 100-continue is told to go ahead with its body, and that
+/// an upstream's own 100 Continue isn't mistaken for its response
+#[tokio::test]
+async fn test_expect_continue() {
+    init_logging();
+    // An upstream that answers with 100 Continue before its real response
+    let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = upstream_listener.local_addr().unwrap().to_string();
+    let upstream_task = tokio::spawn(async move {
+        let (mut stream, _) = upstream_listener.accept().await.unwrap();
+        let head = read_head(&mut stream).await;
+        let mut body = [0_u8; 5];
+        stream.read_exact(&mut body).await.unwrap();
+        stream
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+            .await
+            .unwrap();
+        (head, body)
+    });
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(
+            b"POST /upload HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\n\
+            Expect: 100-continue\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let head = read_head(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 100 Continue\r\n"), "Expected 100 Continue, got {:?}", head);
+
+    log::info!("Sending the body");
+    stream.write_all(b"hello").await.unwrap();
+    let head = read_head(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "Expected 200 OK, got {:?}", head);
+    let mut body = [0_u8; 2];
+    stream.read_exact(&mut body).await.unwrap();
+    assert_eq!(&body, b"ok");
+
+    let (upstream_head, upstream_body) = upstream_task.await.unwrap();
+    assert!(upstream_head.to_ascii_lowercase().contains("expect: 100-continue\r\n"));
+    assert_eq!(&upstream_body, b"hello");
+    log::info!("All done :)");
+}
+
+/// Make sure a client can't keep sending headers past --max-header-bytes
+#[tokio::test]
+async fn test_max_header_bytes() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], None, None, &["--max-header-bytes", "512"])
+            .await;
+
+    log::info!("Sending a request with a few headers");
+    let response_text = balancebeam
+        .get("/small")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.starts_with("GET /small "));
+
+    log::info!("Sending a request with too many headers");
+    // Fill the limit exactly without finishing the headers, so that balancebeam has read
+    // everything sent when it answers
+    let mut head = b"GET /big HTTP/1.1\r\nHost: example.com\r\n".to_vec();
+    let mut i = 0;
+    while head.len() < 512 {
+        head.extend_from_slice(format!("X-Padding-{}: {}\r\n", i, "x".repeat(40)).as_bytes());
+        i += 1;
+    }
+    head.truncate(512);
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream.write_all(&head).await.unwrap();
+    let head = read_head(&mut stream).await;
+    log::info!("Response head: {:?}", head);
+    assert!(head.starts_with("HTTP/1.1 400"), "Expected a 400 response, got {:?}", head);
+    assert!(head.to_ascii_lowercase().contains("connection: close\r\n"));
+
+    let num_requests = Box::new(upstream).stop().await;
+    assert_eq!(num_requests, 1, "The oversized request shouldn't reach the upstream");
+    log::info!("All done :)");
+}
+
+/// Make sure requests and active health checks reach an upstream listening on a Unix socket
+#[tokio::test]
+async fn test_unix_socket_upstream() {
+    init_logging();
+    let socket_path =
+        std::env::temp_dir().join(format!("balancebeam-test-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&socket_path);
+    // An upstream that answers each connection's request with its request line
+    let upstream_listener = UnixListener::bind(&socket_path).unwrap();
+    let upstream_task = tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = upstream_listener.accept().await.unwrap();
+            let head = read_head(&mut stream).await;
+            let request_line = head.lines().next().unwrap().to_string();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                request_line.len(),
+                request_line
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    });
+    let upstream_address = format!("unix:{}", socket_path.display());
+    let metrics_address = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    };
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        Some(1),
+        None,
+        &["--metrics-bind", &metrics_address],
+    )
+    .await;
+
+    let response_text = balancebeam
+        .get("/over-a-socket")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response_text, "GET /over-a-socket HTTP/1.1");
+
+    log::info!("Waiting for a health check");
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    let status = reqwest::get(format!("http://{}/upstreams", metrics_address))
+        .await
+        .expect("Error fetching upstream status")
+        .text()
+        .await
+        .unwrap();
+    log::info!("Upstream status: {}", status);
+    assert!(status.contains("\"healthy\":true"));
+
+    upstream_task.abort();
+    let _ = std::fs::remove_file(&socket_path);
+    log::info!("All done :)");
+}
+
+/// Make sure balancebeam listens on every --bind address, skipping ones it can't bind to
+#[tokio::test]
+async fn test_multiple_bind_addresses() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let second_address = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    };
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        // 192.0.2.0/24 is reserved for documentation, so it isn't a local address
+        &["--bind", &second_address, "--bind", "192.0.2.1:1100"],
+    )
+    .await;
+
+    for address in [&balancebeam.address, &second_address] {
+        log::info!("Sending a request to {}", address);
+        let response_text = reqwest::get(format!("http://{}/hello", address))
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .unwrap();
+        assert!(response_text.starts_with("GET /hello "));
+    }
+
+    let num_requests = Box::new(upstream).stop().await;
+    assert_eq!(num_requests, 2);
+    log::info!("All done :)");
+}
+
+/// Make sure clients connecting over IPv4 and IPv6 to a dual-stack listener are both reported
+/// with their usual address in X-Forwarded-For
+#[tokio::test]
+async fn test_ipv6_clients() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let port = {
+        let listener = std::net::TcpListener::bind("[::]:0").unwrap();
+        listener.local_addr().unwrap().port()
+    };
+    let dual_stack_address = format!("[::]:{}", port);
+    // Kept alive (but not used directly) so that balancebeam keeps running
+    let _balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--bind", &dual_stack_address],
+    )
+    .await;
+
+    for (host, client_ip) in [("127.0.0.1", "127.0.0.1"), ("[::1]", "::1")] {
+        let address = format!("{}:{}", host, port);
+        log::info!("Sending a request to {}", address);
+        let response_text = reqwest::get(format!("http://{}/hello", address))
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .unwrap();
+        assert!(response_text.contains(&format!("x-forwarded-for: {}\n", client_ip)));
+        assert!(response_text.contains(&format!("x-forwarded-host: {}\n", address)));
+    }
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure a connection that never sends a request is closed after --client-idle-timeout
+#[tokio::test]
+async fn test_client_idle_timeout() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--client-idle-timeout", "1"],
+    )
+    .await;
+
+    log::info!("Opening a connection and sending nothing");
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let mut buf = [0_u8; 1];
+    let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+        .await
+        .expect("balancebeam didn't close the idle connection");
+    assert_eq!(read.unwrap(), 0, "Expected the connection to be closed");
+
+    log::info!("Checking that requests still work");
+    let response_text = balancebeam
+        .get("/hello")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.starts_with("GET /hello "));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
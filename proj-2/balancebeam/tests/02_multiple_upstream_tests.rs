@@ -1,14 +1,33 @@
 mod common;
 
-use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
+use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, HangupServer, Server};
 
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
 use tokio::time::sleep;
 
 async fn setup_with_params(
     n_upstreams: usize,
     active_health_check_interval: Option<usize>,
     max_requests_per_minute: Option<usize>,
+) -> (BalanceBeam, Vec<Box<dyn Server>>) {
+    setup_with_args(
+        n_upstreams,
+        active_health_check_interval,
+        max_requests_per_minute,
+        &[],
+    )
+    .await
+}
+
+async fn setup_with_args(
+    n_upstreams: usize,
+    active_health_check_interval: Option<usize>,
+    max_requests_per_minute: Option<usize>,
+    extra_args: &[&str],
 ) -> (BalanceBeam, Vec<Box<dyn Server>>) {
     init_logging();
     let mut upstreams: Vec<Box<dyn Server>> = Vec::new();
@@ -23,10 +42,11 @@ async fn setup_with_params(
         .iter()
         .map(|addr| addr.as_str())
         .collect();
-    let balancebeam = BalanceBeam::new(
+    let balancebeam = BalanceBeam::new_with_args(
         &upstream_addresses,
         active_health_check_interval,
         max_requests_per_minute,
+        extra_args,
     )
     .await;
     (balancebeam, upstreams)
@@ -80,6 +100,209 @@ async fn test_load_distribution() {
     log::info!("All done :)");
 }
 
+/// With the round-robin strategy, every upstream should receive exactly the same number of requests
+#[tokio::test]
+async fn test_round_robin_distribution() {
+    let n_upstreams = 3;
+    let requests_per_upstream = 5;
+    // Use a long health check interval so that health check requests don't skew the counts
+    let (balancebeam, mut upstreams) =
+        setup_with_args(n_upstreams, Some(60), None, &["--strategy", "round-robin"]).await;
+
+    for i in 0..n_upstreams * requests_per_upstream {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    while let Some(upstream) = upstreams.pop() {
+        assert_eq!(
+            upstream.stop().await,
+            requests_per_upstream,
+            "Round-robin did not hit each upstream the same number of times"
+        );
+    }
+
+    log::info!("All done :)");
+}
+
+/// With sticky sessions enabled, a client that sends back the cookie it was given should keep
+/// landing on the same upstream
+#[tokio::test]
+async fn test_sticky_sessions() {
+    let n_upstreams = 3;
+    let n_requests = 10;
+    let (balancebeam, mut upstreams) =
+        setup_with_args(n_upstreams, Some(60), None, &["--sticky-cookie", "bb-upstream"]).await;
+
+    log::info!("Sending a request without a cookie; balancebeam should hand one out");
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/first", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let cookie = response
+        .headers()
+        .get("set-cookie")
+        .expect("balancebeam did not set a sticky session cookie")
+        .to_str()
+        .unwrap()
+        .split(';')
+        .next()
+        .unwrap()
+        .to_string();
+    assert!(cookie.starts_with("bb-upstream="));
+
+    log::info!("Sending more requests (on new connections) with cookie {}", cookie);
+    for i in 0..n_requests {
+        let response = reqwest::Client::new()
+            .get(format!("http://{}/request-{}", balancebeam.address, i))
+            .header("cookie", &cookie)
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(
+            response.headers().get("set-cookie").is_none(),
+            "balancebeam replaced a valid sticky session cookie"
+        );
+    }
+
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.push(upstream.stop().await);
+    }
+    log::info!(
+        "Number of requests received by each upstream: {:?}",
+        request_counters
+    );
+    assert!(
+        request_counters.contains(&(n_requests + 1)),
+        "Requests carrying the same cookie were not all sent to the same upstream"
+    );
+
+    log::info!("All done :)");
+}
+
+/// With IP hashing, all requests from the same client IP should go to the same upstream
+#[tokio::test]
+async fn test_ip_hash_consistency() {
+    let n_upstreams = 3;
+    let n_requests = 10;
+    let (balancebeam, mut upstreams) =
+        setup_with_args(n_upstreams, Some(60), None, &["--strategy", "ip-hash"]).await;
+
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.push(upstream.stop().await);
+    }
+    log::info!(
+        "Number of requests received by each upstream: {:?}",
+        request_counters
+    );
+    assert!(
+        request_counters.contains(&n_requests),
+        "Requests from the same client IP were spread across several upstreams"
+    );
+
+    log::info!("All done :)");
+}
+
+/// With the least-connections strategy, concurrent connections should be spread across all of the
+/// upstream servers rather than piling onto one of them
+#[tokio::test]
+async fn test_least_connections_distribution() {
+    let n_upstreams = 3;
+    let n_connections = 30;
+    let (balancebeam, mut upstreams) =
+        setup_with_args(n_upstreams, None, None, &["--strategy", "least-connections"]).await;
+    let balancebeam = std::sync::Arc::new(balancebeam);
+
+    let mut tasks = Vec::new();
+    for i in 0..n_connections {
+        let balancebeam = balancebeam.clone();
+        tasks.push(tokio::task::spawn(async move {
+            let path = format!("/request-{}", i);
+            let response_text = balancebeam
+                .get(&path)
+                .await
+                .expect("Error sending request to balancebeam");
+            assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        }));
+    }
+    for task in tasks {
+        task.await.expect("Task panicked");
+    }
+
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.insert(0, upstream.stop().await);
+    }
+    log::info!(
+        "Number of requests received by each upstream: {:?}",
+        request_counters
+    );
+    for upstream_req_count in request_counters {
+        assert!(
+            upstream_req_count > 0,
+            "An upstream received no requests under least-connections balancing"
+        );
+    }
+
+    log::info!("All done :)");
+}
+
+/// Give one upstream three times the weight of the other, and make sure it receives roughly three
+/// times as many requests
+#[tokio::test]
+async fn test_weighted_load_distribution() {
+    init_logging();
+    let n_requests = 120;
+    let light = EchoServer::new().await;
+    let heavy = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(
+        &[&light.address, &format!("{}=3", heavy.address)],
+        None,
+        None,
+    )
+    .await;
+
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let light_count = Box::new(light).stop().await;
+    let heavy_count = Box::new(heavy).stop().await;
+    log::info!(
+        "Light upstream received {} requests, heavy upstream received {}",
+        light_count,
+        heavy_count
+    );
+    let expected_heavy = (light_count + heavy_count) as f64 * 0.75;
+    assert!(
+        (heavy_count as f64 - expected_heavy).abs() < 0.2 * expected_heavy,
+        "Upstream weights don't seem to be honored"
+    );
+
+    log::info!("All done :)");
+}
+
 async fn try_failover(balancebeam: &BalanceBeam, upstreams: &mut Vec<Box<dyn Server>>) {
     // Send some initial requests. Everything should work
     log::info!("Sending some initial requests. These should definitely work.");
@@ -121,6 +344,44 @@ async fn try_failover(balancebeam: &BalanceBeam, upstreams: &mut Vec<Box<dyn Ser
     }
 }
 
+/// Point the first request at an upstream that hangs up without responding, and make sure that with
+/// retries enabled the GET request is transparently retried on the working upstream
+#[tokio::test]
+async fn test_retry_on_another_upstream() {
+    init_logging();
+    let broken = HangupServer::new().await;
+    let working = EchoServer::new().await;
+    // Round-robin starts with the first upstream, so the first request goes to the broken one
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&broken.address(), &working.address],
+        Some(60),
+        None,
+        &["--strategy", "round-robin", "--max-retries", "1"],
+    )
+    .await;
+
+    for i in 0..4 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(
+            response_text.contains(&format!("GET {} HTTP/1.1", path)),
+            "balancebeam returned unexpected response. Retries may not be working."
+        );
+    }
+
+    assert_eq!(
+        Box::new(broken).stop().await,
+        1,
+        "The broken upstream should have been tried exactly once"
+    );
+    assert_eq!(Box::new(working).stop().await, 4);
+
+    log::info!("All done :)");
+}
+
 /// Make sure passive health checks work. Send a few requests, then kill one of the upstreams and
 /// make sure requests continue to work
 #[tokio::test]
@@ -190,6 +451,133 @@ async fn test_active_health_checks_check_http_status() {
     }
 }
 
+/// Make sure an upstream that accepts connections but never answers health checks gets taken out
+/// of rotation, instead of stalling the health checks forever
+#[tokio::test]
+async fn test_active_health_checks_time_out() {
+    init_logging();
+    let n_requests = 10;
+    let hanging = HangupServer::new_with_delay(Duration::from_secs(60)).await;
+    let echo = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&hanging.address(), &echo.address],
+        Some(1),
+        None,
+        &["--health-check-timeout", "1"],
+    )
+    .await;
+
+    log::info!("Waiting for the health check of the hanging upstream to time out...");
+    sleep(Duration::from_secs(3)).await;
+
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()
+        .unwrap();
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = client
+            .get(format!("http://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .expect("Request timed out. The hanging upstream may still be in rotation")
+            .text()
+            .await
+            .expect("Balancebeam replied with a malformed response");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    Box::new(hanging).stop().await;
+    Box::new(echo).stop().await;
+
+    log::info!("All done :)");
+}
+
+/// Make sure a per-upstream expected status overrides the default of 200: an upstream that always
+/// returns 500 but is declared with `expect=500` should stay in rotation
+#[tokio::test]
+async fn test_active_health_checks_per_upstream_expected_status() {
+    init_logging();
+    let n_requests = 30;
+    let echo = EchoServer::new().await;
+    let erroring = ErrorServer::new().await;
+    let balancebeam = BalanceBeam::new(
+        &[
+            &echo.address,
+            &format!("{};health=/status;expect=500", erroring.address),
+        ],
+        Some(1),
+        None,
+    )
+    .await;
+
+    log::info!("Waiting for a few rounds of health checks...");
+    sleep(Duration::from_secs(3)).await;
+
+    for i in 0..n_requests {
+        balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let echo_count = Box::new(echo).stop().await;
+    let erroring_count = Box::new(erroring).stop().await;
+    log::info!(
+        "Echo upstream received {} requests, erroring upstream received {}",
+        echo_count,
+        erroring_count
+    );
+    // The health checks alone account for a few requests, so look for more than that
+    assert!(
+        erroring_count > 10,
+        "The upstream expected to return 500 was taken out of rotation"
+    );
+
+    log::info!("All done :)");
+}
+
+/// Make sure one dead upstream doesn't stop the active health checks from keeping the others in
+/// rotation:
+///
+/// * Kill the first of three upstreams, so it is the first one checked
+/// * Wait for a few rounds of health checks
+/// * Send some requests and make sure both remaining upstreams still receive them
+#[tokio::test]
+async fn test_active_health_checks_skip_dead_upstream() {
+    let n_upstreams = 3;
+    let n_requests = 20;
+    let (balancebeam, mut upstreams) = setup_with_params(n_upstreams, Some(1), None).await;
+
+    log::info!("Killing the first upstream server");
+    upstreams.remove(0).stop().await;
+
+    log::info!("Waiting for a few rounds of health checks...");
+    sleep(Duration::from_secs(3)).await;
+
+    for i in 0..n_requests {
+        let path = format!("/after-check-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(
+            response_text.contains(&format!("GET {} HTTP/1.1", path)),
+            "balancebeam returned unexpected response. Active health checks may have dropped \
+            healthy upstreams."
+        );
+    }
+
+    while let Some(upstream) = upstreams.pop() {
+        assert!(
+            upstream.stop().await > 0,
+            "A healthy upstream received no requests after another upstream died"
+        );
+    }
+
+    log::info!("All done :)");
+}
+
 /// Make sure active health checks restore upstreams that were previously failed but are now
 /// working again:
 ///
@@ -283,6 +671,16 @@ async fn test_rate_limiting() {
         log::info!("{:?}", response);
         log::info!("Checking to make sure the server responded with HTTP 429");
         assert_eq!(response.status().as_u16(), 429);
+        log::info!("Checking that the 429 tells the client when to retry");
+        let retry_after: u64 = response
+            .headers()
+            .get("retry-after")
+            .expect("429 response should include a Retry-After header")
+            .to_str()
+            .unwrap()
+            .parse()
+            .expect("Retry-After should be a number of seconds");
+        assert!((1..=60).contains(&retry_after));
     }
 
     log::info!("Ensuring the extra requests didn't go through to the upstream servers");
@@ -293,4 +691,559 @@ async fn test_rate_limiting() {
     assert_eq!(total_request_count, rate_limit_threshold);
 
     log::info!("All done :)");
-}
\ No newline at end of file
+}
+
+/// With a limit of N requests per window, exactly N requests should get through and the N+1th
+/// should be the first to be rejected
+#[tokio::test]
+async fn test_rate_limiting_exact_threshold() {
+    let rate_limit_threshold = 3;
+    let (balancebeam, mut upstreams) =
+        setup_with_params(1, Some(60), Some(rate_limit_threshold)).await;
+    let client = reqwest::Client::new();
+
+    for i in 0..rate_limit_threshold + 1 {
+        let response = client
+            .get(format!("http://{}/request-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        let expected_status = if i < rate_limit_threshold { 200 } else { 429 };
+        assert_eq!(response.status().as_u16(), expected_status);
+    }
+
+    let total_request_count = upstreams.pop().unwrap().stop().await;
+    assert_eq!(total_request_count, rate_limit_threshold);
+
+    log::info!("All done :)");
+}
+
+/// When rate limiting by a header, each header value should get its own limit even though all the
+/// requests come from the same IP
+#[tokio::test]
+async fn test_rate_limiting_by_header() {
+    let (balancebeam, mut upstreams) = setup_with_args(
+        1,
+        Some(60),
+        Some(1),
+        &["--rate-limit-key-header", "X-Api-Key"],
+    )
+    .await;
+    let client = reqwest::Client::new();
+
+    for (key, expected_status) in [("alice", 200), ("bob", 200), ("alice", 429)] {
+        let response = client
+            .get(format!("http://{}/request-{}", balancebeam.address, key))
+            .header("X-Api-Key", key)
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), expected_status);
+    }
+
+    let total_request_count = upstreams.pop().unwrap().stop().await;
+    assert_eq!(total_request_count, 2);
+
+    log::info!("All done :)");
+}
+
+/// Clients in an exempt range should never be rate limited
+#[tokio::test]
+async fn test_rate_limiting_exempt_range() {
+    let rate_limit_threshold = 2;
+    let num_requests = 5;
+    let (balancebeam, mut upstreams) = setup_with_args(
+        1,
+        Some(60),
+        Some(rate_limit_threshold),
+        &["--rate-limit-exempt", "127.0.0.0/8", "--rate-limit-exempt", "::1/128"],
+    )
+    .await;
+
+    for i in 0..num_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let total_request_count = upstreams.pop().unwrap().stop().await;
+    assert_eq!(total_request_count, num_requests);
+
+    log::info!("All done :)");
+}
+
+/// Enable sliding-window rate limiting and ensure that requests beyond the limit are rejected
+#[tokio::test]
+async fn test_sliding_window_rate_limiting() {
+    let rate_limit_threshold = 4;
+    let (balancebeam, mut upstreams) = setup_with_args(
+        1,
+        Some(60),
+        Some(rate_limit_threshold),
+        &["--rate-limit-algo", "sliding-window"],
+    )
+    .await;
+    let client = reqwest::Client::new();
+
+    for i in 0..rate_limit_threshold + 2 {
+        let response = client
+            .get(format!("http://{}/request-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        let expected_status = if i < rate_limit_threshold { 200 } else { 429 };
+        assert_eq!(response.status().as_u16(), expected_status);
+    }
+
+    let total_request_count = upstreams.pop().unwrap().stop().await;
+    assert_eq!(total_request_count, rate_limit_threshold);
+
+    log::info!("All done :)");
+}
+
+/// Enable token-bucket rate limiting with a small burst, and ensure that requests beyond the burst
+/// are rejected until the bucket refills
+#[tokio::test]
+async fn test_token_bucket_rate_limiting() {
+    let burst = 3;
+    let (balancebeam, mut upstreams) = setup_with_args(
+        1,
+        Some(60),
+        Some(60),
+        &["--rate-limit-algo", "token-bucket", "--rate-limit-burst", "3"],
+    )
+    .await;
+    let client = reqwest::Client::new();
+
+    log::info!("Sending a burst of requests. The ones within the burst size should succeed.");
+    for i in 0..burst + 1 {
+        let response = client
+            .get(format!("http://{}/request-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        let expected_status = if i < burst { 200 } else { 429 };
+        assert_eq!(response.status().as_u16(), expected_status);
+    }
+
+    log::info!("Waiting for a token to be added back to the bucket");
+    sleep(Duration::from_millis(1500)).await;
+    let response = client
+        .get(format!("http://{}/after-refill", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+
+    let total_request_count = upstreams.pop().unwrap().stop().await;
+    assert_eq!(total_request_count, burst + 1);
+
+    log::info!("All done :)");
+}
+/// Make sure requests are sent to the upstreams of the longest route prefix matching their path,
+/// and to the default upstreams otherwise
+#[tokio::test]
+async fn test_path_routing() {
+    init_logging();
+    let default_upstream = EchoServer::new().await;
+    let api_upstream = EchoServer::new().await;
+    let admin_upstream = EchoServer::new().await;
+    let api_route = format!("/api={}", api_upstream.address);
+    let admin_route = format!("/api/admin/={}", admin_upstream.address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&default_upstream.address],
+        None,
+        None,
+        &["--route", &api_route, "--route", &admin_route],
+    )
+    .await;
+
+    let paths = [
+        "/api",
+        "/api/users",
+        "/api/admin/settings",
+        "/apis",
+        "/static/style.css",
+        "/",
+    ];
+    for path in paths {
+        let response_text = balancebeam
+            .get(path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.starts_with(&format!("GET {} ", path)));
+    }
+
+    assert_eq!(Box::new(default_upstream).stop().await, 3);
+    assert_eq!(Box::new(api_upstream).stop().await, 2);
+    assert_eq!(Box::new(admin_upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Make sure requests are sent to the upstreams of the host they are for, and that requests for
+/// other hosts either go to the default upstreams or are refused with --reject-unknown-hosts
+#[tokio::test]
+async fn test_vhost_routing() {
+    init_logging();
+    let default_upstream = EchoServer::new().await;
+    let a_upstream = EchoServer::new().await;
+    let b_upstream = EchoServer::new().await;
+    let a_vhost = format!("a.example={}", a_upstream.address);
+    let b_vhost = format!("b.example={}", b_upstream.address);
+    let vhost_args = ["--vhost", &a_vhost, "--vhost", &b_vhost];
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&default_upstream.address], None, None, &vhost_args).await;
+    let rejecting_args: Vec<&str> = vhost_args
+        .iter()
+        .copied()
+        .chain(["--reject-unknown-hosts"])
+        .collect();
+    let rejecting =
+        BalanceBeam::new_with_args(&[&default_upstream.address], None, None, &rejecting_args)
+            .await;
+
+    let client = reqwest::Client::new();
+    for (balancebeam, host, expected_status) in [
+        (&balancebeam, "a.example", 200),
+        (&balancebeam, "B.Example:8080", 200),
+        (&balancebeam, "c.example", 200),
+        (&rejecting, "a.example", 200),
+        (&rejecting, "c.example", 404),
+    ] {
+        log::info!("Sending a request for {} to {}", host, balancebeam.address);
+        let response = client
+            .get(format!("http://{}/", balancebeam.address))
+            .header("host", host)
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), expected_status);
+    }
+
+    assert_eq!(Box::new(default_upstream).stop().await, 1);
+    assert_eq!(Box::new(a_upstream).stop().await, 2);
+    assert_eq!(Box::new(b_upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Make sure the latency-weighted strategy sends most requests to the faster upstream
+#[tokio::test]
+async fn test_latency_weighted() {
+    init_logging();
+    let fast = EchoServer::new().await;
+    let slow = EchoServer::new_with_delay(Duration::from_millis(500)).await;
+    // (Health checks are put off so that they don't add to the request counts)
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&fast.address, &slow.address],
+        Some(60),
+        None,
+        &["--strategy", "latency-weighted"],
+    )
+    .await;
+
+    for i in 0..40 {
+        balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let fast_requests = Box::new(fast).stop().await;
+    let slow_requests = Box::new(slow).stop().await;
+    log::info!(
+        "Fast upstream got {} requests, slow upstream got {}",
+        fast_requests,
+        slow_requests
+    );
+    assert_eq!(fast_requests + slow_requests, 40);
+    assert!(
+        fast_requests > slow_requests * 2,
+        "Fast upstream should get most of the requests"
+    );
+    log::info!("All done :)");
+}
+
+/// Make sure an upstream that passes health checks but keeps failing requests stops getting
+/// requests once its circuit breaker opens
+#[tokio::test]
+async fn test_circuit_breaker() {
+    init_logging();
+    // An upstream that answers health checks, but hangs up on every other request
+    let flaky_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let flaky_address = flaky_listener.local_addr().unwrap().to_string();
+    let failed_requests = Arc::new(AtomicUsize::new(0));
+    let flaky_failed_requests = failed_requests.clone();
+    let flaky_task = tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = flaky_listener.accept().await.unwrap();
+            let mut buffer = [0_u8; 1024];
+            let n = stream.read(&mut buffer).await.unwrap_or(0);
+            if buffer[..n].starts_with(b"GET /health ") {
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                    .await;
+            } else {
+                flaky_failed_requests.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    });
+    let healthy = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&healthy.address, &flaky_address],
+        Some(1),
+        None,
+        &[
+            "--active-health-check-path",
+            "/health",
+            "--strategy",
+            "round-robin",
+            "--max-retries",
+            "1",
+            "--circuit-breaker-threshold",
+            "2",
+            "--circuit-breaker-cooldown",
+            "60",
+        ],
+    )
+    .await;
+
+    log::info!("Sending requests while the flaky upstream keeps being re-added by health checks");
+    for i in 0..20 {
+        let response_text = balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.starts_with(&format!("GET /request-{} ", i)));
+        sleep(Duration::from_millis(250)).await;
+    }
+
+    assert_eq!(
+        failed_requests.load(Ordering::SeqCst),
+        2,
+        "The flaky upstream should only get requests until its breaker opens"
+    );
+    flaky_task.abort();
+    Box::new(healthy).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure an upstream brought back by the health checks only gets a small share of requests at
+/// first with --slow-start
+#[tokio::test]
+async fn test_slow_start() {
+    init_logging();
+    let steady = EchoServer::new().await;
+    let recovering = EchoServer::new().await;
+    let recovering_address = recovering.address.clone();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&steady.address, &recovering_address],
+        Some(1),
+        None,
+        &["--slow-start", "60"],
+    )
+    .await;
+
+    log::info!("Taking an upstream down until the health checks notice");
+    Box::new(recovering).stop().await;
+    sleep(Duration::from_secs(3)).await;
+    log::info!("Bringing it back");
+    let recovering = EchoServer::new_at_address(recovering_address).await;
+    sleep(Duration::from_secs(2)).await;
+
+    let n_requests = 40;
+    for i in 0..n_requests {
+        balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    // Both upstreams also count the same number of health checks
+    let recovering_requests = Box::new(recovering).stop().await;
+    let steady_requests = Box::new(steady).stop().await;
+    log::info!(
+        "Recovering upstream got {} requests, steady upstream got {}",
+        recovering_requests,
+        steady_requests
+    );
+    assert!(
+        recovering_requests * 2 < steady_requests,
+        "Recovering upstream should only get a small share of requests"
+    );
+    log::info!("All done :)");
+}
+
+/// Make sure a request that can't connect to one upstream goes to another, without that upstream
+/// being taken out of rotation for everyone
+#[tokio::test]
+async fn test_connect_failure_tries_other_upstreams() {
+    init_logging();
+    let healthy = EchoServer::new().await;
+    let dead_address = {
+        // Grab a free port, and leave nothing listening on it
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    };
+    let metrics_address = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    };
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&dead_address, &healthy.address],
+        None,
+        None,
+        &[
+            "--strategy",
+            "round-robin",
+            "--max-connect-failures",
+            "10",
+            "--metrics-bind",
+            &metrics_address,
+        ],
+    )
+    .await;
+
+    for i in 0..6 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.starts_with(&format!("GET {} ", path)));
+    }
+
+    let status = reqwest::get(format!("http://{}/upstreams", metrics_address))
+        .await
+        .expect("Error fetching upstream status")
+        .text()
+        .await
+        .unwrap();
+    log::info!("Upstream status: {}", status);
+    assert!(
+        !status.contains("\"active\":false"),
+        "A failed connection shouldn't take an upstream out of rotation"
+    );
+
+    assert_eq!(Box::new(healthy).stop().await, 6);
+    log::info!("All done :)");
+}
+
+/// Make sure an upstream that can't be connected to several times in a row is taken out of
+/// rotation
+#[tokio::test]
+async fn test_repeated_connect_failures() {
+    init_logging();
+    let healthy = EchoServer::new().await;
+    let dead_address = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    };
+    let metrics_address = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    };
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&dead_address, &healthy.address],
+        Some(60),
+        None,
+        &[
+            "--strategy",
+            "round-robin",
+            "--max-connect-failures",
+            "3",
+            "--metrics-bind",
+            &metrics_address,
+        ],
+    )
+    .await;
+    let upstream_status = || async {
+        reqwest::get(format!("http://{}/upstreams", metrics_address))
+            .await
+            .expect("Error fetching upstream status")
+            .text()
+            .await
+            .unwrap()
+    };
+
+    balancebeam
+        .get("/first")
+        .await
+        .expect("Error sending request to balancebeam");
+    let status = upstream_status().await;
+    log::info!("Upstream status after one request: {}", status);
+    assert!(
+        !status.contains("\"active\":false"),
+        "A single failed connection shouldn't take an upstream out of rotation"
+    );
+
+    for i in 0..5 {
+        balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    let status = upstream_status().await;
+    log::info!("Upstream status after more requests: {}", status);
+    assert!(
+        status.contains("\"active\":false"),
+        "An upstream that keeps refusing connections should be taken out of rotation"
+    );
+
+    Box::new(healthy).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure a POST sent over a pooled connection isn't sent again when the upstream reads it and
+/// hangs up without answering, since the upstream may already have acted on it
+#[tokio::test]
+async fn test_no_replay_of_unanswered_post() {
+    init_logging();
+    // An upstream that answers GETs over keep-alive connections, but hangs up on every POST
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let posts_received = Arc::new(AtomicUsize::new(0));
+    let server_posts_received = posts_received.clone();
+    let server_task = tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let posts_received = server_posts_received.clone();
+            tokio::spawn(async move {
+                let mut buffer = [0_u8; 1024];
+                loop {
+                    let n = stream.read(&mut buffer).await.unwrap_or(0);
+                    if n == 0 {
+                        break;
+                    }
+                    if buffer[..n].starts_with(b"POST ") {
+                        posts_received.fetch_add(1, Ordering::SeqCst);
+                        // stream is dropped here, closing the connection
+                        break;
+                    }
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                        .await;
+                }
+            });
+        }
+    });
+    let balancebeam = BalanceBeam::new(&[&address], None, None).await;
+
+    log::info!("Sending a GET to leave a connection to the upstream in the pool");
+    balancebeam
+        .get("/warm-up")
+        .await
+        .expect("Error sending request to balancebeam");
+    log::info!("Sending a POST over the pooled connection");
+    let _ = balancebeam.post("/once", "Hello world!").await;
+
+    assert_eq!(
+        posts_received.load(Ordering::SeqCst),
+        1,
+        "The upstream should only have been sent the POST once"
+    );
+    server_task.abort();
+    log::info!("All done :)");
+}
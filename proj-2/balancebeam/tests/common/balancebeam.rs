@@ -24,6 +24,22 @@ impl BalanceBeam {
         upstreams: &[&str],
         active_health_check_interval: Option<usize>,
         max_requests_per_minute: Option<usize>,
+    ) -> BalanceBeam {
+        BalanceBeam::new_with_args(
+            upstreams,
+            active_health_check_interval,
+            max_requests_per_minute,
+            &[],
+        )
+        .await
+    }
+
+    /// Like `new`, but passes `extra_args` through to the balancebeam command line as well.
+    pub async fn new_with_args(
+        upstreams: &[&str],
+        active_health_check_interval: Option<usize>,
+        max_requests_per_minute: Option<usize>,
+        extra_args: &[&str],
     ) -> BalanceBeam {
         let mut rng = rand::thread_rng();
         let address = format!("127.0.0.1:{}", rng.gen_range(1024..65535));
@@ -40,6 +56,7 @@ impl BalanceBeam {
             cmd.arg("--max-requests-per-minute")
                 .arg(max_requests_per_minute.to_string());
         }
+        cmd.args(extra_args);
         cmd.kill_on_drop(true);
         cmd.stdout(std::process::Stdio::piped());
         cmd.stderr(std::process::Stdio::piped());
@@ -85,6 +102,17 @@ impl BalanceBeam {
         BalanceBeam { child, address }
     }
 
+    /// Sends SIGTERM to balancebeam and waits for it to exit, returning false if it is still
+    /// running after `timeout`.
+    #[allow(dead_code)]
+    pub async fn terminate(&mut self, timeout: Duration) -> bool {
+        let pid = self.child.id().expect("balancebeam already exited");
+        let pid = nix::unistd::Pid::from_raw(pid as i32);
+        nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGTERM)
+            .expect("Could not send SIGTERM to balancebeam");
+        tokio::time::timeout(timeout, self.child.wait()).await.is_ok()
+    }
+
     #[allow(dead_code)]
     pub async fn get(&self, path: &str) -> Result<String, reqwest::Error> {
         let client = reqwest::Client::new();
@@ -4,11 +4,15 @@ use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response};
 use rand::Rng;
 use std::sync::{atomic, Arc};
+use std::time::Duration;
 use tokio::sync::oneshot;
+use tokio::time::sleep;
 
 #[derive(Debug)]
 struct ServerState {
     pub requests_received: atomic::AtomicUsize,
+    /// How long to wait before answering each request
+    pub delay: Duration,
 }
 
 async fn echo(
@@ -18,6 +22,8 @@ async fn echo(
     server_state
         .requests_received
         .fetch_add(1, atomic::Ordering::SeqCst);
+    sleep(server_state.delay).await;
+    let cache_control = req.headers().get("x-echo-cache-control").cloned();
     let mut req_text = format!("{} {} {:?}\n", req.method(), req.uri(), req.version());
     for (header_name, header_value) in req.headers() {
         req_text += &format!(
@@ -29,7 +35,14 @@ async fn echo(
     req_text += "\n";
     let mut req_as_bytes = req_text.into_bytes();
     req_as_bytes.extend(hyper::body::to_bytes(req.into_body()).await?);
-    Ok(Response::new(Body::from(req_as_bytes)))
+    let mut response = Response::new(Body::from(req_as_bytes));
+    // Let tests control how long the response may be cached for
+    if let Some(cache_control) = cache_control {
+        response
+            .headers_mut()
+            .insert(hyper::header::CACHE_CONTROL, cache_control);
+    }
+    Ok(response)
 }
 
 pub struct EchoServer {
@@ -45,7 +58,18 @@ impl EchoServer {
         EchoServer::new_at_address(format!("127.0.0.1:{}", rng.gen_range(1024..65535))).await
     }
 
+    /// Creates a server that waits for `delay` before answering each request, like a slow upstream.
+    #[allow(dead_code)]
+    pub async fn new_with_delay(delay: Duration) -> EchoServer {
+        let mut rng = rand::thread_rng();
+        EchoServer::start(format!("127.0.0.1:{}", rng.gen_range(1024..65535)), delay).await
+    }
+
     pub async fn new_at_address(bind_addr_string: String) -> EchoServer {
+        EchoServer::start(bind_addr_string, Duration::ZERO).await
+    }
+
+    async fn start(bind_addr_string: String, delay: Duration) -> EchoServer {
         let bind_addr = bind_addr_string.parse().unwrap();
         // Create a one-shot channel that can be used to tell the server to shut down
         let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
@@ -53,6 +77,7 @@ impl EchoServer {
         // Start a separate server task
         let server_state = Arc::new(ServerState {
             requests_received: atomic::AtomicUsize::new(0),
+            delay,
         });
         let server_task_state = server_state.clone();
         let server_task = tokio::spawn(async move {
@@ -0,0 +1,83 @@
+use crate::common::server::Server;
+use async_trait::async_trait;
+use std::sync::{atomic, Arc};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio::time::sleep;
+
+/// A server that accepts connections and reads the request, but then hangs up without ever sending
+/// a response, like an upstream that crashes (or, with a delay, hangs) while handling a request.
+#[allow(dead_code)]
+pub struct HangupServer {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    address: String,
+    requests_received: Arc<atomic::AtomicUsize>,
+}
+
+impl HangupServer {
+    #[allow(dead_code)]
+    pub async fn new() -> HangupServer {
+        HangupServer::new_with_delay(Duration::ZERO).await
+    }
+
+    /// Creates a server that keeps each connection open for `delay` before hanging up.
+    #[allow(dead_code)]
+    pub async fn new_with_delay(delay: Duration) -> HangupServer {
+        // Let the OS pick a free port
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("HangupServer could not bind");
+        let address = listener.local_addr().unwrap().to_string();
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+        let requests_received = Arc::new(atomic::AtomicUsize::new(0));
+
+        let server_requests_received = requests_received.clone();
+        let server_task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = &mut shutdown_rx => break,
+                    accepted = listener.accept() => {
+                        if let Ok((mut stream, _)) = accepted {
+                            let requests_received = server_requests_received.clone();
+                            tokio::spawn(async move {
+                                let mut buf = [0_u8; 1024];
+                                if let Ok(n) = stream.read(&mut buf).await {
+                                    if n > 0 {
+                                        requests_received.fetch_add(1, atomic::Ordering::SeqCst);
+                                    }
+                                }
+                                sleep(delay).await;
+                                // stream is dropped here, closing the connection
+                            });
+                        }
+                    }
+                }
+            }
+        });
+
+        HangupServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            address,
+            requests_received,
+        }
+    }
+}
+
+#[async_trait]
+impl Server for HangupServer {
+    async fn stop(self: Box<Self>) -> usize {
+        let _ = self.shutdown_signal_sender.send(());
+        self.server_task
+            .await
+            .expect("HangupServer server task panicked");
+        self.requests_received.load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
@@ -1,6 +1,7 @@
 mod balancebeam;
 mod echo_server;
 mod error_server;
+mod hangup_server;
 mod server;
 
 use std::sync;
@@ -9,6 +10,8 @@ pub use balancebeam::BalanceBeam;
 pub use echo_server::EchoServer;
 #[allow(unused)]
 pub use error_server::ErrorServer;
+#[allow(unused)]
+pub use hangup_server::HangupServer;
 pub use server::Server;
 
 static INIT_TESTS: sync::Once = sync::Once::new();
@@ -31,7 +31,7 @@ impl Grid {
     /// give you more practice with Option :) and because this similar library returns Option:
     /// https://docs.rs/array2d/0.2.1/array2d/struct.Array2D.html
     pub fn get(&self, row: usize, col: usize) -> Option<usize> {
-        if row < self.num_rows && col < self.num_rows {
+        if row < self.num_rows && col < self.num_cols {
             Some(self.elems[row*self.num_cols + col])
         }
         else {
@@ -105,4 +105,14 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn test_grid_wider_than_tall() {
+        let mut grid = Grid::new(2, 5);
+        assert!(grid.set(1, 4, 7).is_ok());
+        assert_eq!(grid.get(1, 4), Some(7));
+        assert_eq!(grid.get(0, 4), Some(0));
+        assert_eq!(grid.get(2, 0), None);
+        assert_eq!(grid.get(0, 5), None);
+    }
 }
@@ -1,24 +1,49 @@
 use grid::Grid;
 use std::env;
-use std::fs::File; // For read_file_lines()
-use std::io::{self, BufRead}; use std::isize::MAX;
+use std::fs::{self, File}; // For read_file_lines()
+use std::io; use std::isize::MAX;
 // For read_file_lines()
+use std::io::{IsTerminal, Read, Seek, SeekFrom, Write};
+use std::collections::BTreeSet;
+use std::ops::Range;
+use std::path::Path;
 use std::process;
 
 pub mod grid;
 
-/// Reads the file at the supplied path, and returns a vector of strings.
+/// Reads the file at the supplied path, and returns a vector of strings. Bytes that aren't valid
+/// UTF-8 are replaced rather than making the whole file unreadable. Only `\n` ends a line, so a
+/// `\r` before it is kept and the lines can be written back out as they were.
 fn read_file_lines(filename: &String) -> Result<Vec<String>, io::Error> {
-    let file = File::open(filename).unwrap();
-    let mut context:Vec<String> = Vec::new();
-    for line in  io::BufReader::new(file).lines() {
-        let line_str = line?;
-        context.push(line_str);
+    let contents = fs::read(filename)?;
+    let text = String::from_utf8_lossy(&contents);
+    Ok(text.split_terminator('\n').map(|line| line.to_string()).collect())
+}
+
+/// Returns whether the file has text on its last line that isn't followed by a newline.
+fn missing_newline(filename: &String) -> Result<bool, io::Error> {
+    let mut file = File::open(filename)?;
+    if file.metadata()?.len() == 0 {
+        return Ok(false);
     }
-    Ok(context)
+    let mut last = [0];
+    file.seek(SeekFrom::End(-1))?;
+    file.read_exact(&mut last)?;
+    Ok(last[0] != b'\n')
 }
 
-fn lcs(seq1: &Vec<String>, seq2: &Vec<String>) -> Grid {
+/// How much of a file is looked at to decide whether it is binary
+const BINARY_CHECK_LEN: u64 = 8192;
+
+/// Returns whether the file looks like a binary file rather than text: like GNU diff, whether
+/// there is a NUL byte near the start.
+fn is_binary_file(filename: &String) -> Result<bool, io::Error> {
+    let mut start = Vec::new();
+    File::open(filename)?.take(BINARY_CHECK_LEN).read_to_end(&mut start)?;
+    Ok(start.contains(&0))
+}
+
+fn lcs(seq1: &[String], seq2: &[String]) -> Grid {
     // Note: Feel free to use unwrap() in this code, as long as you're basically certain it'll
     // never happen. Conceptually, unwrap() is justified here, because there's not really any error
     // condition you're watching out for (i.e. as long as your code is written correctly, nothing
@@ -42,43 +67,696 @@ fn lcs(seq1: &Vec<String>, seq2: &Vec<String>) -> Grid {
     }        
     c
 }
-fn print_diff(lcs_table: &Grid, lines1: &Vec<String>, lines2: &Vec<String>, i: usize, j: usize) {
-    if i > 0 && j > 0 && lines1[i-1] == lines2[j-1] {
-        print_diff(lcs_table, lines1, lines2, i-1, j-1);
-        println!(" {}",lines1[i-1]);
+/// One step of the edit script that turns the first file into the second.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Edit {
+    /// The line is in both files, at these indices of the first and second
+    Same(usize, usize),
+    /// The line at this index of the first file was removed
+    Delete(usize),
+    /// The line at this index of the second file was added
+    Insert(usize),
+}
+
+/// Output settings picked on the command line.
+struct Options {
+    /// Print a unified diff (`-u`) instead of the `<`/`>` format
+    unified: bool,
+    /// How many unchanged lines a unified diff shows around each change (`-C`)
+    context: usize,
+    /// Color added lines green and removed lines red (`--color`)
+    color: bool,
+    /// Compare lines without any of their whitespace (`-w`)
+    ignore_whitespace: bool,
+    /// Compare lines without regard to case (`-i`)
+    ignore_case: bool,
+    /// Print the files next to each other in two columns (`-y`)
+    side_by_side: bool,
+    /// Width of the side-by-side output (`--width`)
+    width: usize,
+    /// Only say whether the files differ (`-q`)
+    brief: bool,
+    /// Diff the files line by line even if they look binary (`--text`)
+    text: bool,
+    /// Summarize the changes, after the diff if one was asked for with `-u` or `-y` (`--stat`)
+    stat: bool,
+    /// Compare the subdirectories of two directories too (`-r`)
+    recursive: bool,
+    /// Show a removed line and the added line replacing it as one line, marking the words that
+    /// changed (`--word-diff`)
+    word_diff: bool,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            unified: false,
+            context: 3,
+            color: false,
+            ignore_whitespace: false,
+            ignore_case: false,
+            side_by_side: false,
+            width: 130,
+            brief: false,
+            text: false,
+            stat: false,
+            recursive: false,
+            word_diff: false,
+        }
+    }
+}
+
+/// Walks the LCS table back from the bottom-right corner to find the edit script, in file order.
+fn edit_script(lcs_table: &Grid, lines1: &[String], lines2: &[String]) -> Vec<Edit> {
+    let (mut i, mut j) = (lines1.len(), lines2.len());
+    let mut edits = Vec::new();
+    while i > 0 || j > 0 {
+        if i > 0 && j > 0 && lines1[i-1] == lines2[j-1] {
+            edits.push(Edit::Same(i-1, j-1));
+            i -= 1;
+            j -= 1;
+        }
+        else if j > 0 && (i == 0 || lcs_table.get(i, j-1) >= lcs_table.get(i-1, j)) {
+            edits.push(Edit::Insert(j-1));
+            j -= 1;
+        }
+        else {
+            edits.push(Edit::Delete(i-1));
+            i -= 1;
+        }
+    }
+    edits.reverse();
+    edits
+}
+
+/// Groups the changes of an edit script into hunks, each with up to `context` unchanged lines
+/// before and after. Changes close enough for their context to overlap share a hunk. Returns the
+/// ranges of the edit script the hunks cover.
+fn hunks(edits: &[Edit], context: usize) -> Vec<Range<usize>> {
+    let mut hunks: Vec<Range<usize>> = Vec::new();
+    for (k, edit) in edits.iter().enumerate() {
+        if let Edit::Same(_, _) = *edit {
+            continue;
+        }
+        let start = k.saturating_sub(context);
+        let end = std::cmp::min(k + 1 + context, edits.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.end => last.end = end,
+            _ => hunks.push(start..end),
+        }
+    }
+    hunks
+}
+
+/// Formats one side of a hunk header: the 1-based line the hunk starts at and how many lines of
+/// the file it has. An empty side gives the line just before it, like GNU diff.
+fn hunk_range(first: usize, count: usize) -> String {
+    match count {
+        0 => format!("{},0", first),
+        1 => format!("{}", first + 1),
+        _ => format!("{},{}", first + 1, count),
+    }
+}
+
+/// What patch(1) expects after a line that has no newline at the end of its file
+const NO_NEWLINE: &str = "\\ No newline at end of file";
+
+/// Returns whether the line an edit prints is the last line of a file with no newline after it.
+/// `lens` are the numbers of lines in the files, and `missing_newline` says which of them lack it.
+fn lacks_newline(edit: Edit, lens: (usize, usize), missing_newline: (bool, bool)) -> bool {
+    match edit {
+        Edit::Same(i, _) | Edit::Delete(i) => missing_newline.0 && i + 1 == lens.0,
+        Edit::Insert(j) => missing_newline.1 && j + 1 == lens.1,
+    }
+}
+
+/// ANSI escape codes for `--color`
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+
+/// Writes one line of the diff, wrapped in the ANSI `color` if colored output is on.
+fn write_line<W: Write>(out: &mut W, options: &Options, color: Option<&str>, prefix: &str,
+                        text: &str) -> io::Result<()> {
+    match color.filter(|_| options.color) {
+        Some(color) => writeln!(out, "{}{}{}{}", color, prefix, text, RESET),
+        None => writeln!(out, "{}{}", prefix, text),
+    }
+}
+
+/// A line of the diff as it is printed
+#[derive(Debug, PartialEq)]
+enum DiffLine {
+    /// A line kept, removed or added
+    Edit(Edit),
+    /// A removed line and the added line replacing it, shown as one with `--word-diff`
+    Changed(usize, usize),
+}
+
+/// Returns the lines of the diff for part of an edit script. With `--word-diff`, the removed and
+/// added lines of each run of changes are paired up in order, and any left over when one side has
+/// more are shown as they are.
+fn diff_lines(edits: &[Edit], options: &Options) -> Vec<DiffLine> {
+    if !options.word_diff {
+        return edits.iter().map(|edit| DiffLine::Edit(*edit)).collect();
+    }
+    let mut lines = Vec::new();
+    let mut rest = edits;
+    while let Some(edit) = rest.first() {
+        if let Edit::Same(_, _) = *edit {
+            lines.push(DiffLine::Edit(*edit));
+            rest = &rest[1..];
+            continue;
+        }
+        let run_len = rest
+            .iter()
+            .position(|edit| matches!(*edit, Edit::Same(_, _)))
+            .unwrap_or(rest.len());
+        let (run, after) = rest.split_at(run_len);
+        let removed: Vec<_> = run.iter().filter(|edit| matches!(**edit, Edit::Delete(_))).collect();
+        let added: Vec<_> = run.iter().filter(|edit| matches!(**edit, Edit::Insert(_))).collect();
+        for k in 0..std::cmp::max(removed.len(), added.len()) {
+            lines.push(match (removed.get(k), added.get(k)) {
+                (Some(&&Edit::Delete(i)), Some(&&Edit::Insert(j))) => DiffLine::Changed(i, j),
+                (Some(&&edit), _) | (_, Some(&&edit)) => DiffLine::Edit(edit),
+                (None, None) => unreachable!(),
+            });
+        }
+        rest = after;
+    }
+    lines
+}
+
+/// Splits a line into its words and the runs of whitespace between them, so that the pieces put
+/// back together give the line again.
+fn split_words(line: &str) -> Vec<String> {
+    let mut words: Vec<String> = Vec::new();
+    for c in line.chars() {
+        match words.last_mut() {
+            Some(word) if word.ends_with(char::is_whitespace) == c.is_whitespace() => word.push(c),
+            _ => words.push(c.to_string()),
+        }
+    }
+    words
+}
+
+/// Shows how the line `new` differs from `old` word by word, with the removed words marked as
+/// `[-old-]` and the added ones as `{+new+}` (or in red and green if colored output is on).
+fn word_diff(old: &str, new: &str, options: &Options) -> String {
+    let (words1, words2) = (split_words(old), split_words(new));
+    let (keys1, keys2) = (normalize(&words1, options), normalize(&words2, options));
+    let edits = edit_script(&lcs(&keys1, &keys2), &keys1, &keys2);
+    let mark = |text: &str, color: &str, start: &str, end: &str| {
+        if options.color {
+            format!("{}{}{}", color, text, RESET)
+        } else {
+            format!("{}{}{}", start, text, end)
+        }
+    };
+    let mut line = String::new();
+    let (mut removed, mut added) = (String::new(), String::new());
+    // a None at the end marks off the last run of changed words
+    for edit in edits.iter().map(Some).chain(std::iter::once(None)) {
+        match edit {
+            Some(&Edit::Delete(i)) => removed += &words1[i],
+            Some(&Edit::Insert(j)) => added += &words2[j],
+            _ => {
+                if !removed.is_empty() {
+                    line += &mark(&removed, RED, "[-", "-]");
+                    removed.clear();
+                }
+                if !added.is_empty() {
+                    line += &mark(&added, GREEN, "{+", "+}");
+                    added.clear();
+                }
+                if let Some(&Edit::Same(i, _)) = edit {
+                    line += &words1[i];
+                }
+            }
+        }
+    }
+    line
+}
+
+/// Writes one line of the diff with the prefix for its kind in `prefixes`: kept, removed, added
+/// and changed lines.
+fn write_diff_line<W: Write>(out: &mut W, line: &DiffLine, lines1: &[String], lines2: &[String],
+                             missing_newline: (bool, bool), prefixes: [&str; 4],
+                             options: &Options) -> io::Result<()> {
+    let lens = (lines1.len(), lines2.len());
+    let lacks_newline = match *line {
+        DiffLine::Edit(edit) => {
+            match edit {
+                Edit::Same(i, _) => write_line(out, options, None, prefixes[0], &lines1[i])?,
+                Edit::Delete(i) => write_line(out, options, Some(RED), prefixes[1], &lines1[i])?,
+                Edit::Insert(j) => write_line(out, options, Some(GREEN), prefixes[2], &lines2[j])?,
+            }
+            lacks_newline(edit, lens, missing_newline)
+        }
+        DiffLine::Changed(i, j) => {
+            let text = word_diff(&lines1[i], &lines2[j], options);
+            write_line(out, options, None, prefixes[3], &text)?;
+            lacks_newline(Edit::Delete(i), lens, missing_newline)
+                || lacks_newline(Edit::Insert(j), lens, missing_newline)
+        }
+    };
+    if lacks_newline {
+        writeln!(out, "{}", NO_NEWLINE)?;
+    }
+    Ok(())
+}
+
+/// Writes the diff in unified format, with `---`/`+++` file headers and `@@` hunk headers.
+fn print_unified<W: Write>(out: &mut W, edits: &[Edit], lines1: &[String], lines2: &[String],
+                           names: (&str, &str), missing_newline: (bool, bool),
+                           options: &Options) -> io::Result<()> {
+    let hunks = hunks(edits, options.context);
+    if hunks.is_empty() {
+        return Ok(());
+    }
+    writeln!(out, "--- {}", names.0)?;
+    writeln!(out, "+++ {}", names.1)?;
+    // how far into each file the edit script has got
+    let (mut line1, mut line2) = (0, 0);
+    let mut next = 0;
+    for hunk in hunks {
+        for edit in &edits[next..hunk.start] {
+            match *edit {
+                Edit::Same(_, _) => { line1 += 1; line2 += 1; }
+                Edit::Delete(_) => line1 += 1,
+                Edit::Insert(_) => line2 += 1,
+            }
+        }
+        let hunk_edits = &edits[hunk.start..hunk.end];
+        let count1 = hunk_edits.iter().filter(|edit| !matches!(**edit, Edit::Insert(_))).count();
+        let count2 = hunk_edits.iter().filter(|edit| !matches!(**edit, Edit::Delete(_))).count();
+        writeln!(out, "@@ -{} +{} @@", hunk_range(line1, count1), hunk_range(line2, count2))?;
+        for line in diff_lines(hunk_edits, options) {
+            write_diff_line(out, &line, lines1, lines2, missing_newline, [" ", "-", "+", "~"],
+                            options)?;
+        }
+        line1 += count1;
+        line2 += count2;
+        next = hunk.end;
+    }
+    Ok(())
+}
+
+/// Returns `text` with its tabs expanded and cut down to at most `width` characters.
+fn fit_column(text: &str, width: usize) -> String {
+    let mut column = String::new();
+    let mut len = 0;
+    for c in text.chars() {
+        let expanded = if c == '\t' { 8 - len % 8 } else { 1 };
+        if len + expanded > width {
+            break;
+        }
+        if c == '\t' {
+            column.extend(std::iter::repeat_n(' ', expanded));
+        } else {
+            column.push(c);
+        }
+        len += expanded;
+    }
+    column
+}
+
+/// Writes the two files next to each other, with a gutter marker between them: ` ` for lines in
+/// both, `|` for changed lines, `<` for lines only in the first file and `>` for lines only in
+/// the second. Within a run of changes, removed and added lines are paired up as changed lines.
+fn print_side_by_side<W: Write>(out: &mut W, edits: &[Edit], lines1: &[String], lines2: &[String],
+                                options: &Options) -> io::Result<()> {
+    // each column gets half of what is left after the gutter
+    let column = options.width.saturating_sub(3) / 2;
+    let mut write_row = |left: Option<&String>, marker: char, right: Option<&String>| {
+        let left = fit_column(left.map_or("", |line| line.as_str()), column);
+        let right = fit_column(right.map_or("", |line| line.as_str()), column);
+        let row = format!("{:<width$} {} {}", left, marker, right, width = column);
+        let color = match marker {
+            '<' => Some(RED),
+            '>' => Some(GREEN),
+            _ => None,
+        };
+        write_line(out, options, color, "", row.trim_end())
+    };
+    let mut k = 0;
+    while k < edits.len() {
+        if let Edit::Same(i, j) = edits[k] {
+            write_row(Some(&lines1[i]), ' ', Some(&lines2[j]))?;
+            k += 1;
+            continue;
+        }
+        let (mut removed, mut added) = (Vec::new(), Vec::new());
+        while k < edits.len() {
+            match edits[k] {
+                Edit::Delete(i) => removed.push(&lines1[i]),
+                Edit::Insert(j) => added.push(&lines2[j]),
+                Edit::Same(_, _) => break,
+            }
+            k += 1;
+        }
+        for row in 0..std::cmp::max(removed.len(), added.len()) {
+            let (left, right) = (removed.get(row).cloned(), added.get(row).cloned());
+            let marker = match (left, right) {
+                (Some(_), Some(_)) => '|',
+                (Some(_), None) => '<',
+                _ => '>',
+            };
+            write_row(left, marker, right)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the diff side by side or in unified format if `options` asks for it, and otherwise as
+/// every line of both files marked ` ` (in both), `<` (only in the first), `>` (only in the
+/// second) or `~` (changed, with `--word-diff`).
+fn print_diff<W: Write>(out: &mut W, edits: &[Edit], lines1: &[String], lines2: &[String],
+                        names: (&str, &str), missing_newline: (bool, bool),
+                        options: &Options) -> io::Result<()> {
+    if options.side_by_side {
+        return print_side_by_side(out, edits, lines1, lines2, options);
+    }
+    if options.unified {
+        return print_unified(out, edits, lines1, lines2, names, missing_newline, options);
+    }
+    writeln!(out)?;
+    for line in diff_lines(edits, options) {
+        write_diff_line(out, &line, lines1, lines2, missing_newline, [" ", "< ", "> ", "~ "],
+                        options)?;
+    }
+    Ok(())
+}
+
+/// Returns a line the way it is compared, going by the `-w` and `-i` options.
+fn normalize_line(line: &str, options: &Options) -> String {
+    let line = if options.ignore_whitespace {
+        line.split_whitespace().collect()
+    } else {
+        line.to_string()
+    };
+    if options.ignore_case { line.to_lowercase() } else { line }
+}
+
+/// Returns the lines the way they are compared, going by the `-w` and `-i` options.
+fn normalize(lines: &[String], options: &Options) -> Vec<String> {
+    lines.iter().map(|line| normalize_line(line, options)).collect()
+}
+
+/// Returns whether the files differ at all. This is much quicker than working out the diff: it
+/// stops at the first pair of lines that don't match.
+fn files_differ(file1: &[String], file2: &[String], options: &Options) -> bool {
+    file1.len() != file2.len()
+        || file1
+            .iter()
+            .zip(file2)
+            .any(|(line1, line2)| normalize_line(line1, options) != normalize_line(line2, options))
+}
+
+/// How many lines a diff adds, removes and changes
+#[derive(Debug, PartialEq, Default)]
+struct DiffStat {
+    added: usize,
+    removed: usize,
+    changed: usize,
+}
+
+/// Counts up the changes in an edit script. Within each run of changes, removed and added lines
+/// are paired up as changed lines, the same as in the side-by-side view.
+fn diff_stat(edits: &[Edit]) -> DiffStat {
+    let mut stat = DiffStat::default();
+    let (mut removed, mut added) = (0, 0);
+    // the Same at the end closes off the last run
+    for edit in edits.iter().chain(std::iter::once(&Edit::Same(0, 0))) {
+        match *edit {
+            Edit::Delete(_) => removed += 1,
+            Edit::Insert(_) => added += 1,
+            Edit::Same(_, _) => {
+                let changed = std::cmp::min(removed, added);
+                stat.changed += changed;
+                stat.removed += removed - changed;
+                stat.added += added - changed;
+                removed = 0;
+                added = 0;
+            }
+        }
+    }
+    stat
+}
+
+/// Returns "1 line" or "`count` lines".
+fn lines_count(count: usize) -> String {
+    format!("{} line{}", count, if count == 1 { "" } else { "s" })
+}
+
+/// Writes the diff of the two files and returns whether they differ. `missing_newline` says which
+/// of the files have no newline at the end.
+fn diff<W: Write>(out: &mut W, file1: &[String], file2: &[String], names: (&str, &str),
+                  missing_newline: (bool, bool), options: &Options) -> io::Result<bool> {
+    // the lines are matched up by their normalized copies, but printed as they are
+    let (mut keys1, mut keys2) = (normalize(file1, options), normalize(file2, options));
+    // a last line without a newline only matches another one without, so that patch adds or
+    // removes the newline (no line can have a \n in it otherwise)
+    for (keys, missing) in [(&mut keys1, missing_newline.0), (&mut keys2, missing_newline.1)] {
+        if let (Some(last), true) = (keys.last_mut(), missing) {
+            last.push('\n');
+        }
+    }
+    let grid = lcs(&keys1, &keys2);
+    let edits = edit_script(&grid, &keys1, &keys2);
+    if !options.stat || options.unified || options.side_by_side {
+        print_diff(out, &edits, file1, file2, names, missing_newline, options)?;
+    }
+    if options.stat {
+        let stat = diff_stat(&edits);
+        writeln!(out, "{} added, {} removed, {} changed", lines_count(stat.added),
+                 lines_count(stat.removed), stat.changed)?;
+    }
+    Ok(edits.iter().any(|edit| !matches!(*edit, Edit::Same(_, _))))
+}
+
+/// Describes an I/O error the way the C library would, leaving off the "(os error N)" that Rust
+/// adds.
+fn describe_error(e: &io::Error) -> String {
+    let message = e.to_string();
+    match message.find(" (os error") {
+        Some(end) => message[..end].to_string(),
+        None => message,
+    }
+}
+
+/// Exit statuses, the same as GNU diff's: the files are the same, they differ, or something went
+/// wrong
+const EXIT_SAME: i32 = 0;
+const EXIT_DIFFERENT: i32 = 1;
+const EXIT_TROUBLE: i32 = 2;
+
+/// Works out from a `--color` setting whether to color the output: `auto` only colors it when
+/// it's going to a terminal.
+fn use_color(when: &str) -> Option<bool> {
+    match when {
+        "always" => Some(true),
+        "never" => Some(false),
+        "auto" => Some(io::stdout().is_terminal()),
+        _ => None,
+    }
+}
+
+/// Returns a message for an error reading `filename`.
+fn file_error(filename: &str) -> impl Fn(io::Error) -> String + '_ {
+    move |e| format!("{}: {}", filename, describe_error(&e))
+}
+
+/// Compares the two files the way `options` asks, prints how they differ, and returns whether
+/// they do. With `header`, for the files of a directory, nothing is printed for files that are
+/// the same and a `diff file1 file2` line goes before the diff of the rest. An error comes back
+/// as the message to print.
+fn compare_files(filename1: &String, filename2: &String, header: bool, options: &Options)
+                 -> Result<bool, String> {
+    // binary files are only compared byte for byte
+    if !options.text
+        && (is_binary_file(filename1).map_err(file_error(filename1))?
+            || is_binary_file(filename2).map_err(file_error(filename2))?)
+    {
+        let contents1 = fs::read(filename1).map_err(file_error(filename1))?;
+        let contents2 = fs::read(filename2).map_err(file_error(filename2))?;
+        if contents1 == contents2 {
+            return Ok(false);
+        }
+        let kind = if options.brief { "Files" } else { "Binary files" };
+        println!("{} {} and {} differ", kind, filename1, filename2);
+        return Ok(true);
     }
-    else if j > 0 && (i ==0 || lcs_table.get(i, j-1) >= lcs_table.get(i-1, j)){
-        print_diff(lcs_table, lines1, lines2, i, j-1);
-        println!("> {}",lines2[j-1]);
+
+    let file1 = read_file_lines(filename1).map_err(file_error(filename1))?;
+    let file2 = read_file_lines(filename2).map_err(file_error(filename2))?;
+    let missing_newline = (missing_newline(filename1).map_err(file_error(filename1))?,
+                           missing_newline(filename2).map_err(file_error(filename2))?);
+
+    let differ = missing_newline.0 != missing_newline.1 || files_differ(&file1, &file2, options);
+    if options.brief {
+        if differ {
+            println!("Files {} and {} differ", filename1, filename2);
+        }
+        return Ok(differ);
     }
-    else if i > 0 && (j ==0 || lcs_table.get(i, j-1) < lcs_table.get(i-1, j)){
-        print_diff(lcs_table, lines1, lines2, i-1, j);
-        println!("< {}",lines1[i-1]);
+    if header {
+        if !differ {
+            return Ok(false);
+        }
+        println!("diff {} {}", filename1, filename2);
     }
-    else {
-        println!("");
+    let stdout = io::stdout();
+    diff(&mut stdout.lock(), &file1, &file2, (filename1, filename2), missing_newline, options)
+        .map_err(|e| describe_error(&e))
+}
+
+/// Returns the exit code for the result of comparing two files, printing the error if there was
+/// one.
+fn exit_code(result: Result<bool, String>) -> i32 {
+    match result {
+        Ok(true) => EXIT_DIFFERENT,
+        Ok(false) => EXIT_SAME,
+        Err(message) => {
+            eprintln!("rdiff: {}", message);
+            EXIT_TROUBLE
+        }
     }
 }
 
-fn diff(file1: &Vec<String>, file2: &Vec<String>) {
-    let grid = lcs(&file1, &file2);
-    let (i,j) = (file1.len(),file2.len());
-    print_diff(&grid, file1, file2, i, j)
+/// Returns the path of the file named `name` (or, for a path, named like its last part) in `dir`.
+fn path_in(dir: &str, name: &str) -> String {
+    let name = Path::new(name).file_name().unwrap_or_default();
+    Path::new(dir).join(name).to_string_lossy().into_owned()
+}
+
+/// Returns the names of the entries of a directory, in order.
+fn dir_entries(dir: &str) -> Result<BTreeSet<String>, String> {
+    let entries = fs::read_dir(dir).map_err(file_error(dir))?;
+    entries
+        .map(|entry| {
+            let entry = entry.map_err(file_error(dir))?;
+            Ok(entry.file_name().to_string_lossy().into_owned())
+        })
+        .collect()
+}
+
+/// Compares two directories like GNU diff: files in both are compared, and the rest are reported
+/// as only being in one. Subdirectories in both are compared too with `-r`, and otherwise just
+/// reported. Returns the exit code, which is the worst of the codes for the files.
+fn compare_dirs(dir1: &str, dir2: &str, options: &Options) -> i32 {
+    let (entries1, entries2) = match (dir_entries(dir1), dir_entries(dir2)) {
+        (Ok(entries1), Ok(entries2)) => (entries1, entries2),
+        (Err(message), _) | (_, Err(message)) => return exit_code(Err(message)),
+    };
+    let mut code = EXIT_SAME;
+    for name in entries1.union(&entries2) {
+        let (path1, path2) = (path_in(dir1, name), path_in(dir2, name));
+        if !entries2.contains(name) {
+            println!("Only in {}: {}", dir1, name);
+            code = code.max(EXIT_DIFFERENT);
+            continue;
+        }
+        if !entries1.contains(name) {
+            println!("Only in {}: {}", dir2, name);
+            code = code.max(EXIT_DIFFERENT);
+            continue;
+        }
+        let kind = |is_dir| if is_dir { "directory" } else { "regular file" };
+        let file_code = match (Path::new(&path1).is_dir(), Path::new(&path2).is_dir()) {
+            (true, true) if options.recursive => compare_dirs(&path1, &path2, options),
+            (true, true) => {
+                println!("Common subdirectories: {} and {}", path1, path2);
+                EXIT_SAME
+            }
+            (false, false) => exit_code(compare_files(&path1, &path2, true, options)),
+            (is_dir1, is_dir2) => {
+                println!("File {} is a {} while file {} is a {}", path1, kind(is_dir1), path2,
+                         kind(is_dir2));
+                EXIT_DIFFERENT
+            }
+        };
+        code = code.max(file_code);
+    }
+    code
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() < 3 {
+    let mut options = Options::default();
+    let mut filenames = Vec::new();
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "-u" => options.unified = true,
+            "-w" => options.ignore_whitespace = true,
+            "-i" => options.ignore_case = true,
+            "-y" | "--side-by-side" => options.side_by_side = true,
+            "-q" | "--brief" => options.brief = true,
+            "-a" | "--text" => options.text = true,
+            "--stat" => options.stat = true,
+            "-r" | "--recursive" => options.recursive = true,
+            "--word-diff" => options.word_diff = true,
+            "-W" | "--width" => {
+                options.width = match rest.next().and_then(|n| n.parse().ok()) {
+                    Some(n) => n,
+                    None => {
+                        println!("{} needs a number of columns.", arg);
+                        process::exit(EXIT_TROUBLE);
+                    }
+                };
+            }
+            "-C" | "--context" => {
+                options.context = match rest.next().and_then(|n| n.parse().ok()) {
+                    Some(n) => n,
+                    None => {
+                        println!("{} needs a number of lines.", arg);
+                        process::exit(EXIT_TROUBLE);
+                    }
+                };
+            }
+            _ if arg == "--color" || arg.starts_with("--color=") => {
+                let when = match arg.split_once('=') {
+                    Some((_, when)) => Some(when),
+                    None => rest.next().map(|when| when.as_str()),
+                };
+                options.color = match when.and_then(use_color) {
+                    Some(color) => color,
+                    None => {
+                        println!("--color needs one of auto, always or never.");
+                        process::exit(EXIT_TROUBLE);
+                    }
+                };
+            }
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                println!("Unknown option {}.", arg);
+                process::exit(EXIT_TROUBLE);
+            }
+            _ => filenames.push(arg),
+        }
+    }
+    if filenames.len() < 2 {
         println!("Too few arguments.");
-        process::exit(1);
+        process::exit(EXIT_TROUBLE);
     }
-    let filename1 = &args[1];
-    let filename2 = &args[2];
-
-    let file1 = read_file_lines(filename1).expect("FAIL TO CALL FILE1");
-    let file2 = read_file_lines(filename2).expect("FAIL TO CALL FILE2");
+    let filename1 = filenames[0];
+    let filename2 = filenames[1];
 
-    diff(&file1, &file2);
+    let is_dir = |filename: &String| Path::new(filename).is_dir();
+    let code = match (is_dir(filename1), is_dir(filename2)) {
+        (true, true) => compare_dirs(filename1, filename2, &options),
+        // a file is compared with the file of the same name in the directory, like GNU diff
+        (true, false) => {
+            let filename1 = path_in(filename1, filename2);
+            exit_code(compare_files(&filename1, filename2, false, &options))
+        }
+        (false, true) => {
+            let filename2 = path_in(filename2, filename1);
+            exit_code(compare_files(filename1, &filename2, false, &options))
+        }
+        (false, false) => exit_code(compare_files(filename1, filename2, false, &options)),
+    };
+    process::exit(code);
 }
 
 #[cfg(test)]
@@ -97,6 +775,40 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_read_file_lines_missing() {
+        let error = read_file_lines(&String::from("no-such-file.txt")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert_eq!(describe_error(&error), "No such file or directory");
+    }
+
+    #[test]
+    fn test_read_file_lines_permission_denied() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = env::temp_dir().join(format!("rdiff-unreadable-{}", process::id()));
+        std::fs::write(&path, "secret\n").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o000)).unwrap();
+        let result = read_file_lines(&path.to_string_lossy().into_owned());
+        std::fs::remove_file(&path).unwrap();
+        // root can read the file regardless, so there's nothing to check then
+        if let Err(error) = result {
+            assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+            assert_eq!(describe_error(&error), "Permission denied");
+        }
+    }
+
+    #[test]
+    fn test_binary_files() {
+        let path = env::temp_dir().join(format!("rdiff-binary-{}", process::id()));
+        let filename = path.to_string_lossy().into_owned();
+        std::fs::write(&path, b"\x7fELF\x02\x01\x00\xff\xfe\n").unwrap();
+        assert!(is_binary_file(&filename).unwrap());
+        // even so it can be read as text, with the invalid UTF-8 replaced
+        assert_eq!(read_file_lines(&filename).unwrap(), vec!["\x7fELF\x02\x01\x00\u{fffd}\u{fffd}"]);
+        std::fs::remove_file(&path).unwrap();
+        assert!(!is_binary_file(&String::from("handout-a.txt")).unwrap());
+    }
+
     #[test]
     fn test_lcs() {
         let mut expected = Grid::new(5, 4);
@@ -116,8 +828,8 @@ mod test {
         println!("Expected:");
         expected.display();
         let result = lcs(
-            &"abcd".chars().map(|c| c.to_string()).collect(),
-            &"adb".chars().map(|c| c.to_string()).collect(),
+            &"abcd".chars().map(|c| c.to_string()).collect::<Vec<_>>(),
+            &"adb".chars().map(|c| c.to_string()).collect::<Vec<_>>(),
         );
         println!("Got:");
         result.display();
@@ -128,4 +840,235 @@ mod test {
             }
         }
     }
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(|line| line.to_string()).collect()
+    }
+
+    fn unified(lines1: &[String], lines2: &[String]) -> String {
+        unified_with_context(lines1, lines2, 3)
+    }
+
+    fn unified_with_context(lines1: &[String], lines2: &[String], context: usize) -> String {
+        let edits = edit_script(&lcs(lines1, lines2), lines1, lines2);
+        let options = Options { unified: true, context, ..Default::default() };
+        let mut out = Vec::new();
+        print_diff(&mut out, &edits, lines1, lines2, ("a", "b"), (false, false), &options).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_edit_script() {
+        let lines1 = lines("a\nb\nc");
+        let lines2 = lines("a\nx\nc\nd");
+        let edits = edit_script(&lcs(&lines1, &lines2), &lines1, &lines2);
+        assert_eq!(
+            edits,
+            vec![Edit::Same(0, 0), Edit::Delete(1), Edit::Insert(1), Edit::Same(2, 2), Edit::Insert(3)]
+        );
+    }
+
+    #[test]
+    fn test_unified() {
+        let lines1 = lines("1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12");
+        let mut lines2 = lines1.clone();
+        lines2[1] = String::from("two");
+        lines2.insert(11, String::from("eleven and a half"));
+        assert_eq!(
+            unified(&lines1, &lines2),
+            "--- a\n+++ b\n\
+             @@ -1,5 +1,5 @@\n 1\n-2\n+two\n 3\n 4\n 5\n\
+             @@ -9,4 +9,5 @@\n 9\n 10\n 11\n+eleven and a half\n 12\n"
+        );
+        assert_eq!(unified(&lines1, &lines1), "");
+    }
+
+    #[test]
+    fn test_unified_merges_close_changes() {
+        let lines1 = lines("1\n2\n3\n4\n5\n6\n7\n8");
+        let mut lines2 = lines1.clone();
+        lines2.remove(0);
+        lines2.remove(6);
+        assert_eq!(
+            unified(&lines1, &lines2),
+            "--- a\n+++ b\n@@ -1,8 +1,6 @@\n-1\n 2\n 3\n 4\n 5\n 6\n 7\n-8\n"
+        );
+    }
+
+    #[test]
+    fn test_unified_context() {
+        let lines1 = lines("1\n2\n3\n4\n5\n6\n7\n8");
+        let mut lines2 = lines1.clone();
+        lines2.remove(0);
+        lines2.remove(6);
+        assert_eq!(
+            unified_with_context(&lines1, &lines2, 1),
+            "--- a\n+++ b\n@@ -1,2 +1 @@\n-1\n 2\n@@ -7,2 +6 @@\n 7\n-8\n"
+        );
+        assert_eq!(
+            unified_with_context(&lines1, &lines2, 0),
+            "--- a\n+++ b\n@@ -1 +0,0 @@\n-1\n@@ -8 +6,0 @@\n-8\n"
+        );
+    }
+
+    #[test]
+    fn test_color() {
+        let (lines1, lines2) = (lines("a\nb"), lines("a\nc"));
+        let edits = edit_script(&lcs(&lines1, &lines2), &lines1, &lines2);
+        let options = Options { color: true, ..Default::default() };
+        let mut out = Vec::new();
+        print_diff(&mut out, &edits, &lines1, &lines2, ("a", "b"), (false, false), &options).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\n a\n\x1b[31m< b\x1b[0m\n\x1b[32m> c\x1b[0m\n"
+        );
+    }
+
+    #[test]
+    fn test_normalize() {
+        let text = lines("  Hello,\tWorld \nfoo");
+        let mut options = Options { ignore_whitespace: true, ..Default::default() };
+        assert_eq!(normalize(&text, &options), vec!["Hello,World", "foo"]);
+        options.ignore_case = true;
+        assert_eq!(normalize(&text, &options), vec!["hello,world", "foo"]);
+        options.ignore_whitespace = false;
+        assert_eq!(normalize(&text, &options), vec!["  hello,\tworld ", "foo"]);
+    }
+
+    #[test]
+    fn test_side_by_side() {
+        let lines1 = lines("same\nold\ngone\n\tend");
+        let lines2 = lines("same\nnew\n\tend\nextra");
+        let edits = edit_script(&lcs(&lines1, &lines2), &lines1, &lines2);
+        let options = Options { side_by_side: true, width: 23, ..Default::default() };
+        let mut out = Vec::new();
+        print_diff(&mut out, &edits, &lines1, &lines2, ("a", "b"), (false, false), &options).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "same         same\n\
+             old        | new\n\
+             gone       <\n        \
+             en           en\n           \
+             > extra\n"
+        );
+    }
+
+    #[test]
+    fn test_files_differ() {
+        let mut options = Options::default();
+        assert!(!files_differ(&lines("a\nb"), &lines("a\nb"), &options));
+        assert!(files_differ(&lines("a\nb"), &lines("a\nB"), &options));
+        assert!(files_differ(&lines("a\nb"), &lines("a\nb\nc"), &options));
+        options.ignore_case = true;
+        assert!(!files_differ(&lines("a\nb"), &lines("a\nB"), &options));
+    }
+
+    #[test]
+    fn test_diff_stat() {
+        let lines1 = lines("a\nb\nc\nd\ne");
+        let lines2 = lines("a\nB\nc\nnew\nnewer\nd");
+        let edits = edit_script(&lcs(&lines1, &lines2), &lines1, &lines2);
+        assert_eq!(diff_stat(&edits), DiffStat { added: 2, removed: 1, changed: 1 });
+        assert_eq!(diff_stat(&[Edit::Same(0, 0)]), DiffStat::default());
+    }
+
+    #[test]
+    fn test_patch_round_trip() {
+        let cases: [(&str, &str); 5] = [
+            ("a\nb\nc\n", "a\nB\nc\nd\n"),
+            ("a\nb\nc", "a\nb\nc\n"),
+            ("a\nb\nc\n", "a\nb\nc"),
+            ("a\nb", "x\na\nc"),
+            ("", "one\r\ntwo\r\n"),
+        ];
+        let dir = env::temp_dir();
+        let path1 = dir.join(format!("rdiff-patch-a-{}", process::id()));
+        let path2 = dir.join(format!("rdiff-patch-b-{}", process::id()));
+        let filename1 = path1.to_string_lossy().into_owned();
+        let filename2 = path2.to_string_lossy().into_owned();
+        let options = Options { unified: true, ..Default::default() };
+        for &(text1, text2) in cases.iter() {
+            std::fs::write(&path1, text1).unwrap();
+            std::fs::write(&path2, text2).unwrap();
+            let file1 = read_file_lines(&filename1).unwrap();
+            let file2 = read_file_lines(&filename2).unwrap();
+            let missing = (missing_newline(&filename1).unwrap(), missing_newline(&filename2).unwrap());
+            let names = (filename1.as_str(), filename2.as_str());
+            let mut patch = Vec::new();
+            assert!(diff(&mut patch, &file1, &file2, names, missing, &options).unwrap());
+
+            let mut child = process::Command::new("patch")
+                .arg("-s")
+                .arg(&path1)
+                .stdin(process::Stdio::piped())
+                .spawn()
+                .unwrap();
+            child.stdin.take().unwrap().write_all(&patch).unwrap();
+            assert!(child.wait().unwrap().success(), "patch failed on {:?}", text1);
+            assert_eq!(std::fs::read_to_string(&path1).unwrap(), text2);
+        }
+        std::fs::remove_file(&path1).unwrap();
+        std::fs::remove_file(&path2).unwrap();
+    }
+
+    #[test]
+    fn test_compare_dirs() {
+        let root = env::temp_dir().join(format!("rdiff-dirs-{}", process::id()));
+        let (dir1, dir2) = (root.join("a"), root.join("b"));
+        for dir in [&dir1, &dir2].iter() {
+            std::fs::create_dir_all(dir.join("sub")).unwrap();
+            std::fs::write(dir.join("same"), "same\n").unwrap();
+            std::fs::write(dir.join("z"), "z\n").unwrap();
+        }
+        std::fs::write(dir1.join("sub").join("file"), "old\n").unwrap();
+        std::fs::write(dir2.join("sub").join("file"), "new\n").unwrap();
+        let name1 = dir1.to_string_lossy().into_owned();
+        let name2 = dir2.to_string_lossy().into_owned();
+        let mut options = Options { brief: true, ..Default::default() };
+
+        let entries: Vec<_> = dir_entries(&name1).unwrap().into_iter().collect();
+        assert_eq!(entries, ["same", "sub", "z"]);
+        assert_eq!(path_in(&name1, "other/same"), dir1.join("same").to_string_lossy());
+        // subdirectories are only compared with -r
+        assert_eq!(compare_dirs(&name1, &name2, &options), EXIT_SAME);
+        options.recursive = true;
+        assert_eq!(compare_dirs(&name1, &name2, &options), EXIT_DIFFERENT);
+        std::fs::remove_file(dir2.join("z")).unwrap();
+        options.recursive = false;
+        assert_eq!(compare_dirs(&name1, &name2, &options), EXIT_DIFFERENT);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_word_diff() {
+        assert_eq!(split_words("  a  bc\td "), vec!["  ", "a", "  ", "bc", "\t", "d", " "]);
+        let options = Options::default();
+        assert_eq!(
+            word_diff("the quick brown fox", "the slow brown fox jumps", &options),
+            "the [-quick-]{+slow+} brown fox{+ jumps+}"
+        );
+        let options = Options { ignore_case: true, ..Default::default() };
+        assert_eq!(word_diff("Same words", "same WORDS", &options), "Same words");
+
+        let lines1 = lines("a\nold one\nold two\nb");
+        let lines2 = lines("a\nnew one\nb\nc");
+        let edits = edit_script(&lcs(&lines1, &lines2), &lines1, &lines2);
+        let options = Options { word_diff: true, ..Default::default() };
+        assert_eq!(
+            diff_lines(&edits, &options),
+            vec![
+                DiffLine::Edit(Edit::Same(0, 0)),
+                DiffLine::Changed(1, 1),
+                DiffLine::Edit(Edit::Delete(2)),
+                DiffLine::Edit(Edit::Same(3, 2)),
+                DiffLine::Edit(Edit::Insert(3)),
+            ]
+        );
+        let mut out = Vec::new();
+        print_diff(&mut out, &edits, &lines1, &lines2, ("a", "b"), (false, false), &options).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\n a\n~ [-old-]{+new+} one\n< old two\n b\n> c\n"
+        );
+    }
 }